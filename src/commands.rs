@@ -0,0 +1,2470 @@
+//! Bot commands and their handlers.
+
+use async_openai::error::OpenAIError;
+use async_openai::types::{CreateImageRequestArgs, Image, ImageSize, Role};
+use chrono::Utc;
+use std::collections::BTreeSet;
+use std::ops::RangeInclusive;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use std::{fs, io};
+use teloxide::net::Download;
+use teloxide::types::{ChatAction, InputFile};
+use teloxide::{prelude::*, utils::command::BotCommands};
+use teloxide::{ApiError, RequestError};
+use tokio::io::AsyncWriteExt;
+
+use crate::completion::{
+    chunk_messages, compact_history, complete_chat, count_prompt_tokens, count_text_tokens,
+    count_tokens, max_reply_tokens, reply_busy, reply_demo_limited, reply_rate_limited,
+    request_summary, retry_after, split_message, stream_reply, supports_vision, utf16_len,
+    ApiFailure, Format, ReplyMode, ReplyTo, MAX_VARIANTS, MESSAGE_LIMIT, RESPONSE_TOKEN_RESERVE,
+    SUMMARY_PREFIX, SUMMARY_PROMPT,
+};
+use crate::config::{Client, API_CHECK_TIMEOUT};
+use crate::state::{
+    default_conversation, set_system_prompt, system_prompt, undo_exchange, AppState, ChatMessage,
+    ChatMessages, ChatSettings, ChatState, Lang, State, Text, TokenUsage, CONVERSATION_NAME_LIMIT,
+    DEFAULT_CONVERSATION, MODEL,
+};
+use crate::{AppError, HandleResult};
+
+/// Room left on every page for a `(1/3)` marker.
+const PAGE_MARKER_RESERVE: usize = 16;
+/// Characters of a message shown by `/view` before it is cut off.
+const VIEW_MESSAGE_LIMIT: usize = 1000;
+/// Maximum size of conversation files accepted by `/load`.
+const LOAD_FILE_LIMIT: u32 = 1024 * 1024;
+const MODELS: &[&str] = &[
+    "gpt-3.5-turbo",
+    "gpt-3.5-turbo-0301",
+    "gpt-4",
+    "gpt-4-0314",
+    "gpt-4-32k",
+    "gpt-4-32k-0314",
+    "gpt-4-turbo",
+    "gpt-4o",
+];
+const RESET_CONFIRM_TIMEOUT: Duration = Duration::from_secs(60);
+/// Asks for the summary shown by `/summarize`, not stored in the history.
+const TLDR_PROMPT: &str = "Give a short TL;DR of the conversation so far as a few bullet points.";
+/// Longest system prompt accepted by `/prompt`, in characters.
+const MAX_PROMPT_CHARS: usize = 4000;
+/// Prompts taking up more than 1/N of the model's context get a warning.
+const LONG_PROMPT_SHARE: usize = 4;
+/// Characters of their prompts shown by `/presets`.
+const PRESET_PREVIEW_CHARS: usize = 60;
+/// Most stop sequences the API accepts.
+const MAX_STOP_SEQUENCES: usize = 4;
+/// Pause between the messages of `/broadcast`, Telegram allows about 30 per
+/// second.
+const BROADCAST_INTERVAL: Duration = Duration::from_millis(50);
+
+async fn compact(bot: Bot, client: Client, state: State, msg: Message) -> HandleResult {
+    if !state.take_demo_completion(msg.chat.id) {
+        return reply_demo_limited(bot, state, msg).await;
+    }
+    tracing::info!("Compact, user: {}", msg.chat.id);
+    bot.send_chat_action(msg.chat.id, ChatAction::Typing)
+        .await?;
+
+    let content = match compact_history(&client, &state, state.key(&msg)).await? {
+        Some(count) => format!("Compacted {} messages into a summary.", count),
+        None => "Nothing to compact.".to_owned(),
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Replies with a summary of the active conversation, leaving it as is.
+///
+/// A history too long for one request is summarized a part at a time, each
+/// summary being carried into the next part.
+async fn summarize(bot: Bot, client: Client, state: State, msg: Message) -> HandleResult {
+    let Some(_replying) = state.lock_replies(&msg).await else {
+        return reply_busy(bot, state, msg).await;
+    };
+    let key = state.key(&msg);
+    let mut messages = state.store().get(key).await;
+    let settings = state.settings(key).await;
+    let start = messages
+        .iter()
+        .take_while(|m| matches!(m.role, Role::System) && !m.content.starts_with(SUMMARY_PREFIX))
+        .count();
+    if messages.len() == start {
+        bot.send_message(msg.chat.id, "Nothing to summarize.")
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+        return Ok(());
+    }
+    if let Err(wait) = state.check_rate_limit(msg.chat.id) {
+        return reply_rate_limited(bot, state, msg, wait).await;
+    }
+
+    tracing::info!("Summarize, user: {}", msg.chat.id);
+    let model = settings.model();
+    if !supports_vision(model) {
+        for message in &mut messages {
+            message.images.clear();
+        }
+    }
+    let body = messages.split_off(start);
+    let prompt = messages;
+    let budget = state.token_budget(model, state.max_tokens(&settings));
+    // Leaves room for the prompts and the summary of the earlier parts.
+    let overhead = count_prompt_tokens(model, &prompt)
+        + count_tokens(model, &ChatMessage::new(Role::User, SUMMARY_PROMPT))
+        + RESPONSE_TOKEN_RESERVE;
+    let mut chunks = chunk_messages(model, &body, budget.saturating_sub(overhead));
+    let last = chunks.pop().unwrap_or_default();
+
+    let mut summary: Option<ChatMessage> = None;
+    if !chunks.is_empty() {
+        bot.send_chat_action(msg.chat.id, ChatAction::Typing)
+            .await?;
+    }
+    for chunk in chunks {
+        // The final part is counted when its reply is requested.
+        if !state.take_demo_completion(msg.chat.id) {
+            return reply_demo_limited(bot, state, msg).await;
+        }
+        let mut request = prompt.clone();
+        request.extend(summary.take());
+        request.extend(chunk);
+        let text = request_summary(&client, model, request, budget)
+            .await?
+            .unwrap_or_default();
+        summary = Some(ChatMessage::new(
+            Role::System,
+            format!("{}{}", SUMMARY_PREFIX, text),
+        ));
+    }
+
+    let mut request = prompt;
+    request.extend(summary);
+    request.extend(last);
+    request.push(ChatMessage::new(Role::User, TLDR_PROMPT));
+    stream_reply(bot, client, state, msg, ReplyMode::Detached(request)).await
+}
+
+/// Replies to `content` alone, without the history or system prompt, and
+/// doesn't store the exchange.
+async fn complete_raw(
+    content: String,
+    bot: Bot,
+    client: Client,
+    state: State,
+    msg: Message,
+) -> HandleResult {
+    let content = content.trim();
+    if content.is_empty() {
+        bot.send_message(msg.chat.id, "Usage: /raw <message>")
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+        return Ok(());
+    }
+    let Some(_replying) = state.lock_replies(&msg).await else {
+        return reply_busy(bot, state, msg).await;
+    };
+    if let Err(wait) = state.check_rate_limit(msg.chat.id) {
+        return reply_rate_limited(bot, state, msg, wait).await;
+    }
+
+    tracing::info!(
+        "Raw completion, user: {}, content: {}",
+        msg.chat.id,
+        content
+    );
+    let request = vec![ChatMessage::new(Role::User, content)];
+    stream_reply(bot, client, state, msg, ReplyMode::Detached(request)).await
+}
+
+pub(crate) async fn regenerate(
+    bot: Bot,
+    client: Client,
+    state: State,
+    msg: Message,
+) -> HandleResult {
+    let Some(_replying) = state.lock_replies(&msg).await else {
+        return reply_busy(bot, state, msg).await;
+    };
+    if let Err(wait) = state.check_rate_limit(msg.chat.id) {
+        return reply_rate_limited(bot, state, msg, wait).await;
+    }
+
+    let key = state.key(&msg);
+    let replied = state
+        .store()
+        .get(key)
+        .await
+        .last()
+        .is_some_and(|message| matches!(message.role, Role::Assistant));
+    if !replied {
+        bot.send_message(
+            msg.chat.id,
+            "The last message is not an assistant reply, nothing to regenerate.",
+        )
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+        return Ok(());
+    }
+    state.store().pop(key).await;
+    state.mark_dirty();
+
+    tracing::info!("Regenerate, user: {}", msg.chat.id);
+    stream_reply(
+        bot,
+        client,
+        state,
+        msg,
+        ReplyMode::History {
+            rollback: false,
+            replacing: None,
+        },
+    )
+    .await
+}
+
+/// Retries the completion of a trailing user message that got no reply, e.g.
+/// because the request failed.
+async fn retry_last(bot: Bot, client: Client, state: State, msg: Message) -> HandleResult {
+    let Some(_replying) = state.lock_replies(&msg).await else {
+        return reply_busy(bot, state, msg).await;
+    };
+    let dangling = state
+        .store()
+        .get(state.key(&msg))
+        .await
+        .last()
+        .is_some_and(|message| matches!(message.role, Role::User));
+    if !dangling {
+        bot.send_message(
+            msg.chat.id,
+            "The last message already has a reply, use /regenerate to get a new one.",
+        )
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+        return Ok(());
+    }
+
+    if let Err(wait) = state.check_rate_limit(msg.chat.id) {
+        return reply_rate_limited(bot, state, msg, wait).await;
+    }
+
+    tracing::info!("Retry last, user: {}", msg.chat.id);
+    stream_reply(
+        bot,
+        client,
+        state,
+        msg,
+        ReplyMode::History {
+            rollback: false,
+            replacing: None,
+        },
+    )
+    .await
+}
+
+/// Extends the last reply, e.g. after it got cut off for being too long.
+async fn continue_reply(bot: Bot, client: Client, state: State, msg: Message) -> HandleResult {
+    let Some(_replying) = state.lock_replies(&msg).await else {
+        return reply_busy(bot, state, msg).await;
+    };
+    let replied = state
+        .store()
+        .get(state.key(&msg))
+        .await
+        .last()
+        .is_some_and(|message| matches!(message.role, Role::Assistant));
+    if !replied {
+        bot.send_message(
+            msg.chat.id,
+            "The last message is not an assistant reply, nothing to continue.",
+        )
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+        return Ok(());
+    }
+
+    if let Err(wait) = state.check_rate_limit(msg.chat.id) {
+        return reply_rate_limited(bot, state, msg, wait).await;
+    }
+
+    tracing::info!("Continue, user: {}", msg.chat.id);
+    stream_reply(bot, client, state, msg, ReplyMode::Continuation).await
+}
+
+/// Joins `blocks` with `separator` into pages of at most `limit` UTF-16 code
+/// units, numbered like `(1/3)` if there is more than one.
+///
+/// Blocks are only split if they don't fit on a page of their own.
+fn paginate<S: AsRef<str>>(blocks: &[S], separator: &str, limit: usize) -> Vec<String> {
+    let limit = limit.saturating_sub(PAGE_MARKER_RESERVE);
+    let mut pages = Vec::new();
+    let mut page = String::new();
+    for piece in blocks
+        .iter()
+        .flat_map(|block| split_message(block.as_ref(), limit))
+    {
+        if !page.is_empty() && utf16_len(&page) + utf16_len(separator) + utf16_len(piece) > limit {
+            pages.push(std::mem::take(&mut page));
+        }
+        if !page.is_empty() {
+            page.push_str(separator);
+        }
+        page.push_str(piece);
+    }
+    if !page.is_empty() || pages.is_empty() {
+        pages.push(page);
+    }
+
+    let count = pages.len();
+    if count > 1 {
+        for (i, page) in pages.iter_mut().enumerate() {
+            page.insert_str(0, &format!("({}/{})\n\n", i + 1, count));
+        }
+    }
+    pages
+}
+
+/// Sends `blocks` as replies to `msg`, on as many pages as needed.
+async fn send_pages<S: AsRef<str>>(
+    bot: &Bot,
+    state: &State,
+    msg: &Message,
+    blocks: &[S],
+    separator: &str,
+) -> Result<(), RequestError> {
+    for page in paginate(blocks, separator, MESSAGE_LIMIT) {
+        bot.send_message(msg.chat.id, page)
+            .reply_to(state.reply_to(msg).await)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn stop(bot: Bot, state: State, msg: Message) -> HandleResult {
+    let content = if state.stop_stream(state.key(&msg)) {
+        "Stopped."
+    } else {
+        "Nothing to stop."
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Sets the system prompt to the preset `name`.
+async fn apply_preset(name: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let name = name.trim().to_lowercase();
+    let Some(prompt) = state.config.presets.get(&name) else {
+        let content = match name.is_empty() {
+            true => "Usage: /preset <name>, see /presets for the available ones.".to_owned(),
+            false => format!("Unknown preset \"{}\", see /presets.", name),
+        };
+        bot.send_message(msg.chat.id, content)
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+        return Ok(());
+    };
+
+    tracing::info!("Apply preset, user: {}, preset: {}", msg.chat.id, name);
+    set_prompt(prompt.clone(), bot, state, msg).await
+}
+
+/// Lists the presets with the start of their prompts.
+async fn list_presets(bot: Bot, state: State, msg: Message) -> HandleResult {
+    if state.config.presets.is_empty() {
+        bot.send_message(msg.chat.id, "No presets are available.")
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+        return Ok(());
+    }
+
+    let lines: Vec<String> = state
+        .config
+        .presets
+        .iter()
+        .map(|(name, prompt)| {
+            let prompt = prompt.lines().next().unwrap_or_default();
+            match prompt.char_indices().nth(PRESET_PREVIEW_CHARS) {
+                Some((end, _)) => format!("{} — {}…", name, &prompt[..end]),
+                None => format!("{} — {}", name, prompt),
+            }
+        })
+        .collect();
+    send_pages(&bot, &state, &msg, &lines, "\n").await?;
+
+    Ok(())
+}
+
+async fn set_prompt(prompt: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let prompt = prompt.trim();
+    let lang = state.lang(&msg).await;
+    let content = if prompt.is_empty() {
+        lang.text(Text::PromptUsage).to_owned()
+    } else if prompt.chars().count() > MAX_PROMPT_CHARS {
+        format!("{} {}.", lang.text(Text::PromptTooLong), MAX_PROMPT_CHARS)
+    } else {
+        tracing::info!("Set prompt, user: {}, prompt: {}", msg.chat.id, prompt);
+
+        let key = state.key(&msg);
+        state
+            .store()
+            .replace(key, vec![ChatMessage::new(Role::System, prompt)])
+            .await;
+        state.update_chat(key, ChatState::reset_tracking).await;
+        state.mark_dirty();
+        let model = state.settings(key).await.model().to_owned();
+
+        let context_size = tiktoken_rs::model::get_context_size(&model);
+        if count_text_tokens(&model, prompt) > context_size / LONG_PROMPT_SHARE {
+            lang.text(Text::PromptSetLong).to_owned()
+        } else {
+            lang.text(Text::PromptSet).to_owned()
+        }
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Shows, sets or removes the persona, a system prompt that conversations
+/// start with again after `/clear`, unlike one set with `/prompt`.
+async fn set_persona(persona: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let persona = persona.trim();
+    let content = match persona {
+        "" => match state.settings(state.key(&msg)).await.persona {
+            Some(persona) => format!("Persona: {}", persona),
+            None => "No persona is set, use /persona <text> to set one.".to_owned(),
+        },
+        "off" => {
+            state
+                .update_chat(state.key(&msg), |chat| chat.settings.persona = None)
+                .await;
+            state.mark_dirty();
+            "Persona removed, conversations start without it after /clear.".to_owned()
+        }
+        _ if persona.chars().count() > MAX_PROMPT_CHARS => format!(
+            "{} {}.",
+            state.lang(&msg).await.text(Text::PromptTooLong),
+            MAX_PROMPT_CHARS
+        ),
+        _ => {
+            tracing::info!("Set persona, user: {}, persona: {}", msg.chat.id, persona);
+            let persona = persona.to_owned();
+            state
+                .update_chat(state.key(&msg), |chat| {
+                    chat.settings.persona = Some(persona.clone())
+                })
+                .await;
+            // Also starts the current conversation over with it.
+            return set_prompt(persona, bot, state, msg).await;
+        }
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+async fn edit_system_prompt(prompt: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let prompt = prompt.trim();
+    let key = state.key(&msg);
+    let mut messages = state.store().get(key).await;
+    let content = if prompt.is_empty() {
+        match system_prompt(&messages) {
+            Some(prompt) => format!("System prompt: {}", prompt),
+            None => "System prompt: none".to_owned(),
+        }
+    } else {
+        tracing::info!(
+            "Set system prompt, user: {}, prompt: {}",
+            msg.chat.id,
+            prompt
+        );
+        set_system_prompt(&mut messages, prompt.to_owned());
+        state.store().replace(key, messages).await;
+        state.mark_dirty();
+        "System prompt updated.".to_owned()
+    };
+
+    send_pages(&bot, &state, &msg, &[content], "").await?;
+
+    Ok(())
+}
+
+/// A message as shown by `/view`. Unless `full`, system messages are hidden
+/// and long messages are cut off.
+fn view_message(message: &ChatMessage, full: bool) -> String {
+    let content = message.content.trim();
+    let content = if full {
+        content.to_owned()
+    } else if message.role == Role::System {
+        "(hidden, use /view full to show it)".to_owned()
+    } else {
+        let len = content.chars().count();
+        match content.char_indices().nth(VIEW_MESSAGE_LIMIT) {
+            Some((end, _)) => format!(
+                "{}… ({} more characters)",
+                &content[..end],
+                len - VIEW_MESSAGE_LIMIT
+            ),
+            None => content.to_owned(),
+        }
+    };
+    match message.images.len() {
+        0 => format!("[{}]: {}", message.role, content),
+        n => format!("[{}]: ({} images) {}", message.role, n, content),
+    }
+}
+
+async fn view_histories(arg: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let full = match arg.trim() {
+        "" => false,
+        "full" => true,
+        arg => {
+            bot.send_message(
+                msg.chat.id,
+                format!("Unknown option \"{}\", use /view or /view full.", arg),
+            )
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+            return Ok(());
+        }
+    };
+
+    let lang = state.lang(&msg).await;
+    let key = state.key(&msg);
+    let messages = state.store().get(key).await;
+    let blocks = if messages.is_empty() {
+        vec![lang.text(Text::EmptyHistory).to_owned()]
+    } else {
+        let trimmed = state.store().load(key).await.map_or(0, |chat| chat.trimmed);
+        let note = (trimmed > 0).then(|| format!("({} {})", lang.text(Text::TrimmedNote), trimmed));
+        note.into_iter()
+            .chain(messages.iter().map(|message| view_message(message, full)))
+            .collect()
+    };
+
+    send_pages(&bot, &state, &msg, &blocks, "\n\n").await?;
+
+    Ok(())
+}
+
+pub(crate) async fn clear_history(bot: Bot, state: State, msg: Message) -> HandleResult {
+    let key = state.key(&msg);
+    if let Some(chat) = state.store().load(key).await {
+        state.store().clear(key).await;
+        for message in state.initial_messages(&chat.settings) {
+            state.store().append(key, message).await;
+        }
+        state.update_chat(key, ChatState::reset_tracking).await;
+    }
+    state.mark_dirty();
+
+    bot.send_message(
+        msg.chat.id,
+        state.lang(&msg).await.text(Text::HistoryCleared),
+    )
+    .reply_to(state.reply_to(&msg).await)
+    .await?;
+
+    Ok(())
+}
+
+pub(crate) async fn undo(bot: Bot, state: State, msg: Message) -> HandleResult {
+    let key = state.key(&msg);
+    let mut messages = state.store().get(key).await;
+    let replied = messages
+        .last()
+        .is_some_and(|message| matches!(message.role, Role::Assistant));
+    let content = if undo_exchange(&mut messages) {
+        let remaining = messages.len();
+        state.store().replace(key, messages).await;
+        if replied {
+            state.update_chat(key, |chat| chat.last_reply.clear()).await;
+        }
+        state.mark_dirty();
+        format!("Last exchange removed, {} messages left.", remaining)
+    } else {
+        "Nothing to undo.".to_owned()
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+async fn set_model(model: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let model = model.trim();
+    let content = if model.is_empty() {
+        let current = state.settings(state.key(&msg)).await.model().to_owned();
+        format!(
+            "Current model: {}\nAvailable models: {}",
+            current,
+            MODELS.join(", ")
+        )
+    } else if MODELS.contains(&model) {
+        tracing::info!("Set model, user: {}, model: {}", msg.chat.id, model);
+        let model = model.to_owned();
+        state
+            .update_chat(state.key(&msg), |chat| {
+                chat.settings.model = Some(model.clone())
+            })
+            .await;
+        state.mark_dirty();
+        format!("Model set to {}.", model)
+    } else {
+        format!(
+            "Unknown model \"{}\". Available models: {}",
+            model,
+            MODELS.join(", ")
+        )
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Lists the models of [`MODELS`] with whether the API has them, and the chat
+/// models the API has that can't be switched to.
+async fn list_models(bot: Bot, client: Client, state: State, msg: Message) -> HandleResult {
+    let current = state.settings(state.key(&msg)).await.model().to_owned();
+    let listed = match state.list_models(&client).await {
+        Ok(listed) => listed,
+        Err(err) => {
+            let failure = ApiFailure::of(&err);
+            failure.log(msg.chat.id, &err);
+            bot.send_message(msg.chat.id, failure.message())
+                .reply_to(state.reply_to(&msg).await)
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let mut lines = vec!["Models, switch with /model <name>:".to_owned()];
+    lines.extend(MODELS.iter().map(|&model| {
+        let available = listed.iter().any(|id| id == model);
+        let mut line = format!("{} {}", if available { "✅" } else { "⚠️" }, model);
+        if model == current {
+            line.push_str(" (current)");
+        }
+        if !available {
+            line.push_str(" (not listed by the API)");
+        }
+        line
+    }));
+    let others: Vec<&str> = listed
+        .iter()
+        .map(String::as_str)
+        .filter(|id| !MODELS.contains(id))
+        .collect();
+    if !others.is_empty() {
+        lines.push(format!("Not allowed: {}", others.join(", ")));
+    }
+
+    send_pages(&bot, &state, &msg, &lines, "\n").await?;
+
+    Ok(())
+}
+
+async fn set_format(format: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let format = format.trim();
+    let content = if format.is_empty() {
+        let format = state
+            .update_chat(state.key(&msg), |chat| {
+                chat.settings.format = match chat.settings.format {
+                    Format::Plain => Format::Markdown,
+                    Format::Markdown | Format::Html | Format::Entities => Format::Plain,
+                };
+                chat.settings.format
+            })
+            .await;
+        state.mark_dirty();
+        format!("Formatting set to {}.", format.name())
+    } else if let Some(format) = Format::parse(format) {
+        state
+            .update_chat(state.key(&msg), |chat| chat.settings.format = format)
+            .await;
+        state.mark_dirty();
+        format!("Formatting set to {}.", format.name())
+    } else {
+        format!(
+            "Unknown format \"{}\". Use plain, markdown, html or entities.",
+            format
+        )
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Shows or sets whether replies are requested as JSON objects.
+async fn set_json(value: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let value = value.trim();
+    let content = match value.to_lowercase().as_str() {
+        "" => {
+            let json = state.settings(state.key(&msg)).await.json;
+            format!("JSON mode is {}.", if json { "on" } else { "off" })
+        }
+        value @ ("on" | "off") => {
+            tracing::info!("Set JSON mode, user: {}, value: {}", msg.chat.id, value);
+            state
+                .update_chat(state.key(&msg), |chat| chat.settings.json = value == "on")
+                .await;
+            state.mark_dirty();
+            format!("JSON mode set to {}.", value)
+        }
+        _ => format!("Unknown value \"{}\". Use on or off.", value),
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Shows or sets the language of the bot's messages in the chat, or resets it
+/// to the sender's Telegram language with `default`.
+async fn set_lang(code: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let code = code.trim();
+    let available = Lang::ALL.map(Lang::code).join(", ");
+    let content = if code.is_empty() {
+        let lang = state.lang(&msg).await;
+        format!(
+            "{} {} ({})",
+            lang.text(Text::LangCurrent),
+            lang.code(),
+            available
+        )
+    } else if code.eq_ignore_ascii_case("default") {
+        state
+            .update_chat(state.key(&msg), |chat| chat.settings.lang = None)
+            .await;
+        state.mark_dirty();
+        state.lang(&msg).await.text(Text::LangReset).to_owned()
+    } else if let Some(lang) = Lang::parse(code) {
+        tracing::info!("Set language, user: {}, lang: {}", msg.chat.id, lang.code());
+        state
+            .update_chat(state.key(&msg), |chat| chat.settings.lang = Some(lang))
+            .await;
+        state.mark_dirty();
+        format!("{} {}.", lang.text(Text::LangSet), lang.code())
+    } else {
+        format!(
+            "{} {}",
+            state.lang(&msg).await.text(Text::UnknownLang),
+            available
+        )
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// A setting of the chat that is on, off or the configured default.
+struct Toggle {
+    /// How it's called in messages, e.g. `Streaming`.
+    name: &'static str,
+    field: fn(&mut ChatSettings) -> &mut Option<bool>,
+    /// Whether it's on in a chat with the given settings.
+    enabled: fn(&AppState, &ChatSettings) -> bool,
+    /// Shown after whether it's on.
+    note: &'static str,
+}
+
+/// Shows or sets `toggle` in the chat, or resets it to the default with
+/// `default`.
+async fn set_toggle(
+    value: String,
+    toggle: Toggle,
+    bot: Bot,
+    state: State,
+    msg: Message,
+) -> HandleResult {
+    let value = value.trim();
+    let content = match value.to_lowercase().as_str() {
+        "" => {
+            let settings = state.settings(state.key(&msg)).await;
+            let current = if (toggle.enabled)(&state, &settings) {
+                "on"
+            } else {
+                "off"
+            };
+            format!("{} is {}{}.", toggle.name, current, toggle.note)
+        }
+        value @ ("on" | "off" | "default") => {
+            let enabled = match value {
+                "on" => Some(true),
+                "off" => Some(false),
+                _ => None,
+            };
+            tracing::info!(
+                "Set {}, user: {}, value: {}",
+                toggle.name.to_lowercase(),
+                msg.chat.id,
+                value
+            );
+            state
+                .update_chat(state.key(&msg), |chat| {
+                    *(toggle.field)(&mut chat.settings) = enabled
+                })
+                .await;
+            state.mark_dirty();
+            format!("{} set to {}.", toggle.name, value)
+        }
+        _ => format!("Unknown value \"{}\". Use on, off or default.", value),
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Splits an optional trailing size such as `512x512` off an image prompt.
+fn parse_image_prompt(prompt: &str) -> (&str, ImageSize) {
+    let prompt = prompt.trim();
+    let size = prompt
+        .rsplit_once(char::is_whitespace)
+        .and_then(|(rest, size)| {
+            let size = match size {
+                "256x256" => ImageSize::S256x256,
+                "512x512" => ImageSize::S512x512,
+                "1024x1024" => ImageSize::S1024x1024,
+                _ => return None,
+            };
+            Some((rest.trim_end(), size))
+        });
+    size.unwrap_or((prompt, ImageSize::S1024x1024))
+}
+
+fn is_content_policy_violation(err: &OpenAIError) -> bool {
+    match err {
+        OpenAIError::ApiError(err) => {
+            err.code.as_ref().and_then(|code| code.as_str()) == Some("content_policy_violation")
+                || err.message.contains("safety system")
+        }
+        _ => false,
+    }
+}
+
+async fn generate_image(
+    prompt: String,
+    bot: Bot,
+    client: Client,
+    state: State,
+    msg: Message,
+) -> HandleResult {
+    let (prompt, size) = parse_image_prompt(&prompt);
+    if prompt.is_empty() {
+        bot.send_message(
+            msg.chat.id,
+            "Usage: /image <prompt> [256x256|512x512|1024x1024]",
+        )
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+        return Ok(());
+    }
+    if let Err(wait) = state.check_rate_limit(msg.chat.id) {
+        return reply_rate_limited(bot, state, msg, wait).await;
+    }
+    if !state.take_demo_completion(msg.chat.id) {
+        return reply_demo_limited(bot, state, msg).await;
+    }
+
+    tracing::info!("Generate image, user: {}, prompt: {}", msg.chat.id, prompt);
+    bot.send_chat_action(msg.chat.id, ChatAction::UploadPhoto)
+        .await?;
+
+    let request = CreateImageRequestArgs::default()
+        .prompt(prompt)
+        .size(size)
+        .build()?;
+    let response = match client.images().create(request).await {
+        Ok(response) => response,
+        Err(err) if is_content_policy_violation(&err) => {
+            tracing::info!("Image prompt rejected, user: {}: {}", msg.chat.id, err);
+            bot.send_message(
+                msg.chat.id,
+                "Your prompt was rejected by OpenAI's content policy, please try another one.",
+            )
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+            return Ok(());
+        }
+        Err(err) => {
+            let content = match ApiFailure::of(&err) {
+                ApiFailure::Other => {
+                    tracing::error!("Image generation failed, user: {}: {}", msg.chat.id, err);
+                    "Failed to generate the image, please try again later."
+                }
+                failure => {
+                    failure.log(msg.chat.id, &err);
+                    failure.message()
+                }
+            };
+            bot.send_message(msg.chat.id, content)
+                .reply_to(state.reply_to(&msg).await)
+                .await?;
+            return Ok(());
+        }
+    };
+
+    for image in response.data {
+        let Image::Url { ref url, .. } = *image else {
+            continue;
+        };
+        match url::Url::parse(url) {
+            Ok(url) => {
+                bot.send_photo(msg.chat.id, InputFile::url(url))
+                    .reply_to(state.reply_to(&msg).await)
+                    .await?;
+            }
+            Err(err) => tracing::error!("Invalid image url {}: {}", url, err),
+        }
+    }
+
+    Ok(())
+}
+
+/// Shows or sets a numeric sampling parameter of the chat, rejecting values
+/// outside `range`.
+async fn set_sampling_param(
+    value: String,
+    name: &str,
+    range: RangeInclusive<f32>,
+    field: fn(&mut ChatSettings) -> &mut Option<f32>,
+    bot: Bot,
+    state: State,
+    msg: Message,
+) -> HandleResult {
+    let value = value.trim();
+    let content = if value.is_empty() {
+        let current = *field(&mut state.settings(state.key(&msg)).await);
+        match current {
+            Some(current) => format!("Current {}: {}", name, current),
+            None => format!("Current {}: default", name),
+        }
+    } else {
+        match value.parse::<f32>() {
+            Ok(value) if range.contains(&value) => {
+                tracing::info!("Set {}, user: {}, value: {}", name, msg.chat.id, value);
+                state
+                    .update_chat(state.key(&msg), |chat| {
+                        *field(&mut chat.settings) = Some(value)
+                    })
+                    .await;
+                state.mark_dirty();
+                format!("{} set to {}.", name, value)
+            }
+            _ => format!(
+                "Invalid {} \"{}\", expected a number between {} and {}.",
+                name,
+                value,
+                range.start(),
+                range.end()
+            ),
+        }
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Shows or sets the maximum reply length of the chat in tokens, or resets it
+/// to the default with `default`.
+async fn set_max_tokens(value: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let value = value.trim();
+    let key = state.key(&msg);
+    let settings = state.settings(key).await;
+    let limit = max_reply_tokens(settings.model());
+
+    let content = if value.is_empty() {
+        match state.max_tokens(&settings) {
+            Some(max_tokens) if settings.max_tokens.is_some() => {
+                format!("Current max_tokens: {}", max_tokens)
+            }
+            Some(max_tokens) => format!("Current max_tokens: {} (default)", max_tokens),
+            None => "Current max_tokens: unlimited".to_owned(),
+        }
+    } else if value.eq_ignore_ascii_case("default") {
+        state
+            .update_chat(key, |chat| chat.settings.max_tokens = None)
+            .await;
+        state.mark_dirty();
+        "max_tokens reset to the default.".to_owned()
+    } else {
+        match value.parse::<u16>() {
+            Ok(max_tokens) if (1..=limit).contains(&max_tokens) => {
+                tracing::info!(
+                    "Set max_tokens, user: {}, value: {}",
+                    msg.chat.id,
+                    max_tokens
+                );
+                state
+                    .update_chat(key, |chat| chat.settings.max_tokens = Some(max_tokens))
+                    .await;
+                state.mark_dirty();
+                format!("max_tokens set to {}.", max_tokens)
+            }
+            _ => format!(
+                "Invalid max_tokens \"{}\", expected a number between 1 and {} for {}.",
+                value,
+                limit,
+                settings.model()
+            ),
+        }
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Shows or sets the seed of the chat that makes replies mostly reproducible,
+/// or clears it with `off`.
+async fn set_seed(value: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let value = value.trim();
+    let content = if value.is_empty() {
+        let (seed, fingerprint) = state
+            .store()
+            .load(state.key(&msg))
+            .await
+            .map(|chat| (chat.settings.seed, chat.fingerprint))
+            .unwrap_or_default();
+        let seed = seed.map_or("Current seed: off".to_owned(), |seed| {
+            format!("Current seed: {}", seed)
+        });
+        match fingerprint {
+            Some(fingerprint) => format!("{}\nSystem fingerprint: {}", seed, fingerprint),
+            None => seed,
+        }
+    } else if value.eq_ignore_ascii_case("off") {
+        state
+            .update_chat(state.key(&msg), |chat| chat.settings.seed = None)
+            .await;
+        state.mark_dirty();
+        "Seed cleared.".to_owned()
+    } else {
+        match value.parse::<i64>() {
+            Ok(seed) => {
+                tracing::info!("Set seed, user: {}, value: {}", msg.chat.id, seed);
+                state
+                    .update_chat(state.key(&msg), |chat| chat.settings.seed = Some(seed))
+                    .await;
+                state.mark_dirty();
+                format!("Seed set to {}.", seed)
+            }
+            _ => format!("Invalid seed \"{}\", expected an integer or off.", value),
+        }
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Shows or sets the number of replies requested at once for the user to pick
+/// from, or goes back to single replies with `off`.
+async fn set_variants(value: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let value = value.trim();
+    let content = if value.is_empty() {
+        let variants = state.settings(state.key(&msg)).await.variants;
+        match variants {
+            Some(n) => format!("Current variants: {}", n),
+            None => "Current variants: off".to_owned(),
+        }
+    } else if value.eq_ignore_ascii_case("off") {
+        state
+            .update_chat(state.key(&msg), |chat| chat.settings.variants = None)
+            .await;
+        state.mark_dirty();
+        "Variants turned off.".to_owned()
+    } else {
+        match value.parse::<u8>() {
+            Ok(n) if (2..=MAX_VARIANTS).contains(&n) => {
+                tracing::info!("Set variants, user: {}, value: {}", msg.chat.id, n);
+                state
+                    .update_chat(state.key(&msg), |chat| chat.settings.variants = Some(n))
+                    .await;
+                state.mark_dirty();
+                format!("Replies now come in {} variants, pick one to keep it.", n)
+            }
+            _ => format!(
+                "Invalid number of variants \"{}\", expected 2-{} or off.",
+                value, MAX_VARIANTS
+            ),
+        }
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Shows or sets the comma-separated sequences the model stops generating at,
+/// or clears them with `off`. `\n` stands for a line break.
+async fn set_stop_sequences(value: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let value = value.trim();
+    let content = if value.is_empty() {
+        let stop = state.settings(state.key(&msg)).await.stop;
+        match stop.is_empty() {
+            true => "No stop sequences set.".to_owned(),
+            false => format!("Current stop sequences: {}", format_stop_sequences(&stop)),
+        }
+    } else if value.eq_ignore_ascii_case("off") {
+        state
+            .update_chat(state.key(&msg), |chat| chat.settings.stop.clear())
+            .await;
+        state.mark_dirty();
+        "Stop sequences cleared.".to_owned()
+    } else {
+        let stop: Vec<String> = value
+            .split(',')
+            .map(|sequence| sequence.trim().replace("\\n", "\n"))
+            .filter(|sequence| !sequence.is_empty())
+            .collect();
+        if stop.len() > MAX_STOP_SEQUENCES {
+            format!(
+                "Too many stop sequences, at most {} are allowed.",
+                MAX_STOP_SEQUENCES
+            )
+        } else {
+            tracing::info!(
+                "Set stop sequences, user: {}, value: {:?}",
+                msg.chat.id,
+                stop
+            );
+            let content = format!("Stop sequences set to {}.", format_stop_sequences(&stop));
+            state
+                .update_chat(state.key(&msg), |chat| chat.settings.stop = stop)
+                .await;
+            state.mark_dirty();
+            content
+        }
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+fn format_stop_sequences(stop: &[String]) -> String {
+    stop.iter()
+        .map(|sequence| format!("{:?}", sequence))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn messages_to_markdown(messages: &[ChatMessage]) -> String {
+    messages
+        .iter()
+        .map(|message| match message.created_at {
+            Some(created_at) => format!(
+                "## {} ({})\n\n{}\n",
+                message.role,
+                created_at.format("%Y-%m-%d %H:%M UTC"),
+                message.content.trim()
+            ),
+            None => format!("## {}\n\n{}\n", message.role, message.content.trim()),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+async fn export_history(format: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let messages = state.store().get(state.key(&msg)).await;
+    if messages.is_empty() {
+        bot.send_message(msg.chat.id, "Nothing to export.")
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+        return Ok(());
+    }
+
+    let (data, extension) = match format.trim().to_lowercase().as_str() {
+        "" | "json" => (
+            serde_json::to_vec_pretty(&messages).map_err(io::Error::from)?,
+            "json",
+        ),
+        "md" | "markdown" => (messages_to_markdown(&messages).into_bytes(), "md"),
+        format => {
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "Unknown export format \"{}\". Use json or markdown.",
+                    format
+                ),
+            )
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+            return Ok(());
+        }
+    };
+
+    tracing::info!(
+        "Export history, user: {}, format: {}",
+        msg.chat.id,
+        extension
+    );
+    let file = InputFile::memory(data).file_name(format!("chat-{}.{}", msg.chat.id, extension));
+    bot.send_document(msg.chat.id, file)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Backs up the histories of all chats, as a file sent to the admin or in
+/// the backup directory.
+async fn export_all(bot: Bot, state: State, msg: Message) -> HandleResult {
+    if !state.config.admins.contains(&msg.chat.id) {
+        bot.send_message(msg.chat.id, "Only admins can use /export_all.")
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+        return Ok(());
+    }
+
+    let snapshot = state.snapshot().await;
+    let chats = snapshot.len();
+    // Serialized without holding any history, which can take a while.
+    let data = tokio::task::spawn_blocking(move || serde_json::to_vec_pretty(&snapshot))
+        .await
+        .map_err(io::Error::from)?
+        .map_err(io::Error::from)?;
+    let file_name = format!("backup-{}.json", Utc::now().format("%Y%m%d-%H%M%S"));
+    tracing::info!(
+        "Export all, user: {}, chats: {}, bytes: {}",
+        msg.chat.id,
+        chats,
+        data.len()
+    );
+
+    match state.config.backup_dir {
+        Some(ref dir) => {
+            let path = dir.join(file_name);
+            let content = match tokio::fs::write(&path, data).await {
+                Ok(()) => format!("Backed up {} chats to {}.", chats, path.display()),
+                Err(err) => {
+                    tracing::error!("Failed to write backup to {}: {}", path.display(), err);
+                    format!("Failed to write the backup: {}", err)
+                }
+            };
+            bot.send_message(msg.chat.id, content)
+                .reply_to(state.reply_to(&msg).await)
+                .await?;
+        }
+        None => {
+            bot.send_document(msg.chat.id, InputFile::memory(data).file_name(file_name))
+                .caption(format!("Backup of {} chats.", chats))
+                .reply_to(state.reply_to(&msg).await)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends `text` to every chat with a history, pausing between the messages to
+/// stay below Telegram's broadcast limits.
+///
+/// Chats that blocked the bot or are gone are removed from the histories.
+async fn broadcast(text: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    if !state.config.admins.contains(&msg.chat.id) {
+        bot.send_message(msg.chat.id, "Only admins can use /broadcast.")
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+        return Ok(());
+    }
+    let text = text.trim();
+    if text.is_empty() {
+        bot.send_message(msg.chat.id, "Usage: /broadcast <message>")
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+        return Ok(());
+    }
+
+    let chats: BTreeSet<ChatId> = state
+        .store()
+        .keys()
+        .await
+        .iter()
+        .map(|key| key.chat)
+        .collect();
+    tracing::info!("Broadcast, user: {}, chats: {}", msg.chat.id, chats.len());
+    let (mut sent, mut failed, mut removed) = (0, 0, 0);
+    for chat_id in chats {
+        match retry_after(|| bot.send_message(chat_id, text).send()).await {
+            Ok(_) => sent += 1,
+            Err(RequestError::Api(
+                ApiError::BotBlocked | ApiError::BotKicked | ApiError::UserDeactivated,
+            )) => {
+                tracing::info!("Removing chat that blocked the bot: {}", chat_id);
+                for key in state.store().keys().await {
+                    if key.chat == chat_id {
+                        state.store().remove(key).await;
+                    }
+                }
+                state.mark_dirty();
+                removed += 1;
+            }
+            Err(err) => {
+                tracing::warn!("Failed to broadcast to {}: {}", chat_id, err);
+                failed += 1;
+            }
+        }
+        tokio::time::sleep(BROADCAST_INTERVAL).await;
+    }
+
+    bot.send_message(
+        msg.chat.id,
+        format!(
+            "Broadcast to {} chats, {} failed, {} removed for blocking the bot.",
+            sent, failed, removed
+        ),
+    )
+    .reply_to(state.reply_to(&msg).await)
+    .await?;
+
+    Ok(())
+}
+
+/// Parses an exported conversation, rejecting it unless every message is
+/// well-formed.
+fn parse_history(data: &[u8]) -> Result<ChatMessages, String> {
+    let messages: ChatMessages = serde_json::from_slice(data)
+        .map_err(|err| format!("Invalid conversation file: {}", err))?;
+    if messages.is_empty() {
+        return Err("The conversation file contains no messages.".to_owned());
+    }
+    if let Some(i) = messages.iter().position(|m| m.content.trim().is_empty()) {
+        return Err(format!("Message {} has no content.", i + 1));
+    }
+    if let Some(i) = messages
+        .iter()
+        .position(|m| matches!(m.role, Role::Tool | Role::Function))
+    {
+        return Err(format!("Message {} has an unsupported role.", i + 1));
+    }
+    Ok(messages)
+}
+
+/// Replaces the chat history with the JSON document attached to `msg`, or to
+/// the message it replies to.
+pub(crate) async fn load_history(bot: Bot, state: State, msg: Message) -> HandleResult {
+    let document = msg
+        .document()
+        .or_else(|| msg.reply_to_message().and_then(Message::document));
+    let Some(document) = document else {
+        bot.send_message(
+            msg.chat.id,
+            "Send an exported JSON file with the caption /load, or reply to one with /load.",
+        )
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+        return Ok(());
+    };
+    if document.file.size > LOAD_FILE_LIMIT {
+        bot.send_message(msg.chat.id, "The conversation file is too large.")
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+        return Ok(());
+    }
+
+    let file = bot.get_file(&document.file.id).await?;
+    let mut data = Vec::new();
+    bot.download_file(&file.path, &mut data).await?;
+
+    let content = match parse_history(&data) {
+        Ok(messages) => {
+            tracing::info!(
+                "Load history, user: {}, messages: {}",
+                msg.chat.id,
+                messages.len()
+            );
+            let count = messages.len();
+            state.store().replace(state.key(&msg), messages).await;
+            state.mark_dirty();
+            format!("Conversation loaded, {} messages.", count)
+        }
+        Err(err) => err,
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Shows the effective settings of the chat, marking the ones not overridden
+/// with `(default)`.
+async fn show_settings(bot: Bot, state: State, msg: Message) -> HandleResult {
+    fn line(name: &str, value: Option<String>, default: &str) -> String {
+        match value {
+            Some(value) => format!("{}: {}", name, value),
+            None => format!("{}: {} (default)", name, default),
+        }
+    }
+
+    let key = state.key(&msg);
+    let (settings, conversation, fingerprint) = state
+        .store()
+        .load(key)
+        .await
+        .map(|chat| (chat.settings, chat.conversation, chat.fingerprint))
+        .unwrap_or_else(|| (ChatSettings::default(), default_conversation(), None));
+    let prompt = system_prompt(&state.store().get(key).await).map(str::to_owned);
+    let model = settings.model();
+
+    let lines = [
+        line("model", settings.model.clone(), MODEL),
+        format!("language: {}", state.lang(&msg).await.code()),
+        line(
+            "format",
+            (settings.format != Format::default()).then(|| settings.format.name().to_owned()),
+            Format::default().name(),
+        ),
+        format!("json mode: {}", if settings.json { "on" } else { "off" }),
+        line(
+            "temperature",
+            settings.temperature.map(|v| v.to_string()),
+            "API default",
+        ),
+        line(
+            "top_p",
+            settings.top_p.map(|v| v.to_string()),
+            "API default",
+        ),
+        line(
+            "presence_penalty",
+            settings.presence_penalty.map(|v| v.to_string()),
+            "API default",
+        ),
+        line(
+            "frequency_penalty",
+            settings.frequency_penalty.map(|v| v.to_string()),
+            "API default",
+        ),
+        line(
+            "max_tokens",
+            settings.max_tokens.map(|v| v.to_string()),
+            &state
+                .max_tokens(&settings)
+                .map_or("unlimited".to_owned(), |v| v.to_string()),
+        ),
+        format!(
+            "seed: {}",
+            settings.seed.map_or("off".to_owned(), |v| v.to_string())
+        ),
+        format!(
+            "variants: {}",
+            settings
+                .variants
+                .map_or("off".to_owned(), |n| n.to_string())
+        ),
+        format!(
+            "stop sequences: {}",
+            match settings.stop.is_empty() {
+                true => "none".to_owned(),
+                false => format_stop_sequences(&settings.stop),
+            }
+        ),
+        format!(
+            "system fingerprint: {}",
+            fingerprint.as_deref().unwrap_or("unknown")
+        ),
+        format!("conversation: {}", conversation),
+        format!(
+            "system prompt: {}",
+            match prompt {
+                Some(_) if settings.persona == prompt => "persona",
+                Some(_) if state.config.default_prompt.as_deref() == prompt.as_deref() => "default",
+                Some(_) => "custom",
+                None => "none",
+            }
+        ),
+        format!(
+            "token budget: {}",
+            state.token_budget(model, state.max_tokens(&settings))
+        ),
+        format!(
+            "history cap: {}",
+            state
+                .max_history(&msg.chat)
+                .map_or("unlimited".to_owned(), |max| format!("{} messages", max))
+        ),
+        line(
+            "streaming",
+            settings
+                .streaming
+                .map(|v| if v { "on" } else { "off" }.to_owned()),
+            if state.config.streaming { "on" } else { "off" },
+        ),
+        line(
+            "progress",
+            settings
+                .progress
+                .map(|v| if v { "on" } else { "off" }.to_owned()),
+            if state.config.progress { "on" } else { "off" },
+        ),
+        line(
+            "reply threading",
+            settings
+                .reply_threading
+                .map(|v| if v { "on" } else { "off" }.to_owned()),
+            if state.config.reply_threading {
+                "on"
+            } else {
+                "off"
+            },
+        ),
+        format!(
+            "tools: {}",
+            if state.config.tools_enabled {
+                "on"
+            } else {
+                "off"
+            }
+        ),
+    ];
+
+    bot.send_message(msg.chat.id, lines.join("\n"))
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Approximate heap and inline size of `messages`, in bytes.
+fn messages_size(messages: &ChatMessages) -> usize {
+    messages
+        .iter()
+        .map(|message| {
+            std::mem::size_of::<ChatMessage>()
+                + message.content.capacity()
+                + message.name.as_ref().map_or(0, String::capacity)
+        })
+        .sum()
+}
+
+fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m {}s", minutes, secs % 60)
+    }
+}
+
+/// Asks an admin to confirm clearing all histories with a follow-up "yes".
+async fn request_reset_all(arg: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let content = if !state.config.admins.contains(&msg.chat.id) {
+        "Only admins can use /reset_all.".to_owned()
+    } else {
+        match arg.trim() {
+            arg @ ("" | "file") => {
+                let delete_file = arg == "file";
+                state
+                    .pending_resets
+                    .insert(msg.chat.id, (Instant::now(), delete_file));
+                let keys = state.store().keys().await;
+                let mut conversations = 0;
+                for &key in &keys {
+                    if let Some(chat) = state.store().load(key).await {
+                        conversations += 1 + chat.conversations.len();
+                    }
+                }
+                format!(
+                    "This clears {} conversations of {} chats{}. Send \"yes\" within {} seconds to confirm.",
+                    conversations,
+                    keys.len(),
+                    if delete_file { " and deletes the history file" } else { "" },
+                    RESET_CONFIRM_TIMEOUT.as_secs()
+                )
+            }
+            _ => "Usage: /reset_all [file]".to_owned(),
+        }
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Clears all histories if `msg` confirms a pending `/reset_all`, returning
+/// whether it did.
+pub(crate) async fn confirm_reset_all(
+    bot: &Bot,
+    state: &State,
+    msg: &Message,
+    content: &str,
+) -> Result<bool, AppError> {
+    if !content.trim().eq_ignore_ascii_case("yes") {
+        return Ok(false);
+    }
+    let Some((_, (requested, delete_file))) = state.pending_resets.remove(&msg.chat.id) else {
+        return Ok(false);
+    };
+    if requested.elapsed() > RESET_CONFIRM_TIMEOUT {
+        return Ok(false);
+    }
+
+    let mut conversations = 0;
+    for key in state.store().keys().await {
+        if let Some(chat) = state.store().remove(key).await {
+            conversations += 1 + chat.conversations.len();
+        }
+    }
+    tracing::warn!(
+        "Cleared all {} conversations, admin: {}",
+        conversations,
+        msg.chat.id
+    );
+
+    let mut content = format!("Cleared {} conversations.", conversations);
+    match state.persistence {
+        Some(ref persistence) if delete_file => match fs::remove_file(&persistence.path) {
+            Ok(()) => content.push_str(" The history file was deleted."),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                tracing::error!("Failed to delete {}: {}", persistence.path.display(), err);
+                content.push_str(" Failed to delete the history file.");
+            }
+        },
+        _ => state.mark_dirty(),
+    }
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(msg).await)
+        .await?;
+
+    Ok(true)
+}
+
+/// Replies with the uptime and the round trip time to the OpenAI API, for
+/// uptime monitors.
+async fn ping(bot: Bot, client: Client, state: State, msg: Message) -> HandleResult {
+    let started = Instant::now();
+    let api = match tokio::time::timeout(API_CHECK_TIMEOUT, client.models().list()).await {
+        Ok(Ok(_)) => format!("{} ms", started.elapsed().as_millis()),
+        Ok(Err(err)) => {
+            tracing::warn!("Ping failed, user: {}: {}", msg.chat.id, err);
+            "unreachable".to_owned()
+        }
+        Err(_) => format!("no response within {:?}", API_CHECK_TIMEOUT),
+    };
+    let content = format!(
+        "pong\nuptime: {}\nOpenAI: {}",
+        format_uptime(state.started.elapsed()),
+        api
+    );
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Shows the ids the bot sees for the chat and the sender, e.g. to configure
+/// `ALLOWED_CHAT_IDS` and `ADMIN_CHAT_IDS`.
+async fn whoami(bot: Bot, state: State, msg: Message) -> HandleResult {
+    let kind = if msg.chat.is_private() {
+        "private"
+    } else if msg.chat.is_group() {
+        "group"
+    } else if msg.chat.is_supergroup() {
+        "supergroup"
+    } else {
+        "channel"
+    };
+    let user = match msg.from() {
+        Some(user) => match user.username {
+            Some(ref username) => format!("{} (@{})", user.id, username),
+            None => user.id.to_string(),
+        },
+        None => "unknown".to_owned(),
+    };
+    let lines = [
+        format!("chat id: {}", msg.chat.id),
+        format!("chat type: {}", kind),
+        format!("user id: {}", user),
+        format!("history: {}", state.key(&msg)),
+        format!(
+            "admin: {}",
+            if state.config.admins.contains(&msg.chat.id) {
+                "yes"
+            } else {
+                "no"
+            }
+        ),
+    ];
+
+    bot.send_message(msg.chat.id, lines.join("\n"))
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Appends `text` to `FEEDBACK_PATH` and forwards it to `FEEDBACK_CHAT_ID`.
+///
+/// Failing to do either is logged, the user is thanked all the same.
+pub(crate) async fn feedback(text: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let text = text.trim();
+    if text.is_empty() {
+        bot.send_message(msg.chat.id, "Usage: /feedback <text>")
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+        return Ok(());
+    }
+
+    let user_id = msg.from().map(|user| user.id.0);
+    tracing::info!("Feedback, user: {}, chars: {}", msg.chat.id, text.len());
+    if let Some(ref path) = state.config.feedback_path {
+        let mut line = serde_json::json!({
+            "timestamp": Utc::now(),
+            "chat_id": msg.chat.id,
+            "user_id": user_id,
+            "text": text,
+        })
+        .to_string();
+        line.push('\n');
+        let result = async {
+            tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?
+                .write_all(line.as_bytes())
+                .await
+        }
+        .await;
+        if let Err(err) = result {
+            tracing::error!("Failed to write feedback to {}: {}", path.display(), err);
+        }
+    }
+    if let Some(chat_id) = state.config.feedback_chat {
+        let user = user_id.map_or_else(|| "unknown".to_owned(), |id| id.to_string());
+        let content = format!(
+            "Feedback from chat {}, user {}:\n{}",
+            msg.chat.id, user, text
+        );
+        if let Err(err) = bot.send_message(chat_id, content).await {
+            tracing::error!("Failed to forward feedback to {}: {}", chat_id, err);
+        }
+    }
+
+    bot.send_message(msg.chat.id, "Thanks for your feedback!")
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Shows global statistics of the bot to admins.
+async fn show_stats(bot: Bot, state: State, msg: Message) -> HandleResult {
+    if !state.config.admins.contains(&msg.chat.id) {
+        bot.send_message(msg.chat.id, "Only admins can use /stats.")
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+        return Ok(());
+    }
+
+    // Every chat is only copied while it is being counted.
+    let (mut chats, mut conversations, mut messages, mut bytes) = (0, 0, 0, 0);
+    for key in state.store().keys().await {
+        let Some(chat) = state.store().load(key).await else {
+            continue;
+        };
+        let active = state.store().get(key).await;
+        chats += 1;
+        bytes += std::mem::size_of::<ChatState>();
+        for history in std::iter::once(&active).chain(chat.conversations.values()) {
+            if !history.is_empty() {
+                conversations += 1;
+            }
+            messages += history.len();
+            bytes += messages_size(history);
+        }
+    }
+
+    let mut content = format!(
+        "chats: {}\nconversations: {}\nmessages: {}\nhistory size: {:.1} KiB\nin-flight replies: {}\nuptime: {}",
+        chats,
+        conversations,
+        messages,
+        bytes as f64 / 1024.0,
+        state.streams.len(),
+        format_uptime(state.started.elapsed())
+    );
+    if let Some(limit) = state.config.demo_limit {
+        content.push_str(&format!(
+            "\ndemo completions: {}/{}",
+            state.demo_served.load(Ordering::Relaxed),
+            limit
+        ));
+    }
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+async fn show_usage(bot: Bot, state: State, msg: Message) -> HandleResult {
+    let mut usage: Vec<(String, TokenUsage)> = state
+        .store()
+        .load(state.key(&msg))
+        .await
+        .map(|chat| chat.usage.into_iter().collect())
+        .unwrap_or_default();
+    usage.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let content = if usage.is_empty() {
+        "No tokens used yet.".to_owned()
+    } else {
+        let mut lines = vec!["Token usage of this chat, not reset by /clear:".to_owned()];
+        let (mut total_tokens, mut total_cost) = (0, 0.0);
+        for (model, usage) in usage {
+            let tokens = usage.prompt_tokens + usage.completion_tokens;
+            total_tokens += tokens;
+            let cost = match state.config.prices.get(&model) {
+                Some(price) => {
+                    total_cost += price.cost(&usage);
+                    format!("~${:.4}", price.cost(&usage))
+                }
+                None => "unknown cost".to_owned(),
+            };
+            lines.push(format!(
+                "{}: {} prompt + {} completion tokens, {}",
+                model, usage.prompt_tokens, usage.completion_tokens, cost
+            ));
+        }
+        lines.push(format!(
+            "Total: {} tokens, ~${:.4}",
+            total_tokens, total_cost
+        ));
+        lines.join("\n")
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Counts the messages of the active conversation by role, and the user
+/// messages that got a reply.
+async fn show_count(bot: Bot, state: State, msg: Message) -> HandleResult {
+    let messages = state.store().get(state.key(&msg)).await;
+    let content = match messages.is_empty() {
+        false => {
+            let count = |role| messages.iter().filter(|m| m.role == role).count();
+            let turns = messages
+                .windows(2)
+                .filter(|pair| pair[0].role == Role::User && pair[1].role == Role::Assistant)
+                .count();
+            format!(
+                "{} messages: {} system, {} user, {} assistant.\n{} complete turns.",
+                messages.len(),
+                count(Role::System),
+                count(Role::User),
+                count(Role::Assistant),
+                turns
+            )
+        }
+        true => "The history is empty.".to_owned(),
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+async fn show_tokens(bot: Bot, state: State, msg: Message) -> HandleResult {
+    let settings = state.settings(state.key(&msg)).await;
+    let messages = state.store().get(state.key(&msg)).await;
+
+    let content = if messages.is_empty() {
+        "The history is empty.".to_owned()
+    } else {
+        let model = settings.model();
+        let tokens = count_prompt_tokens(model, &messages);
+        let budget = state.token_budget(model, state.max_tokens(&settings));
+        match budget.checked_sub(tokens) {
+            Some(left) => format!(
+                "The history takes up about {} tokens of {}, {} are left before older messages are trimmed.",
+                tokens, model, left
+            ),
+            None => format!(
+                "The history takes up about {} tokens of {}, {} over the budget of {}, older messages will be trimmed.",
+                tokens,
+                model,
+                tokens - budget,
+                budget
+            ),
+        }
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+fn validate_conversation_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        Err("Please give the conversation a name.".to_owned())
+    } else if name.chars().count() > CONVERSATION_NAME_LIMIT || name.contains(char::is_whitespace) {
+        Err(format!(
+            "Conversation names must be a single word of at most {} characters.",
+            CONVERSATION_NAME_LIMIT
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+async fn new_conversation(name: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let name = name.trim();
+    let content = match validate_conversation_name(name) {
+        Err(err) => err,
+        Ok(()) => {
+            let key = state.key(&msg);
+            let chat = state.store().load(key).await.unwrap_or_default();
+            if chat.has_conversation(name) {
+                format!(
+                    "Conversation \"{}\" already exists, use /switch {} instead.",
+                    name, name
+                )
+            } else {
+                tracing::info!("New conversation, user: {}, name: {}", msg.chat.id, name);
+                let initial = state.initial_messages(&chat.settings);
+                let active = state.store().get(key).await;
+                let messages = state
+                    .update_chat(key, |chat| chat.switch_conversation(name, initial, active))
+                    .await;
+                state.store().replace(key, messages).await;
+                state.mark_dirty();
+                format!("Started conversation \"{}\".", name)
+            }
+        }
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Copies the active conversation into a new one and switches to the copy,
+/// naming it after the original if no name is given.
+async fn fork_conversation(name: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let content = {
+        let key = state.key(&msg);
+        let chat = state.store().load(key).await.unwrap_or_default();
+        let name = match name.trim() {
+            "" => chat.fork_name(),
+            name => name.to_owned(),
+        };
+        match validate_conversation_name(&name) {
+            Err(err) => err,
+            Ok(()) if chat.has_conversation(&name) => {
+                format!("Conversation \"{}\" already exists.", name)
+            }
+            Ok(()) => {
+                tracing::info!("Fork conversation, user: {}, name: {}", msg.chat.id, name);
+                let original = chat.conversation;
+                let active = state.store().get(key).await;
+                let messages = state
+                    .update_chat(key, |chat| {
+                        chat.switch_conversation(&name, active.clone(), active)
+                    })
+                    .await;
+                state.store().replace(key, messages).await;
+                state.mark_dirty();
+                format!(
+                    "Forked \"{}\" into \"{}\", use /switch {} to go back.",
+                    original, name, original
+                )
+            }
+        }
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+async fn switch_conversation(name: String, bot: Bot, state: State, msg: Message) -> HandleResult {
+    let name = name.trim();
+    let key = state.key(&msg);
+    let content = match state.store().load(key).await {
+        Some(chat) if chat.has_conversation(name) => {
+            tracing::info!("Switch conversation, user: {}, name: {}", msg.chat.id, name);
+            let active = state.store().get(key).await;
+            let messages = state
+                .update_chat(key, |chat| {
+                    chat.switch_conversation(name, ChatMessages::new(), active)
+                })
+                .await;
+            state.store().replace(key, messages).await;
+            state.mark_dirty();
+            format!("Switched to conversation \"{}\".", name)
+        }
+        _ if name == DEFAULT_CONVERSATION => "Already in the default conversation.".to_owned(),
+        _ => format!(
+            "No conversation named \"{}\", use /new {} to start one.",
+            name, name
+        ),
+    };
+
+    bot.send_message(msg.chat.id, content)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+async fn list_conversations(bot: Bot, state: State, msg: Message) -> HandleResult {
+    let key = state.key(&msg);
+    let lines = match state.store().load(key).await {
+        Some(chat) => {
+            let active = state.store().get(key).await.len();
+            let mut conversations: Vec<(&String, usize)> = chat
+                .conversations
+                .iter()
+                .map(|(name, messages)| (name, messages.len()))
+                .chain([(&chat.conversation, active)])
+                .collect();
+            conversations.sort();
+            conversations
+                .into_iter()
+                .map(|(name, count)| {
+                    let marker = if *name == chat.conversation { "*" } else { " " };
+                    format!("{} {} ({} messages)", marker, name, count)
+                })
+                .collect()
+        }
+        None => vec![format!("* {} (0 messages)", DEFAULT_CONVERSATION)],
+    };
+
+    send_pages(&bot, &state, &msg, &lines, "\n").await?;
+
+    Ok(())
+}
+
+pub(crate) async fn run_command(
+    bot: Bot,
+    client: Client,
+    state: State,
+    msg: Message,
+    cmd: Command,
+) -> HandleResult {
+    match cmd {
+        Command::Help => {
+            let help = Command::help(state.config.admins.contains(&msg.chat.id));
+            bot.send_message(msg.chat.id, help).await?;
+        }
+        Command::Prompt(prompt) => {
+            set_prompt(prompt, bot, state, msg).await?;
+        }
+        Command::Persona(persona) => {
+            set_persona(persona, bot, state, msg).await?;
+        }
+        Command::Preset(name) => {
+            apply_preset(name, bot, state, msg).await?;
+        }
+        Command::Presets => {
+            list_presets(bot, state, msg).await?;
+        }
+        Command::Chat(content) => {
+            complete_chat(content, bot, client, state, msg).await?;
+        }
+        Command::Raw(content) => {
+            complete_raw(content, bot, client, state, msg).await?;
+        }
+        Command::View(arg) => {
+            view_histories(arg, bot, state, msg).await?;
+        }
+        Command::Clear => {
+            clear_history(bot, state, msg).await?;
+        }
+        Command::Model(model) => {
+            set_model(model, bot, state, msg).await?;
+        }
+        Command::Models => {
+            list_models(bot, client, state, msg).await?;
+        }
+        Command::Regenerate => {
+            regenerate(bot, client, state, msg).await?;
+        }
+        Command::RetryLast => {
+            retry_last(bot, client, state, msg).await?;
+        }
+        Command::Continue => {
+            continue_reply(bot, client, state, msg).await?;
+        }
+        Command::Undo => {
+            undo(bot, state, msg).await?;
+        }
+        Command::Json(value) => {
+            set_json(value, bot, state, msg).await?;
+        }
+        Command::Format(format) => {
+            set_format(format, bot, state, msg).await?;
+        }
+        Command::Image(prompt) => {
+            generate_image(prompt, bot, client, state, msg).await?;
+        }
+        Command::Temperature(value) => {
+            let field: fn(&mut ChatSettings) -> &mut Option<f32> = |s| &mut s.temperature;
+            set_sampling_param(value, "temperature", 0.0..=2.0, field, bot, state, msg).await?;
+        }
+        Command::TopP(value) => {
+            let field: fn(&mut ChatSettings) -> &mut Option<f32> = |s| &mut s.top_p;
+            set_sampling_param(value, "top_p", 0.0..=1.0, field, bot, state, msg).await?;
+        }
+        Command::PresencePenalty(value) => {
+            let field: fn(&mut ChatSettings) -> &mut Option<f32> = |s| &mut s.presence_penalty;
+            let name = "presence_penalty";
+            set_sampling_param(value, name, -2.0..=2.0, field, bot, state, msg).await?;
+        }
+        Command::FrequencyPenalty(value) => {
+            let field: fn(&mut ChatSettings) -> &mut Option<f32> = |s| &mut s.frequency_penalty;
+            let name = "frequency_penalty";
+            set_sampling_param(value, name, -2.0..=2.0, field, bot, state, msg).await?;
+        }
+        Command::MaxTokens(value) => {
+            set_max_tokens(value, bot, state, msg).await?;
+        }
+        Command::Seed(value) => {
+            set_seed(value, bot, state, msg).await?;
+        }
+        Command::Variants(value) => {
+            set_variants(value, bot, state, msg).await?;
+        }
+        Command::StopSequences(value) => {
+            set_stop_sequences(value, bot, state, msg).await?;
+        }
+        Command::Lang(code) => {
+            set_lang(code, bot, state, msg).await?;
+        }
+        Command::Stream(value) => {
+            let toggle = Toggle {
+                name: "Streaming",
+                field: |s| &mut s.streaming,
+                enabled: AppState::streaming,
+                note: "",
+            };
+            set_toggle(value, toggle, bot, state, msg).await?;
+        }
+        Command::Progress(value) => {
+            let toggle = Toggle {
+                name: "Progress",
+                field: |s| &mut s.progress,
+                enabled: AppState::progress,
+                note: ", it's only shown with /max_tokens set",
+            };
+            set_toggle(value, toggle, bot, state, msg).await?;
+        }
+        Command::Threading(value) => {
+            let toggle = Toggle {
+                name: "Reply threading",
+                field: |s| &mut s.reply_threading,
+                enabled: AppState::reply_threading,
+                note: "",
+            };
+            set_toggle(value, toggle, bot, state, msg).await?;
+        }
+        Command::Export(format) => {
+            export_history(format, bot, state, msg).await?;
+        }
+        Command::Load => {
+            load_history(bot, state, msg).await?;
+        }
+        Command::Usage => {
+            show_usage(bot, state, msg).await?;
+        }
+        Command::Tokens => {
+            show_tokens(bot, state, msg).await?;
+        }
+        Command::Count => {
+            show_count(bot, state, msg).await?;
+        }
+        Command::New(name) => {
+            new_conversation(name, bot, state, msg).await?;
+        }
+        Command::Switch(name) => {
+            switch_conversation(name, bot, state, msg).await?;
+        }
+        Command::Fork(name) => {
+            fork_conversation(name, bot, state, msg).await?;
+        }
+        Command::Conversations => {
+            list_conversations(bot, state, msg).await?;
+        }
+        Command::Compact => {
+            compact(bot, client, state, msg).await?;
+        }
+        Command::Summarize => {
+            summarize(bot, client, state, msg).await?;
+        }
+        Command::Stop => {
+            stop(bot, state, msg).await?;
+        }
+        Command::System(prompt) => {
+            edit_system_prompt(prompt, bot, state, msg).await?;
+        }
+        Command::Settings => {
+            show_settings(bot, state, msg).await?;
+        }
+        Command::Ping => {
+            ping(bot, client, state, msg).await?;
+        }
+        Command::WhoAmI => {
+            whoami(bot, state, msg).await?;
+        }
+        Command::Feedback(text) => {
+            feedback(text, bot, state, msg).await?;
+        }
+        Command::Stats => {
+            show_stats(bot, state, msg).await?;
+        }
+        Command::ExportAll => {
+            export_all(bot, state, msg).await?;
+        }
+        Command::Broadcast(text) => {
+            broadcast(text, bot, state, msg).await?;
+        }
+        Command::ResetAll(arg) => {
+            request_reset_all(arg, bot, state, msg).await?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(BotCommands, Clone, Debug)]
+#[command(
+    rename_rule = "lowercase",
+    description = "These commands are supported:"
+)]
+pub(crate) enum Command {
+    #[command(description = "display this text.")]
+    Help,
+    #[command(description = "set prompt text.")]
+    Prompt(String),
+    #[command(
+        description = "show or set a prompt that is kept after /clear, or remove it with off."
+    )]
+    Persona(String),
+    #[command(description = "set the prompt to a preset.")]
+    Preset(String),
+    #[command(description = "list the prompt presets.")]
+    Presets,
+    #[command(description = "chat with gpt.")]
+    Chat(String),
+    #[command(
+        description = "chat with gpt without the history and system prompt, nothing is kept."
+    )]
+    Raw(String),
+    #[command(description = "view chat histories, add \"full\" to show everything.")]
+    View(String),
+    #[command(description = "clear history chats.")]
+    Clear,
+    #[command(description = "show or switch the model.")]
+    Model(String),
+    #[command(description = "list the models of the API and which ones can be used.")]
+    Models,
+    #[command(description = "regenerate the last reply.")]
+    Regenerate,
+    #[command(
+        rename = "retry_last",
+        description = "retry the last message if it got no reply."
+    )]
+    RetryLast,
+    #[command(description = "continue the last reply, e.g. when it got cut off.")]
+    Continue,
+    #[command(description = "remove the last exchange.")]
+    Undo,
+    #[command(description = "toggle or set reply formatting (plain, markdown, html, entities).")]
+    Format(String),
+    #[command(description = "show or set whether replies are JSON objects (on, off).")]
+    Json(String),
+    #[command(description = "generate an image, optionally with a size suffix.")]
+    Image(String),
+    #[command(description = "show or set the sampling temperature (0.0-2.0).")]
+    Temperature(String),
+    #[command(
+        rename = "top_p",
+        description = "show or set nucleus sampling top_p (0.0-1.0)."
+    )]
+    TopP(String),
+    #[command(
+        rename = "presence_penalty",
+        description = "show or set the presence penalty (-2.0-2.0)."
+    )]
+    PresencePenalty(String),
+    #[command(
+        rename = "frequency_penalty",
+        description = "show or set the frequency penalty (-2.0-2.0)."
+    )]
+    FrequencyPenalty(String),
+    #[command(
+        rename = "max_tokens",
+        description = "show or set the maximum reply length in tokens, or reset it with default."
+    )]
+    MaxTokens(String),
+    #[command(
+        description = "show or set the seed for reproducible replies, or clear it with off."
+    )]
+    Seed(String),
+    #[command(
+        description = "show or set how many replies to pick from (2-5), or turn it off with off."
+    )]
+    Variants(String),
+    #[command(
+        rename = "stop_sequences",
+        description = "show or set up to 4 comma-separated stop sequences, or clear them with off."
+    )]
+    StopSequences(String),
+    #[command(
+        description = "show or set the language of bot messages (en, zh), or reset it with default."
+    )]
+    Lang(String),
+    #[command(description = "show or set whether replies are streamed (on, off, default).")]
+    Stream(String),
+    #[command(
+        description = "show or set whether streamed replies show their progress (on, off, default)."
+    )]
+    Progress(String),
+    #[command(description = "show or set whether replies quote your message (on, off, default).")]
+    Threading(String),
+    #[command(description = "export the chat history as json or markdown.")]
+    Export(String),
+    #[command(description = "load an exported json conversation, as a caption or reply.")]
+    Load,
+    #[command(description = "show token usage and estimated cost of this chat.")]
+    Usage,
+    #[command(
+        description = "show the size of the history in tokens and what is left of the budget."
+    )]
+    Tokens,
+    #[command(description = "count the messages of the history by role.")]
+    Count,
+    #[command(description = "start a new named conversation.")]
+    New(String),
+    #[command(description = "switch to another conversation.")]
+    Switch(String),
+    #[command(
+        description = "copy this conversation into a new one, optionally named, and switch to it."
+    )]
+    Fork(String),
+    #[command(description = "list conversations.")]
+    Conversations,
+    #[command(description = "summarize older messages to save context.")]
+    Compact,
+    #[command(description = "reply with a summary of this conversation.")]
+    Summarize,
+    #[command(description = "stop the reply being generated.")]
+    Stop,
+    #[command(description = "show or change the system prompt, keeping the history.")]
+    System(String),
+    #[command(description = "show the effective settings of this chat.")]
+    Settings,
+    #[command(description = "check that the bot and OpenAI respond.")]
+    Ping,
+    #[command(description = "show the ids of this chat and you.")]
+    WhoAmI,
+    #[command(description = "send feedback about the bot to its operators.")]
+    Feedback(String),
+    #[command(description = "show global bot statistics, admins only.")]
+    Stats,
+    #[command(
+        rename = "export_all",
+        description = "back up the histories of all chats, admins only."
+    )]
+    ExportAll,
+    #[command(description = "send a message to all chats, admins only.")]
+    Broadcast(String),
+    #[command(
+        rename = "reset_all",
+        description = "clear all histories, add \"file\" to delete the history file, admins only."
+    )]
+    ResetAll(String),
+}
+
+impl Command {
+    /// Commands only admins can use.
+    const ADMIN: &'static [&'static str] = &["stats", "export_all", "broadcast", "reset_all"];
+
+    /// The help text, listing admin commands only to admins.
+    fn help(admin: bool) -> String {
+        let descriptions = Self::descriptions().to_string();
+        if admin {
+            return descriptions;
+        }
+        descriptions
+            .lines()
+            .filter(|line| {
+                let name = line
+                    .strip_prefix('/')
+                    .and_then(|line| line.split_whitespace().next())
+                    .map(|name| name.split('@').next().unwrap_or(name));
+                !name.is_some_and(|name| Self::ADMIN.contains(&name))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
@@ -0,0 +1,2806 @@
+//! Getting replies from the model and streaming them to Telegram.
+
+use async_openai::error::OpenAIError;
+use async_openai::types::{
+    ChatChoiceStream, ChatCompletionMessageToolCall, ChatCompletionMessageToolCallChunk,
+    ChatCompletionRequestAssistantMessage, ChatCompletionRequestMessage,
+    ChatCompletionRequestMessageContentPartImage, ChatCompletionRequestMessageContentPartText,
+    ChatCompletionRequestSystemMessage, ChatCompletionRequestToolMessage,
+    ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent,
+    ChatCompletionResponseFormat, ChatCompletionResponseFormatType, ChatCompletionResponseStream,
+    ChatCompletionStreamResponseDelta, ChatCompletionTool, ChatCompletionToolType,
+    CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateChatCompletionResponse,
+    CreateChatCompletionStreamResponse, FinishReason, FunctionCall, FunctionCallStream,
+    FunctionObject, ImageUrl, ImageUrlDetail, Role, Stop,
+};
+use chrono::Utc;
+use futures::{stream, StreamExt};
+use metrics::{counter, histogram, increment_counter};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use teloxide::payloads::{SendDocument, SendMessage, SendPhoto};
+use teloxide::prelude::*;
+use teloxide::requests::{HasPayload, JsonRequest, MultipartRequest};
+use teloxide::types::{
+    ChatAction, InlineKeyboardButton, InlineKeyboardMarkup, MessageEntity, MessageId, ParseMode,
+};
+use teloxide::{ApiError, RequestError};
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::CoreBPE;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use crate::config::{BusyPolicy, Client, RetryPolicy};
+use crate::state::{
+    ChatKey, ChatMessage, ChatMessages, ChatSettings, KeyPool, PendingVariants, ReplySlots, State,
+    Text, TokenUsage,
+};
+use crate::{AppError, HandleResult};
+
+/// Maximum length of a Telegram message, in UTF-16 code units.
+pub(crate) const MESSAGE_LIMIT: usize = 4096;
+/// Prefixes of the models that accept images.
+const VISION_MODELS: &[&str] = &["gpt-4-vision", "gpt-4-turbo", "gpt-4o"];
+/// Estimated prompt tokens of an image, a 1024x1024 image in high detail.
+const IMAGE_TOKENS: usize = 765;
+/// Telegram shows a chat action for up to 5 seconds.
+const TYPING_INTERVAL: Duration = Duration::from_secs(4);
+/// Maximum total time to wait for Telegram rate limits on a single request.
+const TELEGRAM_MAX_RETRY_WAIT: Duration = Duration::from_secs(30);
+/// Tokens left free in the context window for the model's reply.
+pub(crate) const RESPONSE_TOKEN_RESERVE: usize = 1024;
+/// Tokens of the context window a `max_tokens` limit has to leave for the
+/// prompt.
+const MIN_PROMPT_TOKENS: usize = 512;
+/// Every message is wrapped as `<|start|>{role}\n{content}<|end|>\n`.
+const TOKENS_PER_MESSAGE: usize = 4;
+/// Every reply is primed with `<|start|>assistant<|message|>`.
+const REPLY_PRIMING_TOKENS: usize = 3;
+/// Maximum number of tool call rounds before the model has to answer.
+const MAX_TOOL_ROUNDS: usize = 5;
+/// Number of recent messages kept verbatim when compacting a conversation.
+const COMPACT_KEEP_MESSAGES: usize = 4;
+pub(crate) const SUMMARY_PREFIX: &str = "Summary of the earlier conversation: ";
+pub(crate) const SUMMARY_PROMPT: &str =
+    "Summarize the conversation so far in a concise paragraph, \
+keeping all facts, names, decisions and open questions needed to continue it.";
+/// Sent after a cut off reply to get the rest of it, not stored in the history.
+const CONTINUE_PROMPT: &str = "Continue exactly where you left off, without repeating anything.";
+/// Sent when a reply times out before any of it arrives.
+const TIMEOUT_TEXT: &str = "The reply timed out before anything arrived, please try again.";
+/// Sent instead of a reply while OpenAI recovers from repeated failures.
+const UNAVAILABLE_TEXT: &str = "OpenAI is temporarily unavailable, please try again in a minute.";
+/// Sent once the demo limit is reached.
+const DEMO_LIMIT_TEXT: &str = "The demo limit has been reached, thanks for trying the bot!";
+/// Added to previews of streamed replies to show that more is coming.
+const CURSOR: &str = "▌";
+/// How long a reply waits for a free slot before the user is told their
+/// place in the queue.
+const QUEUE_NOTICE_AFTER: Duration = Duration::from_secs(1);
+/// Maximum number of variants `/variants` asks for.
+pub(crate) const MAX_VARIANTS: u8 = 5;
+/// Added to the prompt in JSON mode, which the API requires to mention JSON.
+const JSON_PROMPT: &str = "Respond with a single valid JSON object and nothing else.";
+
+pub(crate) fn supports_vision(model: &str) -> bool {
+    VISION_MODELS.iter().any(|prefix| model.starts_with(prefix))
+}
+
+impl From<&ChatMessage> for ChatCompletionRequestMessage {
+    fn from(message: &ChatMessage) -> Self {
+        let content = message.content.clone();
+        let name = message.name.clone();
+        match message.role {
+            Role::System => ChatCompletionRequestSystemMessage {
+                content,
+                role: Role::System,
+                name,
+            }
+            .into(),
+            Role::Assistant => ChatCompletionRequestAssistantMessage {
+                content: Some(content),
+                role: Role::Assistant,
+                name,
+                ..Default::default()
+            }
+            .into(),
+            // Tool results are never stored in the history.
+            Role::User | Role::Tool | Role::Function => {
+                let content = if message.images.is_empty() {
+                    ChatCompletionRequestUserMessageContent::Text(content)
+                } else {
+                    let text = ChatCompletionRequestMessageContentPartText {
+                        r#type: "text".to_owned(),
+                        text: content,
+                    };
+                    let images = message.images.iter().map(|url| {
+                        ChatCompletionRequestMessageContentPartImage {
+                            r#type: "image_url".to_owned(),
+                            image_url: ImageUrl {
+                                url: url.clone(),
+                                detail: ImageUrlDetail::Auto,
+                            },
+                        }
+                        .into()
+                    });
+                    ChatCompletionRequestUserMessageContent::Array(
+                        [text.into()].into_iter().chain(images).collect(),
+                    )
+                };
+                ChatCompletionRequestUserMessage {
+                    content,
+                    role: Role::User,
+                    name,
+                }
+                .into()
+            }
+        }
+    }
+}
+
+fn to_request_messages(messages: &[ChatMessage]) -> Vec<ChatCompletionRequestMessage> {
+    messages.iter().map(Into::into).collect()
+}
+
+/// How assistant replies are rendered in Telegram.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Format {
+    #[default]
+    Plain,
+    Markdown,
+    /// Code blocks, inline code and bold text as HTML tags.
+    Html,
+    /// Code blocks and inline code sent as message entities, which keeps
+    /// their indentation in every client.
+    Entities,
+}
+
+/// A reply rendered for Telegram.
+enum Rendered {
+    Parsed(String, ParseMode),
+    Entities(String, Vec<MessageEntity>),
+}
+
+impl Format {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "plain" | "off" => Some(Self::Plain),
+            "markdown" | "on" => Some(Self::Markdown),
+            "html" => Some(Self::Html),
+            "entities" => Some(Self::Entities),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Plain => "plain",
+            Self::Markdown => "markdown",
+            Self::Html => "html",
+            Self::Entities => "entities",
+        }
+    }
+
+    /// The format of the previews of a streamed reply.
+    fn preview(self) -> Self {
+        match self {
+            Self::Entities => Self::Plain,
+            format => format,
+        }
+    }
+
+    /// Renders `text` for sending, followed by `note` in italics, or `None`
+    /// to send it as plain text.
+    fn render(self, text: &str, note: Option<&str>) -> Option<Rendered> {
+        let rendered = match (self, note) {
+            (Self::Plain, None) => return None,
+            (Self::Markdown, _) => {
+                let mut rendered = to_markdown_v2(text);
+                if let Some(note) = note {
+                    rendered.push_str(&format!("\n\n_{}_", escape_markdown_v2(note)));
+                }
+                Rendered::Parsed(rendered, ParseMode::MarkdownV2)
+            }
+            (Self::Html, _) => {
+                let mut rendered = to_html(text);
+                if let Some(note) = note {
+                    rendered.push_str(&format!("\n\n<i>{}</i>", escape_html(note)));
+                }
+                Rendered::Parsed(rendered, ParseMode::Html)
+            }
+            (Self::Plain | Self::Entities, _) => {
+                let (mut rendered, mut entities) = match self {
+                    Self::Entities => to_entities(text),
+                    _ => (text.to_owned(), Vec::new()),
+                };
+                if let Some(note) = note {
+                    rendered.push_str("\n\n");
+                    entities.push(MessageEntity::italic(utf16_len(&rendered), utf16_len(note)));
+                    rendered.push_str(note);
+                }
+                Rendered::Entities(rendered, entities)
+            }
+        };
+        let (Rendered::Parsed(ref text, _) | Rendered::Entities(ref text, _)) = rendered;
+        (utf16_len(text) <= MESSAGE_LIMIT).then_some(rendered)
+    }
+}
+
+/// The largest `max_tokens` allowed for `model`.
+pub(crate) fn max_reply_tokens(model: &str) -> u16 {
+    let context_size = tiktoken_rs::model::get_context_size(model);
+    u16::try_from(context_size.saturating_sub(MIN_PROMPT_TOKENS)).unwrap_or(u16::MAX)
+}
+
+fn tokenizer(model: &str) -> Arc<parking_lot::Mutex<CoreBPE>> {
+    match get_tokenizer(model) {
+        Some(Tokenizer::P50kBase) => tiktoken_rs::p50k_base_singleton(),
+        Some(Tokenizer::R50kBase | Tokenizer::Gpt2) => tiktoken_rs::r50k_base_singleton(),
+        Some(Tokenizer::P50kEdit) => tiktoken_rs::p50k_edit_singleton(),
+        Some(Tokenizer::O200kBase) => tiktoken_rs::o200k_base_singleton(),
+        Some(Tokenizer::Cl100kBase) | None => tiktoken_rs::cl100k_base_singleton(),
+    }
+}
+
+pub(crate) fn count_text_tokens(model: &str, text: &str) -> usize {
+    tokenizer(model)
+        .lock()
+        .encode_with_special_tokens(text)
+        .len()
+}
+
+/// Number of prompt tokens `messages` take up, including the reply priming.
+pub(crate) fn count_prompt_tokens(model: &str, messages: &[ChatMessage]) -> usize {
+    messages
+        .iter()
+        .map(|message| count_tokens(model, message))
+        .sum::<usize>()
+        + REPLY_PRIMING_TOKENS
+}
+
+pub(crate) fn count_tokens(model: &str, message: &ChatMessage) -> usize {
+    let bpe = tokenizer(model);
+    let bpe = bpe.lock();
+    let mut tokens = TOKENS_PER_MESSAGE
+        + bpe
+            .encode_with_special_tokens(&message.role.to_string())
+            .len()
+        + bpe.encode_with_special_tokens(&message.content).len();
+    if let Some(ref name) = message.name {
+        tokens += bpe.encode_with_special_tokens(name).len();
+    }
+    tokens + message.images.len() * IMAGE_TOKENS
+}
+
+/// Share of the token budget in percent above which the user is warned that
+/// older messages will be trimmed.
+const BUDGET_WARNING_PERCENT: usize = 80;
+
+/// Drops the oldest non-system messages until `messages` fits in `budget`
+/// tokens, returning the number of dropped messages.
+///
+/// System messages and the latest message are always kept, so the result may
+/// still exceed the budget.
+fn trim_to_budget(model: &str, messages: &mut ChatMessages, budget: usize) -> usize {
+    let costs: Vec<usize> = messages.iter().map(|m| count_tokens(model, m)).collect();
+    let mut total = costs.iter().sum::<usize>() + REPLY_PRIMING_TOKENS;
+    let last = messages.len().saturating_sub(1);
+
+    let mut keep = vec![true; messages.len()];
+    for (i, message) in messages.iter().enumerate() {
+        if total <= budget {
+            break;
+        }
+        if i == last || matches!(message.role, Role::System) {
+            continue;
+        }
+        keep[i] = false;
+        total -= costs[i];
+    }
+
+    let before = messages.len();
+    let mut keep = keep.into_iter();
+    messages.retain(|_| keep.next().unwrap_or(true));
+    before - messages.len()
+}
+
+/// Whether `err` is likely transient, e.g. rate limits, server errors and
+/// connection failures.
+fn is_retryable(err: &OpenAIError) -> bool {
+    match err {
+        OpenAIError::Reqwest(err) => {
+            err.is_timeout()
+                || err.is_connect()
+                || err
+                    .status()
+                    .is_some_and(|status| status.as_u16() == 429 || status.is_server_error())
+        }
+        OpenAIError::ApiError(err) => err.r#type.as_deref() == Some("server_error"),
+        // Streaming errors only carry the message of the underlying event
+        // source error.
+        OpenAIError::StreamError(message) => match message.strip_prefix("Invalid status code: ") {
+            Some(status) => status.starts_with("429") || status.starts_with('5'),
+            None => message.starts_with("Transport error"),
+        },
+        _ => false,
+    }
+}
+
+/// Why an OpenAI request failed, as far as the user needs to know.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ApiFailure {
+    /// The API key is invalid or lacks permissions.
+    Auth,
+    /// The account ran out of quota or has a billing problem.
+    Quota,
+    RateLimited,
+    Other,
+}
+
+impl ApiFailure {
+    pub(crate) fn of(err: &OpenAIError) -> Self {
+        let status = match err {
+            OpenAIError::ApiError(err) => {
+                let code = err.code.as_ref().and_then(serde_json::Value::as_str);
+                return match (err.r#type.as_deref(), code) {
+                    (_, Some("invalid_api_key" | "invalid_organization"))
+                    | (Some("authentication_error" | "permission_error"), _) => Self::Auth,
+                    (Some("insufficient_quota" | "billing_error"), _)
+                    | (_, Some("insufficient_quota" | "billing_hard_limit_reached")) => Self::Quota,
+                    (Some("tokens" | "requests"), _) | (_, Some("rate_limit_exceeded")) => {
+                        Self::RateLimited
+                    }
+                    _ => Self::Other,
+                };
+            }
+            OpenAIError::Reqwest(err) => err.status().map(|status| status.as_u16()),
+            OpenAIError::StreamError(message) => message
+                .strip_prefix("Invalid status code: ")
+                .and_then(|status| status.get(..3)?.parse().ok()),
+            _ => None,
+        };
+        match status {
+            Some(401 | 403) => Self::Auth,
+            Some(402) => Self::Quota,
+            Some(429) => Self::RateLimited,
+            _ => Self::Other,
+        }
+    }
+
+    /// What to tell the user, leaving out the details of the error.
+    pub(crate) fn message(self) -> &'static str {
+        match self {
+            Self::Auth => "The bot can't access OpenAI right now, please let its operator know.",
+            Self::Quota => "The bot is out of OpenAI quota, please let its operator know.",
+            Self::RateLimited => "OpenAI is busy, please try again in a minute.",
+            Self::Other => "Failed to get a response from OpenAI, please try again later.",
+        }
+    }
+
+    /// Logs `err`, at error level if the operator has to step in.
+    pub(crate) fn log(self, chat_id: ChatId, err: &OpenAIError) {
+        match self {
+            Self::Auth => {
+                tracing::error!("OpenAI rejected the API key, user: {}: {}", chat_id, err)
+            }
+            Self::Quota => tracing::error!("OpenAI quota exceeded, user: {}: {}", chat_id, err),
+            Self::RateLimited => tracing::warn!("OpenAI rate limited, user: {}: {}", chat_id, err),
+            Self::Other => tracing::error!("OpenAI request failed, user: {}: {}", chat_id, err),
+        }
+    }
+}
+
+/// Why a completion stream could not be opened.
+enum OpenError {
+    Api(OpenAIError),
+    /// OpenAI didn't answer within the stream timeout.
+    TimedOut,
+    /// The reply was stopped while connecting or waiting to retry.
+    Cancelled,
+}
+
+/// Waits for `future`, for at most `timeout` if there is one.
+async fn within<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = T>,
+) -> Result<T, OpenError> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| OpenError::TimedOut),
+        None => Ok(future.await),
+    }
+}
+
+/// Opens a chat completion stream and waits for its first chunk, retrying
+/// transient failures according to `policy`.
+///
+/// With `keys`, each attempt takes the next key instead of `client`, and a
+/// key that is rate limited or out of quota is failed over from right away.
+///
+/// Without `streaming`, makes a single request whose response is the only
+/// chunk of the stream. Each wait for OpenAI ends after `timeout`, which
+/// isn't retried, and waiting for a retry ends when `cancel` fires.
+async fn open_stream(
+    client: &Client,
+    keys: Option<&KeyPool>,
+    request: CreateChatCompletionRequest,
+    policy: &RetryPolicy,
+    streaming: bool,
+    timeout: Option<Duration>,
+    cancel: &CancellationToken,
+) -> Result<ChatCompletionResponseStream, OpenError> {
+    let mut attempt = 0;
+    let mut failovers = 0;
+    loop {
+        let (key, client) = match keys {
+            Some(keys) => {
+                let (key, client) = keys.next(Instant::now());
+                tracing::debug!("Requesting a completion with API key {}", key + 1);
+                (Some(key), client)
+            }
+            None => (None, client),
+        };
+        let result = if streaming {
+            match within(timeout, client.chat().create_stream(request.clone())).await? {
+                Ok(mut stream) => match within(timeout, stream.next()).await? {
+                    Some(Err(err)) => Err(err),
+                    first => Ok(stream::iter(first).chain(stream).boxed()),
+                },
+                Err(err) => Err(err),
+            }
+        } else {
+            within(timeout, client.chat().create(request.clone()))
+                .await?
+                .map(|response| stream::iter([Ok(into_chunk(response))]).boxed())
+        };
+
+        if let (Err(ref err), Some((keys, key))) = (&result, keys.zip(key)) {
+            if matches!(
+                ApiFailure::of(err),
+                ApiFailure::RateLimited | ApiFailure::Quota
+            ) {
+                keys.rest(key, Instant::now());
+                if failovers + 1 < keys.len() {
+                    failovers += 1;
+                    tracing::warn!(
+                        "OpenAI request failed with API key {}, trying another one: {}",
+                        key + 1,
+                        err
+                    );
+                    continue;
+                }
+            }
+        }
+        match result {
+            Err(err) if attempt < policy.max_retries && is_retryable(&err) => {
+                let delay = policy.delay(attempt);
+                attempt += 1;
+                tracing::warn!(
+                    "OpenAI request failed, retry {}/{} in {:?}: {}",
+                    attempt,
+                    policy.max_retries,
+                    delay,
+                    err
+                );
+                tokio::select! {
+                    _ = cancel.cancelled() => return Err(OpenError::Cancelled),
+                    _ = tokio::time::sleep(delay) => {}
+                }
+            }
+            result => return result.map_err(OpenError::Api),
+        }
+    }
+}
+
+/// A complete response as a single stream chunk.
+#[allow(deprecated)]
+fn into_chunk(response: CreateChatCompletionResponse) -> CreateChatCompletionStreamResponse {
+    let choices = response
+        .choices
+        .into_iter()
+        .map(|choice| ChatChoiceStream {
+            index: choice.index,
+            delta: ChatCompletionStreamResponseDelta {
+                content: choice.message.content,
+                function_call: None,
+                tool_calls: choice.message.tool_calls.map(|calls| {
+                    calls
+                        .into_iter()
+                        .zip(0..)
+                        .map(|(call, index)| ChatCompletionMessageToolCallChunk {
+                            index,
+                            id: Some(call.id),
+                            r#type: Some(call.r#type),
+                            function: Some(FunctionCallStream {
+                                name: Some(call.function.name),
+                                arguments: Some(call.function.arguments),
+                            }),
+                        })
+                        .collect()
+                }),
+                role: Some(choice.message.role),
+            },
+            finish_reason: choice.finish_reason,
+            logprobs: choice.logprobs,
+        })
+        .collect();
+    CreateChatCompletionStreamResponse {
+        id: response.id,
+        choices,
+        created: response.created,
+        model: response.model,
+        system_fingerprint: response.system_fingerprint,
+        object: response.object,
+    }
+}
+
+type ToolFn = Box<dyn Fn(serde_json::Value) -> Result<String, String> + Send + Sync>;
+
+/// A local function the model can call.
+pub(crate) struct Tool {
+    description: &'static str,
+    /// JSON schema of the arguments.
+    parameters: serde_json::Value,
+    call: ToolFn,
+}
+
+/// Functions the model can call, keyed by name.
+pub(crate) type Tools = HashMap<String, Tool>;
+
+pub(crate) fn default_tools() -> Tools {
+    let mut tools = Tools::new();
+    tools.insert(
+        "get_current_time".to_owned(),
+        Tool {
+            description: "Get the current date and time in UTC, in RFC 3339 format.",
+            parameters: serde_json::json!({ "type": "object", "properties": {} }),
+            call: Box::new(|_| Ok(Utc::now().to_rfc3339())),
+        },
+    );
+    tools.insert(
+        "calculator".to_owned(),
+        Tool {
+            description: "Evaluate an arithmetic expression with + - * / ^ and parentheses.",
+            parameters: serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "expression": { "type": "string", "description": "e.g. (2 + 3) * 4" }
+                },
+                "required": ["expression"],
+            }),
+            call: Box::new(|args| {
+                let expression = args["expression"].as_str().ok_or("missing expression")?;
+                evaluate(expression).map(|value| value.to_string())
+            }),
+        },
+    );
+    tools
+}
+
+fn tool_definitions(tools: &Tools) -> Vec<ChatCompletionTool> {
+    tools
+        .iter()
+        .map(|(name, tool)| ChatCompletionTool {
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionObject {
+                name: name.clone(),
+                description: Some(tool.description.to_owned()),
+                parameters: Some(tool.parameters.clone()),
+            },
+        })
+        .collect()
+}
+
+/// Runs the tool call `name` with the JSON `arguments` the model produced,
+/// returning the result or the error to report back to the model.
+fn call_tool(tools: &Tools, name: &str, arguments: &str) -> String {
+    let Some(tool) = tools.get(name) else {
+        return format!("Error: unknown function {}", name);
+    };
+    let arguments = if arguments.trim().is_empty() {
+        Ok(serde_json::Value::Object(Default::default()))
+    } else {
+        serde_json::from_str(arguments).map_err(|err| format!("invalid arguments: {}", err))
+    };
+    match arguments.and_then(|arguments| (tool.call)(arguments)) {
+        Ok(result) => result,
+        Err(err) => format!("Error: {}", err),
+    }
+}
+
+/// Evaluates an arithmetic expression of numbers, `+ - * / ^` and parentheses.
+fn evaluate(expression: &str) -> Result<f64, String> {
+    let tokens: Vec<char> = expression.chars().filter(|c| !c.is_whitespace()).collect();
+    let mut parser = ExprParser { tokens, pos: 0 };
+    let value = parser.sum()?;
+    match parser.peek() {
+        None => Ok(value),
+        Some(c) => Err(format!("unexpected {:?}", c)),
+    }
+}
+
+struct ExprParser {
+    tokens: Vec<char>,
+    pos: usize,
+}
+
+impl ExprParser {
+    fn peek(&self) -> Option<char> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn sum(&mut self) -> Result<f64, String> {
+        let mut value = self.product()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.pos += 1;
+            let rhs = self.product()?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<f64, String> {
+        let mut value = self.power()?;
+        while let Some(op @ ('*' | '/')) = self.peek() {
+            self.pos += 1;
+            let rhs = self.power()?;
+            value = if op == '*' { value * rhs } else { value / rhs };
+        }
+        Ok(value)
+    }
+
+    /// `^` is right associative and binds tighter than unary minus.
+    fn power(&mut self) -> Result<f64, String> {
+        if self.peek() == Some('-') {
+            self.pos += 1;
+            return Ok(-self.power()?);
+        }
+        let base = self.atom()?;
+        if self.peek() == Some('^') {
+            self.pos += 1;
+            return Ok(base.powf(self.power()?));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<f64, String> {
+        if self.peek() == Some('(') {
+            self.pos += 1;
+            let value = self.sum()?;
+            if self.peek() != Some(')') {
+                return Err("missing )".to_owned());
+            }
+            self.pos += 1;
+            return Ok(value);
+        }
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+            self.pos += 1;
+        }
+        let number: String = self.tokens[start..self.pos].iter().collect();
+        match self.peek() {
+            _ if !number.is_empty() => number
+                .parse()
+                .map_err(|_| format!("invalid number {:?}", number)),
+            Some(c) => Err(format!("unexpected {:?}", c)),
+            None => Err("unexpected end of expression".to_owned()),
+        }
+    }
+}
+
+/// A tool call being assembled from streamed chunks.
+#[derive(Default)]
+struct PendingToolCall {
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+fn merge_tool_call_chunks(
+    calls: &mut Vec<PendingToolCall>,
+    chunks: &[ChatCompletionMessageToolCallChunk],
+) {
+    for chunk in chunks {
+        let index = chunk.index.max(0) as usize;
+        if calls.len() <= index {
+            calls.resize_with(index + 1, Default::default);
+        }
+        let call = &mut calls[index];
+        if let Some(ref id) = chunk.id {
+            call.id.push_str(id);
+        }
+        if let Some(ref function) = chunk.function {
+            if let Some(ref name) = function.name {
+                call.name.push_str(name);
+            }
+            if let Some(ref arguments) = function.arguments {
+                call.arguments.push_str(arguments);
+            }
+        }
+    }
+}
+
+pub(crate) async fn complete_chat(
+    content: String,
+    bot: Bot,
+    client: Client,
+    state: State,
+    msg: Message,
+) -> HandleResult {
+    let user_message = ChatMessage::new(Role::User, with_quote(content, &msg));
+    complete_message(user_message, bot, client, state, msg).await
+}
+
+/// Replaces the latest user message with its edited version `msg` and
+/// replies to it anew, in the message of the previous reply.
+///
+/// Earlier messages have been built upon already, so edits to them are only
+/// answered with a notice.
+pub(crate) async fn complete_edit(
+    content: String,
+    bot: Bot,
+    client: Client,
+    state: State,
+    msg: Message,
+) -> HandleResult {
+    let Some(_replying) = state.lock_replies(&msg).await else {
+        return reply_busy(bot, state, msg).await;
+    };
+
+    let key = state.key(&msg);
+    let mut messages = state.store().get(key).await;
+    let latest = messages
+        .iter()
+        .rposition(|message| message.role == Role::User);
+    let edited = match messages
+        .iter()
+        .rposition(|message| message.message_id == Some(msg.id))
+    {
+        // The latest user message, possibly followed by its reply.
+        Some(index) if Some(index) == latest && messages.len() - index <= 2 => {
+            Edited::Latest(index)
+        }
+        Some(_) => Edited::Earlier,
+        None => Edited::Unknown,
+    };
+    let index = match edited {
+        Edited::Latest(index) => index,
+        Edited::Earlier => {
+            tracing::info!("Ignored edit of an earlier message, user: {}", msg.chat.id);
+            bot.send_message(
+                msg.chat.id,
+                "Only the latest message can be edited to get a new reply, please send it again instead.",
+            )
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+            return Ok(());
+        }
+        Edited::Unknown => return Ok(()),
+    };
+
+    // The history and the previous reply are only changed once the new
+    // reply is sure to be requested.
+    if let Err(wait) = state.check_rate_limit(msg.chat.id) {
+        return reply_rate_limited(bot, state, msg, wait).await;
+    }
+    let admitting = ReplyMode::History {
+        rollback: false,
+        replacing: None,
+    };
+    let Some(slot) = admit_reply(&bot, &state, &msg, &admitting).await? else {
+        return Ok(());
+    };
+
+    messages.truncate(index + 1);
+    let message = &mut messages[index];
+    message.content = with_quote(content, &msg);
+    message.created_at = Some(Utc::now());
+    state.store().replace(key, messages).await;
+    let previous = state
+        .update_chat(key, |chat| {
+            chat.budget_warned = false;
+            std::mem::take(&mut chat.last_reply)
+        })
+        .await;
+    state.mark_dirty();
+
+    // The new reply goes in the first message of the previous one.
+    let mut previous = previous.into_iter();
+    let replacing = previous.next();
+    for id in previous {
+        if let Err(err) = bot.delete_message(msg.chat.id, id).await {
+            tracing::warn!(
+                "Failed to delete previous reply, user: {}: {}",
+                msg.chat.id,
+                err
+            );
+        }
+    }
+
+    tracing::info!("Complete edited message, user: {}", msg.chat.id);
+    let mode = ReplyMode::History {
+        rollback: false,
+        replacing,
+    };
+    stream_admitted(bot, client, state, msg, mode, slot).await
+}
+
+/// Where in the history an edited message is.
+enum Edited {
+    /// The latest user message, at this index.
+    Latest(usize),
+    Earlier,
+    /// Not part of the conversation, e.g. after it was cleared.
+    Unknown,
+}
+
+/// `content` preceded by the message `msg` replies to, if any.
+fn with_quote(content: String, msg: &Message) -> String {
+    match msg.reply_to_message().and_then(quote) {
+        Some(quote) => format!("{}\n\n{}", quote, content),
+        None => content,
+    }
+}
+
+/// The message `reply` quoted for the model, so that it knows what a reply to
+/// it refers to.
+///
+/// Messages of bots are left out, replying to one of this bot's replies
+/// continues the conversation at that point instead.
+fn quote(reply: &Message) -> Option<String> {
+    if reply.from().is_some_and(|user| user.is_bot) {
+        return None;
+    }
+    let author = match (reply.from(), reply.sender_chat()) {
+        (_, Some(chat)) => chat.title().unwrap_or("a chat").to_owned(),
+        (Some(user), None) => user.full_name(),
+        (None, None) => "someone".to_owned(),
+    };
+    let media = if reply.photo().is_some() {
+        Some("a photo".to_owned())
+    } else if reply.video().is_some() || reply.video_note().is_some() {
+        Some("a video".to_owned())
+    } else if reply.voice().is_some() || reply.audio().is_some() {
+        Some("an audio message".to_owned())
+    } else if let Some(document) = reply.document() {
+        Some(match document.file_name {
+            Some(ref name) => format!("the file {}", name),
+            None => "a file".to_owned(),
+        })
+    } else if let Some(sticker) = reply.sticker() {
+        Some(match sticker.emoji {
+            Some(ref emoji) => format!("a {} sticker", emoji),
+            None => "a sticker".to_owned(),
+        })
+    } else if reply.animation().is_some() {
+        Some("an animation".to_owned())
+    } else if reply.location().is_some() {
+        Some("a location".to_owned())
+    } else if reply.poll().is_some() {
+        Some("a poll".to_owned())
+    } else {
+        None
+    };
+    let text = reply.text().or_else(|| reply.caption());
+    let quoted = match (media, text) {
+        (Some(media), Some(text)) => format!("({}) {}", media, text),
+        (Some(media), None) => format!("({})", media),
+        (None, Some(text)) => text.to_owned(),
+        (None, None) => return None,
+    };
+    Some(format!(
+        "In reply to this message from {}:\n\"\"\"\n{}\n\"\"\"",
+        author, quoted
+    ))
+}
+
+/// Adds `user_message` to the history and replies to it.
+pub(crate) async fn complete_message(
+    mut user_message: ChatMessage,
+    bot: Bot,
+    client: Client,
+    state: State,
+    msg: Message,
+) -> HandleResult {
+    tracing::info!(
+        "Complete chat, user: {}, content: {}, images: {}",
+        msg.chat.id,
+        user_message.content,
+        user_message.images.len()
+    );
+    increment_counter!("chatgpt_bot_completions_total");
+
+    let Some(_replying) = state.lock_replies(&msg).await else {
+        return reply_busy(bot, state, msg).await;
+    };
+    if let Err(wait) = state.check_rate_limit(msg.chat.id) {
+        return reply_rate_limited(bot, state, msg, wait).await;
+    }
+
+    if let Some(mut thread) = state.thread_of_reply(&msg).await {
+        tracing::info!("Branch off an earlier reply, user: {}", msg.chat.id);
+        thread.push(user_message);
+        return stream_reply(bot, client, state, msg, ReplyMode::Branch(thread)).await;
+    }
+
+    let key = state.key(&msg);
+    let messages = state.store().get(key).await;
+    let duplicate = state.store().load(key).await.is_some_and(|chat| {
+        chat.is_duplicate(&messages, &user_message, state.config.duplicate_window)
+    });
+    if duplicate {
+        // A resend of a message that got no reply is answered once more.
+        let answered = messages
+            .last()
+            .is_some_and(|message| message.role != Role::User);
+        tracing::info!(
+            "Suppressed duplicate message, user: {}, answered: {}",
+            msg.chat.id,
+            answered
+        );
+        if answered {
+            return Ok(());
+        }
+    } else {
+        user_message.message_id = Some(msg.id);
+        // First, so that a new chat starts with its initial messages.
+        state
+            .update_chat(key, |chat| chat.last_user_at = Some(Instant::now()))
+            .await;
+        state.store().append(key, user_message).await;
+        state.mark_dirty();
+    }
+    let rollback = !duplicate;
+
+    let messages = state.store().get(key).await;
+    let (warn, variants) = state
+        .update_chat(key, |chat| {
+            let model = chat.settings.model();
+            let budget = state.token_budget(model, state.max_tokens(&chat.settings));
+            let tokens = count_prompt_tokens(model, &messages);
+            let warn = !chat.budget_warned && tokens * 100 >= budget * BUDGET_WARNING_PERCENT;
+            if warn {
+                tracing::info!(
+                    "Conversation close to the token budget, user: {}, tokens: {}, budget: {}",
+                    msg.chat.id,
+                    tokens,
+                    budget
+                );
+                chat.budget_warned = true;
+            }
+            (warn, chat.settings.variants)
+        })
+        .await;
+    if warn {
+        bot.send_message(
+            msg.chat.id,
+            state.lang(&msg).await.text(Text::BudgetWarning),
+        )
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+    }
+
+    match variants {
+        Some(n) if n > 1 => {
+            reply_variants(n, rollback, bot, client.clone(), state.clone(), msg.clone()).await?
+        }
+        _ => {
+            stream_reply(
+                bot,
+                client.clone(),
+                state.clone(),
+                msg.clone(),
+                ReplyMode::History {
+                    rollback,
+                    replacing: None,
+                },
+            )
+            .await?
+        }
+    }
+
+    if let Some(threshold) = state.config.compact_threshold {
+        let model = state.settings(key).await.model().to_owned();
+        let tokens = count_prompt_tokens(&model, &state.store().get(key).await);
+        // Skipped once the demo limit is reached, like any other request.
+        if tokens > threshold && state.take_demo_completion(msg.chat.id) {
+            tokio::spawn(
+                async move {
+                    match compact_history(&client, &state, key).await {
+                        Ok(Some(count)) => tracing::info!(
+                            "Compacted {} messages, user: {}, tokens: {}",
+                            count,
+                            msg.chat.id,
+                            tokens
+                        ),
+                        Ok(None) => {}
+                        Err(err) => {
+                            tracing::error!("Failed to compact, user: {}: {}", msg.chat.id, err)
+                        }
+                    }
+                }
+                .in_current_span(),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the older messages of the active conversation of `key` with a
+/// summary written by the model, returning the number of replaced messages.
+///
+/// The leading system prompt and the most recent messages are kept as is.
+/// Returns `None` if there is not enough to summarize or the conversation
+/// changed while summarizing.
+pub(crate) async fn compact_history(
+    client: &Client,
+    state: &State,
+    key: ChatKey,
+) -> Result<Option<usize>, AppError> {
+    let Some(chat) = state.store().load(key).await else {
+        return Ok(None);
+    };
+    let (conversation, model) = (chat.conversation, chat.settings.model().to_owned());
+    let messages = state.store().get(key).await;
+
+    let start = messages
+        .iter()
+        .take_while(|m| matches!(m.role, Role::System) && !m.content.starts_with(SUMMARY_PREFIX))
+        .count();
+    let end = messages.len().saturating_sub(COMPACT_KEEP_MESSAGES);
+    if end <= start + 1 {
+        return Ok(None);
+    }
+
+    let budget = state.token_budget(&model, None);
+    let Some(summary) =
+        request_summary(client, &model, messages[start..end].to_vec(), budget).await?
+    else {
+        return Ok(None);
+    };
+    let summary = ChatMessage::new(Role::System, format!("{}{}", SUMMARY_PREFIX, summary));
+
+    let Some(chat) = state.store().load(key).await else {
+        return Ok(None);
+    };
+    let mut current = state.store().get(key).await;
+    let unchanged = chat.conversation == conversation
+        && current.len() >= end
+        && current[..end]
+            .iter()
+            .zip(&messages[..end])
+            .all(|(a, b)| a == b);
+    if !unchanged {
+        return Ok(None);
+    }
+    current.splice(start..end, [summary]);
+    state.store().replace(key, current).await;
+    state
+        .update_chat(key, |chat| chat.budget_warned = false)
+        .await;
+    state.mark_dirty();
+
+    Ok(Some(end - start))
+}
+
+/// Asks the model for a summary of `messages` that the conversation can be
+/// continued from, or `None` if it didn't give one.
+pub(crate) async fn request_summary(
+    client: &Client,
+    model: &str,
+    mut messages: ChatMessages,
+    budget: usize,
+) -> Result<Option<String>, AppError> {
+    messages.push(ChatMessage::new(Role::User, SUMMARY_PROMPT));
+    trim_to_budget(model, &mut messages, budget);
+    let request = CreateChatCompletionRequestArgs::default()
+        .model(model)
+        .messages(to_request_messages(&messages))
+        .build()?;
+    let response = client.chat().create(request).await?;
+    Ok(response
+        .choices
+        .into_iter()
+        .next()
+        .map(|choice| choice.message.content.unwrap_or_default().trim().to_owned()))
+}
+
+/// Splits `messages` into runs of consecutive messages of at most `budget`
+/// tokens each, a message taking up more than that gets a run of its own.
+pub(crate) fn chunk_messages(
+    model: &str,
+    messages: &[ChatMessage],
+    budget: usize,
+) -> Vec<ChatMessages> {
+    let mut chunks: Vec<ChatMessages> = Vec::new();
+    let mut tokens = 0;
+    for message in messages {
+        let cost = count_tokens(model, message);
+        match chunks.last_mut() {
+            Some(chunk) if tokens + cost <= budget => chunk.push(message.clone()),
+            _ => {
+                chunks.push(vec![message.clone()]);
+                tokens = 0;
+            }
+        }
+        tokens += cost;
+    }
+    chunks
+}
+
+pub(crate) fn utf16_len(text: &str) -> usize {
+    text.chars().map(char::len_utf16).sum()
+}
+
+/// Byte index of the longest prefix of `text` that fits in `limit` UTF-16
+/// code units.
+fn prefix_end(text: &str, limit: usize) -> usize {
+    let mut len = 0;
+    for (i, c) in text.char_indices() {
+        len += c.len_utf16();
+        if len > limit {
+            return i;
+        }
+    }
+    text.len()
+}
+
+/// Splits `text` into parts of at most `limit` UTF-16 code units, preferring
+/// to break between paragraphs, then lines, then words.
+pub(crate) fn split_message(text: &str, limit: usize) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = text;
+    while utf16_len(rest) > limit {
+        let end = prefix_end(rest, limit);
+        let head = &rest[..end];
+        let split = ["\n\n", "\n", " "]
+            .iter()
+            .find_map(|sep| head.rfind(sep).filter(|&i| i > 0))
+            .unwrap_or(end);
+        parts.push(rest[..split].trim_end());
+        rest = rest[split..].trim_start();
+    }
+    if !rest.is_empty() || parts.is_empty() {
+        parts.push(rest);
+    }
+    parts
+}
+
+/// The tail of an in-progress reply that fits in `limit` UTF-16 code units,
+/// the full reply is split into several messages once streaming finishes.
+fn streaming_preview(text: &str, limit: usize) -> &str {
+    let len = utf16_len(text);
+    if len <= limit {
+        return text;
+    }
+    let mut start = 0;
+    let mut skipped = 0;
+    for (i, c) in text.char_indices() {
+        if len - skipped <= limit {
+            start = i;
+            break;
+        }
+        skipped += c.len_utf16();
+    }
+    &text[start..]
+}
+
+/// Line under a streamed reply telling how far it is from `max_tokens`.
+fn progress_note(tokens: usize, max_tokens: u16) -> String {
+    let max_tokens = usize::from(max_tokens).max(1);
+    format!(
+        "\n\n⏳ ~{}/{} tokens ({}%)",
+        tokens,
+        max_tokens,
+        (tokens * 100 / max_tokens).min(100)
+    )
+}
+
+/// Escapes `text` to be shown as is in a MarkdownV2 message.
+fn escape_markdown_v2(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "_*[]()~`>#+-=|{}.!\\".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Converts the markdown produced by the model to Telegram's MarkdownV2.
+///
+/// Fenced code blocks, inline code and bold text are kept, everything else is
+/// escaped. Unterminated code blocks, which happen while streaming, are closed
+/// at the end of the text, so the result is always valid MarkdownV2.
+fn to_markdown_v2(text: &str) -> String {
+    let escape_code = |code: &str| code.replace('\\', "\\\\").replace('`', "\\`");
+
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if let Some(after) = rest.strip_prefix("```") {
+            let (lang, body) = after.split_once('\n').unwrap_or(("", after));
+            let (code, next) = body.split_once("```").unwrap_or((body, ""));
+            out.push_str("```");
+            if lang
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-#_".contains(c))
+            {
+                out.push_str(lang);
+            }
+            out.push('\n');
+            out.push_str(&escape_code(code));
+            out.push_str("```");
+            rest = next;
+            continue;
+        }
+
+        if let Some(after) = rest.strip_prefix("**") {
+            if let Some((bold, next)) = after
+                .split_once("**")
+                .filter(|(bold, _)| !bold.is_empty() && !bold.contains('\n'))
+            {
+                out.push('*');
+                out.push_str(&escape_markdown_v2(bold));
+                out.push('*');
+                rest = next;
+                continue;
+            }
+        }
+
+        if let Some(after) = rest.strip_prefix('`') {
+            if let Some((code, next)) = after
+                .split_once('`')
+                .filter(|(code, _)| !code.is_empty() && !code.contains('\n'))
+            {
+                out.push('`');
+                out.push_str(&escape_code(code));
+                out.push('`');
+                rest = next;
+                continue;
+            }
+        }
+
+        out.push_str(&escape_markdown_v2(&rest[..c.len_utf8()]));
+        rest = &rest[c.len_utf8()..];
+    }
+    out
+}
+
+/// Escapes `text` to be shown as is in an HTML message.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Converts the markdown produced by the model to Telegram's HTML.
+///
+/// Works like [`to_markdown_v2`]: fenced code blocks become `<pre>`, inline
+/// code `<code>` and bold text `<b>`, everything else is escaped. Every tag is
+/// closed before the next one opens and unterminated code blocks are closed at
+/// the end of the text, so previews of a streamed reply are balanced too.
+fn to_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if let Some(after) = rest.strip_prefix("```") {
+            let (lang, body) = after.split_once('\n').unwrap_or(("", after));
+            let (code, next) = body.split_once("```").unwrap_or((body, ""));
+            let code = code.strip_suffix('\n').unwrap_or(code);
+            let lang = lang.trim();
+            if !lang.is_empty()
+                && lang
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-#_".contains(c))
+            {
+                out.push_str(&format!(
+                    "<pre><code class=\"language-{}\">{}</code></pre>",
+                    lang,
+                    escape_html(code)
+                ));
+            } else {
+                out.push_str(&format!("<pre>{}</pre>", escape_html(code)));
+            }
+            rest = next;
+            continue;
+        }
+
+        if let Some(after) = rest.strip_prefix("**") {
+            if let Some((bold, next)) = after
+                .split_once("**")
+                .filter(|(bold, _)| !bold.is_empty() && !bold.contains('\n'))
+            {
+                out.push_str(&format!("<b>{}</b>", escape_html(bold)));
+                rest = next;
+                continue;
+            }
+        }
+
+        if let Some(after) = rest.strip_prefix('`') {
+            if let Some((code, next)) = after
+                .split_once('`')
+                .filter(|(code, _)| !code.is_empty() && !code.contains('\n'))
+            {
+                out.push_str(&format!("<code>{}</code>", escape_html(code)));
+                rest = next;
+                continue;
+            }
+        }
+
+        out.push_str(&escape_html(&rest[..c.len_utf8()]));
+        rest = &rest[c.len_utf8()..];
+    }
+    out
+}
+
+/// Converts the fenced code blocks and inline code in the markdown produced
+/// by the model to message entities, leaving the rest of the text as is.
+///
+/// Unterminated code blocks, which happen when a reply is split, run to the
+/// end of the text.
+fn to_entities(text: &str) -> (String, Vec<MessageEntity>) {
+    let mut out = String::with_capacity(text.len());
+    let mut entities = Vec::new();
+    // Length of `out` in UTF-16 code units, which entity offsets count.
+    let mut offset = 0;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if let Some(after) = rest.strip_prefix("```") {
+            let (lang, body) = after.split_once('\n').unwrap_or(("", after));
+            let (code, next) = body.split_once("```").unwrap_or((body, ""));
+            let code = code.strip_suffix('\n').unwrap_or(code);
+            let lang = lang.trim();
+            let language = (!lang.is_empty()
+                && lang
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-#_".contains(c)))
+            .then(|| lang.to_owned());
+            let length = utf16_len(code);
+            if length > 0 {
+                entities.push(MessageEntity::pre(language, offset, length));
+                out.push_str(code);
+                offset += length;
+            }
+            rest = next;
+            continue;
+        }
+
+        if let Some(after) = rest.strip_prefix('`') {
+            if let Some((code, next)) = after
+                .split_once('`')
+                .filter(|(code, _)| !code.is_empty() && !code.contains('\n'))
+            {
+                let length = utf16_len(code);
+                entities.push(MessageEntity::code(offset, length));
+                out.push_str(code);
+                offset += length;
+                rest = next;
+                continue;
+            }
+        }
+
+        out.push(c);
+        offset += c.len_utf16();
+        rest = &rest[c.len_utf8()..];
+    }
+    (out, entities)
+}
+
+fn is_parse_error(err: &RequestError) -> bool {
+    match err {
+        RequestError::Api(ApiError::CantParseEntities) => true,
+        RequestError::Api(ApiError::Unknown(message)) => message.contains("can't parse entities"),
+        _ => false,
+    }
+}
+
+/// Whether `err` says that the message to edit no longer exists or can't be
+/// edited anymore.
+fn is_uneditable(err: &RequestError) -> bool {
+    matches!(
+        err,
+        RequestError::Api(ApiError::MessageToEditNotFound | ApiError::MessageCantBeEdited)
+    )
+}
+
+/// Sets the message a bot message replies to, if any.
+pub(crate) trait ReplyTo {
+    fn reply_to(self, id: Option<MessageId>) -> Self;
+}
+
+impl ReplyTo for JsonRequest<SendMessage> {
+    fn reply_to(mut self, id: Option<MessageId>) -> Self {
+        self.payload_mut().reply_to_message_id = id;
+        self
+    }
+}
+
+impl ReplyTo for MultipartRequest<SendPhoto> {
+    fn reply_to(mut self, id: Option<MessageId>) -> Self {
+        self.payload_mut().reply_to_message_id = id;
+        self
+    }
+}
+
+impl ReplyTo for MultipartRequest<SendDocument> {
+    fn reply_to(mut self, id: Option<MessageId>) -> Self {
+        self.payload_mut().reply_to_message_id = id;
+        self
+    }
+}
+
+/// Sends `text` rendered with `format` and followed by `note`, falling back
+/// to plain text if Telegram rejects the formatting.
+async fn send_formatted(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: Option<MessageId>,
+    text: &str,
+    format: Format,
+    note: Option<&str>,
+) -> Result<Message, RequestError> {
+    retry_after(|| send_formatted_once(bot, chat_id, reply_to, text, format, note)).await
+}
+
+async fn send_formatted_once(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: Option<MessageId>,
+    text: &str,
+    format: Format,
+    note: Option<&str>,
+) -> Result<Message, RequestError> {
+    if let Some(rendered) = format.render(text, note) {
+        let request = match rendered {
+            Rendered::Parsed(text, parse_mode) => {
+                bot.send_message(chat_id, text).parse_mode(parse_mode)
+            }
+            Rendered::Entities(text, entities) => {
+                bot.send_message(chat_id, text).entities(entities)
+            }
+        };
+        match request.reply_to(reply_to).await {
+            Err(err) if is_parse_error(&err) => {
+                tracing::warn!(
+                    "Failed to send formatted message, user: {}: {}",
+                    chat_id,
+                    err
+                )
+            }
+            result => return result,
+        }
+    }
+    bot.send_message(chat_id, with_note(text, note))
+        .reply_to(reply_to)
+        .await
+}
+
+/// `text` followed by `note`, if any, as plain text.
+fn with_note(text: &str, note: Option<&str>) -> String {
+    match note {
+        Some(note) => format!("{}\n\n{}", text, note),
+        None => text.to_owned(),
+    }
+}
+
+/// Edits a message to `text` rendered with `format` and followed by `note`,
+/// falling back to plain text if Telegram rejects the formatting.
+///
+/// Editing a message to its current text is not an error.
+async fn edit_formatted(
+    bot: &Bot,
+    chat_id: ChatId,
+    message_id: MessageId,
+    text: &str,
+    format: Format,
+    note: Option<&str>,
+) -> Result<(), RequestError> {
+    retry_after(|| edit_formatted_once(bot, chat_id, message_id, text, format, note)).await
+}
+
+async fn edit_formatted_once(
+    bot: &Bot,
+    chat_id: ChatId,
+    message_id: MessageId,
+    text: &str,
+    format: Format,
+    note: Option<&str>,
+) -> Result<(), RequestError> {
+    let result = match format.render(text, note) {
+        Some(rendered) => {
+            let request = match rendered {
+                Rendered::Parsed(text, parse_mode) => bot
+                    .edit_message_text(chat_id, message_id, text)
+                    .parse_mode(parse_mode),
+                Rendered::Entities(text, entities) => bot
+                    .edit_message_text(chat_id, message_id, text)
+                    .entities(entities),
+            };
+            match request.await {
+                Err(err) if is_parse_error(&err) => {
+                    tracing::warn!(
+                        "Failed to edit formatted message, user: {}: {}",
+                        chat_id,
+                        err
+                    );
+                    bot.edit_message_text(chat_id, message_id, with_note(text, note))
+                        .await
+                }
+                result => result,
+            }
+        }
+        None => {
+            bot.edit_message_text(chat_id, message_id, with_note(text, note))
+                .await
+        }
+    };
+    match result {
+        Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Runs `request` until Telegram stops asking to retry it later, waiting at
+/// most [`TELEGRAM_MAX_RETRY_WAIT`] in total.
+pub(crate) async fn retry_after<T, F, Fut>(mut request: F) -> Result<T, RequestError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RequestError>>,
+{
+    let mut waited = Duration::ZERO;
+    loop {
+        match request().await {
+            Err(RequestError::RetryAfter(wait)) if waited + wait <= TELEGRAM_MAX_RETRY_WAIT => {
+                tracing::warn!("Telegram rate limit hit, retrying in {:?}", wait);
+                tokio::time::sleep(wait).await;
+                waited += wait;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Edits a streamed reply message with previews in the background, so that
+/// the stream keeps being read while Telegram is slow.
+///
+/// Edits are coalesced: a preview is skipped if it is unchanged or the
+/// previous edit is still in flight.
+struct PreviewEditor {
+    bot: Bot,
+    chat_id: ChatId,
+    message_id: MessageId,
+    format: Format,
+    /// Text of the latest edit, sent or in flight.
+    last_text: String,
+    in_flight: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl PreviewEditor {
+    fn new(bot: Bot, chat_id: ChatId, message_id: MessageId, format: Format, text: String) -> Self {
+        Self {
+            bot,
+            chat_id,
+            message_id,
+            format,
+            last_text: text,
+            in_flight: None,
+        }
+    }
+
+    /// Starts editing the message to `text`, returning whether an edit was
+    /// started.
+    fn update(&mut self, text: &str) -> bool {
+        if text == self.last_text
+            || self
+                .in_flight
+                .as_ref()
+                .is_some_and(|task| !task.is_finished())
+        {
+            return false;
+        }
+        self.last_text = text.to_owned();
+
+        let (bot, chat_id, message_id, format) =
+            (self.bot.clone(), self.chat_id, self.message_id, self.format);
+        let text = text.to_owned();
+        self.in_flight = Some(tokio::spawn(
+            async move {
+                if let Err(err) =
+                    edit_formatted(&bot, chat_id, message_id, &text, format, None).await
+                {
+                    tracing::warn!(
+                        "Failed to edit streamed message, user: {}: {}",
+                        chat_id,
+                        err
+                    );
+                }
+            }
+            .in_current_span(),
+        ));
+        true
+    }
+
+    /// Deletes the message, e.g. a placeholder that didn't get a reply.
+    async fn discard(self) {
+        let (bot, chat_id) = (self.bot.clone(), self.chat_id);
+        let message_id = self.finish().await;
+        if let Err(err) = bot.delete_message(chat_id, message_id).await {
+            tracing::warn!("Failed to delete placeholder, user: {}: {}", chat_id, err);
+        }
+    }
+
+    /// Waits for the edit in flight, so that it doesn't overwrite a later one,
+    /// and returns the id of the edited message.
+    async fn finish(mut self) -> MessageId {
+        if let Some(task) = self.in_flight.take() {
+            if let Err(err) = task.await {
+                tracing::warn!("Streamed message edit failed: {}", err);
+            }
+        }
+        self.message_id
+    }
+}
+
+/// Shows `text` in place of the reply, in a new message if there is none yet.
+async fn show_placeholder(
+    bot: &Bot,
+    msg: &Message,
+    reply_to: Option<MessageId>,
+    editor: &mut Option<PreviewEditor>,
+    text: &str,
+    format: Format,
+) -> Result<(), RequestError> {
+    match editor {
+        Some(editor) => {
+            editor.update(text);
+        }
+        None => {
+            let reply = send_formatted(bot, msg.chat.id, reply_to, text, format, None).await?;
+            *editor = Some(PreviewEditor::new(
+                bot.clone(),
+                msg.chat.id,
+                reply.id,
+                format,
+                text.to_owned(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Sleeps until `deadline`, or forever without one.
+async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+pub(crate) async fn reply_busy(bot: Bot, state: State, msg: Message) -> HandleResult {
+    tracing::info!("Busy, user: {}", msg.chat.id);
+
+    bot.send_message(
+        msg.chat.id,
+        "Please wait until the current reply is finished.",
+    )
+    .reply_to(state.reply_to(&msg).await)
+    .await?;
+
+    Ok(())
+}
+
+pub(crate) async fn reply_rate_limited(
+    bot: Bot,
+    state: State,
+    msg: Message,
+    wait: Duration,
+) -> HandleResult {
+    tracing::info!("Rate limited, user: {}, wait: {:?}", msg.chat.id, wait);
+
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    bot.send_message(
+        msg.chat.id,
+        format!("Slow down, try again in {} seconds.", seconds.max(1)),
+    )
+    .reply_to(state.reply_to(&msg).await)
+    .await?;
+
+    Ok(())
+}
+
+/// Gets one of the reply slots shared by all chats, waiting in line for it
+/// unless busy chats are rejected, then `None` is returned if all are taken.
+///
+/// After waiting for a moment, the user is told their place in the queue.
+async fn wait_for_slot(
+    bot: &Bot,
+    state: &State,
+    msg: &Message,
+    slots: &ReplySlots,
+) -> Result<Option<OwnedSemaphorePermit>, RequestError> {
+    if let Some(slot) = slots.try_acquire() {
+        return Ok(Some(slot));
+    }
+    if let BusyPolicy::Reject = state.config.busy_policy {
+        return Ok(None);
+    }
+
+    // Leaves the queue when dropped, with or without a slot.
+    let ticket = slots.enqueue();
+    let acquire = slots.acquire();
+    tokio::pin!(acquire);
+    tokio::select! {
+        slot = &mut acquire => return Ok(Some(slot)),
+        _ = tokio::time::sleep(QUEUE_NOTICE_AFTER) => {}
+    }
+    tracing::info!("Waiting for a reply slot, user: {}", msg.chat.id);
+    bot.send_message(
+        msg.chat.id,
+        format!(
+            "The bot is busy with other chats, your message is number {} in the queue.",
+            slots.position(&ticket)
+        ),
+    )
+    .reply_to(state.reply_to(msg).await)
+    .await?;
+    Ok(Some(acquire.await))
+}
+
+pub(crate) async fn reply_demo_limited(bot: Bot, state: State, msg: Message) -> HandleResult {
+    tracing::info!("Demo limit reached, user: {}", msg.chat.id);
+
+    bot.send_message(msg.chat.id, DEMO_LIMIT_TEXT)
+        .reply_to(state.reply_to(&msg).await)
+        .await?;
+
+    Ok(())
+}
+
+/// Keeps the typing indicator of a chat alive until dropped.
+struct TypingIndicator(tokio::task::JoinHandle<()>);
+
+impl TypingIndicator {
+    fn start(bot: Bot, chat_id: ChatId) -> Self {
+        Self(tokio::spawn(async move {
+            loop {
+                if let Err(err) = bot.send_chat_action(chat_id, ChatAction::Typing).await {
+                    tracing::warn!("Failed to send typing action, user: {}: {}", chat_id, err);
+                }
+                tokio::time::sleep(TYPING_INTERVAL).await;
+            }
+        }))
+    }
+}
+
+impl Drop for TypingIndicator {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// An in-flight reply, unregistered from [`AppState::streams`] when dropped.
+pub(crate) struct ActiveStream {
+    pub(crate) state: State,
+    pub(crate) key: ChatKey,
+    pub(crate) id: u64,
+    pub(crate) token: CancellationToken,
+}
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.state
+            .streams
+            .remove_if(&self.key, |_, (id, _)| *id == self.id);
+    }
+}
+
+/// What a reply is written for and where it is stored.
+pub(crate) enum ReplyMode {
+    /// The chat's current history, which the reply is appended to.
+    ///
+    /// With `rollback`, the last message was just added by the user and is
+    /// removed again if the request fails, so that it doesn't get in the way
+    /// of the next one. With `replacing`, the reply is shown in that message
+    /// instead of a new one.
+    History {
+        rollback: bool,
+        replacing: Option<MessageId>,
+    },
+    /// Earlier messages, leaving the history alone, the reply can only be
+    /// continued by replying to it.
+    Branch(ChatMessages),
+    /// The last reply in the history, which the reply is merged into.
+    Continuation,
+    /// Messages the reply is only shown for, e.g. a summary.
+    Detached(ChatMessages),
+}
+
+/// Removes the user message a failed reply was for, if `mode` asks for it.
+async fn roll_back(state: &State, msg: &Message, mode: &ReplyMode) {
+    if let ReplyMode::History { rollback: true, .. } = mode {
+        let key = state.key(msg);
+        if state
+            .store()
+            .get(key)
+            .await
+            .last()
+            .is_some_and(|message| message.role == Role::User)
+        {
+            state.store().pop(key).await;
+            state.mark_dirty();
+        }
+    }
+}
+
+/// Checks that a reply to `msg` can be requested now, telling the user and
+/// rolling back `mode` if not.
+///
+/// Every reply is counted against the demo limit here, once it would
+/// otherwise be requested.
+///
+/// Returns the reply slot to hold until the reply is finished, which is
+/// `None` without a limit on parallel replies.
+async fn admit_reply(
+    bot: &Bot,
+    state: &State,
+    msg: &Message,
+    mode: &ReplyMode,
+) -> Result<Option<Option<OwnedSemaphorePermit>>, AppError> {
+    if !state.allow_api_request() {
+        tracing::info!("Circuit breaker open, user: {}", msg.chat.id);
+        roll_back(state, msg, mode).await;
+        bot.send_message(msg.chat.id, UNAVAILABLE_TEXT)
+            .reply_to(state.reply_to(msg).await)
+            .await?;
+        return Ok(None);
+    }
+    let slot = match state.reply_slots {
+        Some(ref slots) => match wait_for_slot(bot, state, msg, slots).await? {
+            Some(slot) => Some(slot),
+            None => {
+                tracing::info!("No free reply slot, user: {}", msg.chat.id);
+                roll_back(state, msg, mode).await;
+                bot.send_message(
+                    msg.chat.id,
+                    "The bot is busy with other chats, please try again in a moment.",
+                )
+                .reply_to(state.reply_to(msg).await)
+                .await?;
+                return Ok(None);
+            }
+        },
+        None => None,
+    };
+    if !state.take_demo_completion(msg.chat.id) {
+        roll_back(state, msg, mode).await;
+        reply_demo_limited(bot.clone(), state.clone(), msg.clone()).await?;
+        return Ok(None);
+    }
+    Ok(Some(slot))
+}
+
+/// Request arguments for a reply with the model and parameters of
+/// `settings`, without the messages.
+fn completion_args(
+    settings: &ChatSettings,
+    max_tokens: Option<u16>,
+) -> CreateChatCompletionRequestArgs {
+    let mut args = CreateChatCompletionRequestArgs::default();
+    args.model(settings.model());
+    if let Some(temperature) = settings.temperature {
+        args.temperature(temperature);
+    }
+    if let Some(top_p) = settings.top_p {
+        args.top_p(top_p);
+    }
+    if let Some(presence_penalty) = settings.presence_penalty {
+        args.presence_penalty(presence_penalty);
+    }
+    if let Some(frequency_penalty) = settings.frequency_penalty {
+        args.frequency_penalty(frequency_penalty);
+    }
+    if let Some(max_tokens) = max_tokens {
+        args.max_tokens(max_tokens);
+    }
+    if let Some(seed) = settings.seed {
+        args.seed(seed);
+    }
+    if !settings.stop.is_empty() {
+        args.stop(Stop::StringArray(settings.stop.clone()));
+    }
+    if settings.json {
+        args.response_format(ChatCompletionResponseFormat {
+            r#type: ChatCompletionResponseFormatType::JsonObject,
+        });
+    }
+    args
+}
+
+/// Streams a reply as a reply to `msg` and stores it according to `mode`.
+pub(crate) async fn stream_reply(
+    bot: Bot,
+    client: Client,
+    state: State,
+    msg: Message,
+    mode: ReplyMode,
+) -> HandleResult {
+    let Some(slot) = admit_reply(&bot, &state, &msg, &mode).await? else {
+        return Ok(());
+    };
+    stream_admitted(bot, client, state, msg, mode, slot).await
+}
+
+/// Streams a reply that [`admit_reply`] admitted with `slot`, see
+/// [`stream_reply`].
+async fn stream_admitted(
+    bot: Bot,
+    client: Client,
+    state: State,
+    msg: Message,
+    mode: ReplyMode,
+    // Held until the reply is finished.
+    _slot: Option<OwnedSemaphorePermit>,
+) -> HandleResult {
+    let key = state.key(&msg);
+    let settings = state.settings(key).await;
+    let mut hists = match mode {
+        ReplyMode::Branch(ref messages) | ReplyMode::Detached(ref messages) => messages.clone(),
+        ReplyMode::History { .. } | ReplyMode::Continuation => state.store().get(key).await,
+    };
+    if let ReplyMode::Continuation = mode {
+        hists.push(ChatMessage::new(Role::User, CONTINUE_PROMPT));
+    }
+    if settings.json {
+        // First, so that trimming keeps the latest message.
+        hists.insert(0, ChatMessage::new(Role::System, JSON_PROMPT));
+    }
+    let model = settings.model();
+    let format = settings.format;
+    // Entities are only applied to the finished reply.
+    let preview_format = format.preview();
+    let max_tokens = state.max_tokens(&settings);
+    let streaming = state.streaming(&settings);
+    // Only shown in previews, the finished reply is sent without them.
+    let progress = max_tokens.filter(|_| state.progress(&settings));
+    let cursor = if state.config.cursor { CURSOR } else { "" };
+    if !supports_vision(model) {
+        // Images from before switching models are left out.
+        for message in &mut hists {
+            message.images.clear();
+        }
+    }
+
+    let dropped = trim_to_budget(model, &mut hists, state.token_budget(model, max_tokens));
+    if dropped > 0 {
+        tracing::info!(
+            "Trimmed {} messages to fit token budget, user: {}",
+            dropped,
+            msg.chat.id
+        );
+    }
+
+    let prompt_tokens = count_prompt_tokens(model, &hists);
+    let reply_to = state.reply_to(&msg).await;
+    let started = Instant::now();
+    let mut typing = Some(TypingIndicator::start(bot.clone(), msg.chat.id));
+    let active = state.start_stream(key);
+
+    let mut messages = to_request_messages(&hists);
+    let tools = if state.config.tools_enabled {
+        tool_definitions(&state.tools)
+    } else {
+        Vec::new()
+    };
+
+    let mut chunks = Vec::new();
+    let mut count = 0;
+    let mut first_token_at = None;
+    // Whether any of the reply has been shown, rather than just a placeholder.
+    let mut shown = false;
+    let mut last_edit = Instant::now();
+    let mut editor = match mode {
+        ReplyMode::History {
+            replacing: Some(id),
+            ..
+        } => Some(PreviewEditor::new(
+            bot.clone(),
+            msg.chat.id,
+            id,
+            preview_format,
+            String::new(),
+        )),
+        _ => None,
+    };
+    if let Some(ref text) = state.config.placeholder.text {
+        show_placeholder(&bot, &msg, reply_to, &mut editor, text, preview_format).await?;
+    }
+    // Until the first tokens arrive, when to say that the reply takes a while.
+    let mut slow_at = state
+        .config
+        .placeholder
+        .slow_text
+        .as_ref()
+        .map(|_| tokio::time::Instant::now() + state.config.placeholder.slow_after);
+    let mut finish_reason = None;
+    let mut fingerprint = None;
+    // Whether the stream stalled, the reply then ends with what it got.
+    let mut timed_out = false;
+    // Why the stream broke off, the reply then ends with what it got too.
+    let mut failed = None;
+    // Whether the demo limit was reached between tool rounds.
+    let mut demo_limited = false;
+    for round in 0.. {
+        let mut args = completion_args(&settings, max_tokens);
+        args.messages(messages.clone());
+        // The last round leaves out the tools so the model has to answer.
+        if !tools.is_empty() && round < MAX_TOOL_ROUNDS {
+            args.tools(tools.clone());
+        }
+        let request = args.build()?;
+
+        let opening = open_stream(
+            &client,
+            state.keys.as_ref(),
+            request,
+            &state.config.retry_policy,
+            streaming,
+            state.config.stream_timeout,
+            &active.token,
+        );
+        tokio::pin!(opening);
+        let opened = loop {
+            tokio::select! {
+                biased;
+                _ = active.token.cancelled() => break Err(OpenError::Cancelled),
+                result = &mut opening => break result,
+                _ = sleep_until(slow_at) => {
+                    slow_at = None;
+                    if let Some(ref text) = state.config.placeholder.slow_text {
+                        show_placeholder(&bot, &msg, reply_to, &mut editor, text, preview_format).await?;
+                    }
+                }
+            }
+        };
+        // Errors that aren't transient still mean that OpenAI answered.
+        state.record_api_result(match opened {
+            Ok(_) => true,
+            Err(OpenError::Api(ref err)) => !is_retryable(err),
+            Err(OpenError::TimedOut) => false,
+            // OpenAI had no say in it.
+            Err(OpenError::Cancelled) => true,
+        });
+        let mut stream = match opened {
+            Ok(stream) => stream,
+            Err(OpenError::TimedOut) => {
+                tracing::warn!("Reply timed out while opening, user: {}", msg.chat.id);
+                timed_out = true;
+                break;
+            }
+            Err(OpenError::Cancelled) => {
+                tracing::info!("Reply stopped while opening, user: {}", msg.chat.id);
+                break;
+            }
+            Err(OpenError::Api(err)) => {
+                typing.take();
+                let failure = ApiFailure::of(&err);
+                failure.log(msg.chat.id, &err);
+                increment_counter!("chatgpt_bot_errors_total", "type" => "openai");
+                roll_back(&state, &msg, &mode).await;
+                // The placeholder shows the error, rather than waiting forever.
+                if let Some(editor) = editor.take() {
+                    let message_id = editor.finish().await;
+                    match bot
+                        .edit_message_text(msg.chat.id, message_id, failure.message())
+                        .await
+                    {
+                        Ok(_) => return Ok(()),
+                        Err(err) => tracing::warn!(
+                            "Failed to show error in placeholder, user: {}: {}",
+                            msg.chat.id,
+                            err
+                        ),
+                    }
+                }
+                bot.send_message(msg.chat.id, failure.message())
+                    .reply_to(reply_to)
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        let mut calls = Vec::new();
+        loop {
+            let idle_until = state
+                .config
+                .stream_timeout
+                .map(|timeout| tokio::time::Instant::now() + timeout);
+            let result = tokio::select! {
+                biased;
+                _ = active.token.cancelled() => {
+                    tracing::info!("Reply stopped, user: {}", msg.chat.id);
+                    break;
+                }
+                result = stream.next() => result,
+                _ = sleep_until(idle_until) => {
+                    tracing::warn!("Reply timed out, user: {}", msg.chat.id);
+                    timed_out = true;
+                    break;
+                }
+                _ = sleep_until(slow_at) => {
+                    slow_at = None;
+                    if let Some(ref text) = state.config.placeholder.slow_text {
+                        show_placeholder(&bot, &msg, reply_to, &mut editor, text, preview_format).await?;
+                    }
+                    continue;
+                }
+            };
+            let Some(result) = result else {
+                break;
+            };
+            let response = match result {
+                Ok(response) => response,
+                Err(err) => {
+                    let failure = ApiFailure::of(&err);
+                    failure.log(msg.chat.id, &err);
+                    increment_counter!("chatgpt_bot_errors_total", "type" => "openai");
+                    state.record_api_result(!is_retryable(&err));
+                    failed = Some(failure);
+                    break;
+                }
+            };
+            if response.system_fingerprint.is_some() {
+                fingerprint = response.system_fingerprint.clone();
+            }
+            let Some(choice) = response.choices.first() else {
+                continue;
+            };
+            if choice.finish_reason.is_some() {
+                finish_reason = choice.finish_reason;
+            }
+            if let Some(ref tool_calls) = choice.delta.tool_calls {
+                merge_tool_call_chunks(&mut calls, tool_calls);
+            }
+            if let Some(ref content) = choice.delta.content {
+                chunks.push(content.to_owned());
+                // Without streaming, the reply is sent once it's complete.
+                if streaming && !content.trim().is_empty() {
+                    count += 1;
+                    let text = chunks.join("");
+                    let first_token_at = *first_token_at.get_or_insert_with(|| {
+                        slow_at = None;
+                        histogram!(
+                            "chatgpt_bot_first_chunk_seconds",
+                            started.elapsed().as_secs_f64()
+                        );
+                        Instant::now()
+                    });
+                    // A few words are shown at once, rather than a single one.
+                    let first = !shown
+                        && (text.chars().count() >= state.config.first_chunk_chars
+                            || first_token_at.elapsed() >= state.config.first_chunk_wait);
+                    if first {
+                        shown = true;
+                        typing.take();
+                    }
+                    // Each streamed chunk is about one token.
+                    let note = progress
+                        .map(|max_tokens| progress_note(chunks.len(), max_tokens))
+                        .unwrap_or_default();
+                    let suffix = format!("{}{}", cursor, note);
+                    let limit = MESSAGE_LIMIT - utf16_len(&suffix);
+                    let preview = format!("{}{}", streaming_preview(&text, limit), suffix);
+                    match editor {
+                        None if !shown => {}
+                        None => {
+                            let reply = send_formatted(
+                                &bot,
+                                msg.chat.id,
+                                reply_to,
+                                &preview,
+                                preview_format,
+                                None,
+                            )
+                            .await?;
+                            editor = Some(PreviewEditor::new(
+                                bot.clone(),
+                                msg.chat.id,
+                                reply.id,
+                                preview_format,
+                                preview,
+                            ));
+                            last_edit = Instant::now();
+                        }
+                        // The first tokens replace the placeholder right away.
+                        Some(ref mut editor)
+                            if first
+                                || shown
+                                    && state.config.edit_throttle.should_edit(count, last_edit) =>
+                        {
+                            if editor.update(&preview) {
+                                last_edit = Instant::now();
+                            }
+                        }
+                        Some(_) => {}
+                    }
+                }
+            }
+        }
+
+        if calls.is_empty()
+            || round >= MAX_TOOL_ROUNDS
+            || active.token.is_cancelled()
+            || timed_out
+            || failed.is_some()
+        {
+            break;
+        }
+        // Every round is a request of its own, counted like the first one.
+        if !state.take_demo_completion(msg.chat.id) {
+            tracing::info!("Demo limit reached in tool calls, user: {}", msg.chat.id);
+            demo_limited = true;
+            break;
+        }
+        messages.push(
+            ChatCompletionRequestAssistantMessage {
+                role: Role::Assistant,
+                tool_calls: Some(
+                    calls
+                        .iter()
+                        .map(|call| ChatCompletionMessageToolCall {
+                            id: call.id.clone(),
+                            r#type: ChatCompletionToolType::Function,
+                            function: FunctionCall {
+                                name: call.name.clone(),
+                                arguments: call.arguments.clone(),
+                            },
+                        })
+                        .collect(),
+                ),
+                ..Default::default()
+            }
+            .into(),
+        );
+        for call in calls {
+            let result = call_tool(&state.tools, &call.name, &call.arguments);
+            tracing::info!(
+                "Tool call, user: {}, function: {}, arguments: {}, result: {}",
+                msg.chat.id,
+                call.name,
+                call.arguments,
+                result
+            );
+            messages.push(
+                ChatCompletionRequestToolMessage {
+                    role: Role::Tool,
+                    content: result,
+                    tool_call_id: call.id,
+                }
+                .into(),
+            );
+        }
+    }
+    typing.take();
+    let stopped = active.token.is_cancelled();
+    drop(active);
+
+    let linear = matches!(mode, ReplyMode::History { .. } | ReplyMode::Continuation);
+    let notice = match (timed_out, failed) {
+        (true, _) => Some("The reply timed out, this is as far as it got."),
+        (false, Some(failure)) => Some(failure.message()),
+        (false, None) if demo_limited => Some(DEMO_LIMIT_TEXT),
+        (false, None) => finish_reason.and_then(|reason| finish_notice(reason, linear)),
+    };
+    if let Some(reason) = finish_reason {
+        if notice.is_some() {
+            tracing::warn!("Reply finished with {:?}, user: {}", reason, msg.chat.id);
+        }
+    }
+
+    let text = chunks.join("");
+    if text.is_empty() {
+        // Nothing answers the message then, which is left for the next one.
+        if timed_out || stopped || failed.is_some() || demo_limited {
+            roll_back(&state, &msg, &mode).await;
+        }
+        let notice = match timed_out {
+            true => Some(TIMEOUT_TEXT),
+            false => notice,
+        };
+        // The placeholder shows the notice, if there is one to show.
+        match (editor.take(), notice) {
+            (Some(editor), Some(notice)) => {
+                let message_id = editor.finish().await;
+                if let Err(err) = bot.edit_message_text(msg.chat.id, message_id, notice).await {
+                    tracing::warn!(
+                        "Failed to show notice in placeholder, user: {}: {}",
+                        msg.chat.id,
+                        err
+                    );
+                    bot.send_message(msg.chat.id, notice)
+                        .reply_to(reply_to)
+                        .await?;
+                }
+            }
+            (Some(editor), None) => editor.discard().await,
+            (None, Some(notice)) => {
+                bot.send_message(msg.chat.id, notice)
+                    .reply_to(reply_to)
+                    .await?;
+            }
+            (None, None) => {}
+        }
+        return Ok(());
+    }
+    let msg_id = match editor {
+        Some(editor) => Some(editor.finish().await),
+        None => None,
+    };
+    let branded = state.config.branding.apply(&text, model);
+    // The notice goes under the last part, with room left for it.
+    let reserve = notice.map_or(0, |notice| utf16_len(notice) + 2);
+    let parts = split_message(&branded, MESSAGE_LIMIT - reserve);
+    let last = parts.len().saturating_sub(1);
+    let note = |i| notice.filter(|_| i == last);
+    let mut parts = parts.into_iter();
+    let first = parts.next().unwrap_or_default();
+    let edited = match msg_id {
+        Some(id) => match edit_formatted(&bot, msg.chat.id, id, first, format, note(0)).await {
+            Ok(()) => Some(id),
+            // E.g. the placeholder was deleted while streaming.
+            Err(err) if is_uneditable(&err) => {
+                tracing::debug!(
+                    "Streamed message gone, sending the reply anew, user: {}: {}",
+                    msg.chat.id,
+                    err
+                );
+                None
+            }
+            Err(err) => return Err(err.into()),
+        },
+        None => None,
+    };
+    let first = match edited {
+        Some(id) => id,
+        None => {
+            send_formatted(&bot, msg.chat.id, reply_to, first, format, note(0))
+                .await?
+                .id
+        }
+    };
+    let mut reply_ids = vec![first];
+    for (i, part) in parts.enumerate() {
+        let reply = send_formatted(&bot, msg.chat.id, reply_to, part, format, note(i + 1)).await?;
+        reply_ids.push(reply.id);
+    }
+
+    // A reply that isn't valid JSON is shown but not kept.
+    let invalid_json = settings.json && serde_json::from_str::<serde_json::Value>(&text).is_err();
+    if invalid_json {
+        tracing::warn!("Reply is not valid JSON, user: {}", msg.chat.id);
+        bot.send_message(
+            msg.chat.id,
+            "The reply is not valid JSON, so it was not added to the history.",
+        )
+        .reply_to(reply_to)
+        .await?;
+    }
+
+    let completion_tokens = count_text_tokens(model, &text);
+    tracing::debug!(
+        prompt_tokens,
+        completion_tokens,
+        chunks = count,
+        elapsed = ?started.elapsed(),
+        "Reply finished"
+    );
+    histogram!("chatgpt_bot_reply_seconds", started.elapsed().as_secs_f64());
+    counter!("chatgpt_bot_tokens_total", prompt_tokens as u64, "model" => model.to_owned(), "kind" => "prompt");
+    counter!("chatgpt_bot_tokens_total", completion_tokens as u64, "model" => model.to_owned(), "kind" => "completion");
+    let reply = ChatMessage::new(Role::Assistant, text);
+    let usage = TokenUsage {
+        prompt_tokens: prompt_tokens as u64,
+        completion_tokens: completion_tokens as u64,
+    };
+    state.store().add_usage(key, model.to_owned(), usage).await;
+    state
+        .update_chat(key, |chat| {
+            if fingerprint.is_some() && fingerprint != chat.fingerprint {
+                tracing::info!(
+                    "System fingerprint {:?}, user: {}",
+                    fingerprint,
+                    msg.chat.id
+                );
+                chat.fingerprint = fingerprint;
+            }
+        })
+        .await;
+    let thread = match mode {
+        _ if invalid_json => None,
+        ReplyMode::Branch(mut thread) => {
+            thread.push(reply);
+            Some(thread)
+        }
+        ReplyMode::Detached(_) => None,
+        ReplyMode::Continuation => {
+            let reply = match state.store().get(key).await.pop() {
+                Some(mut last) if last.role == Role::Assistant => {
+                    state.store().pop(key).await;
+                    last.content.push_str(&reply.content);
+                    last
+                }
+                // The history changed while streaming.
+                _ => reply,
+            };
+            state.store().append(key, reply).await;
+            state
+                .update_chat(key, |chat| {
+                    chat.last_reply.extend(reply_ids.iter().copied())
+                })
+                .await;
+            match msg.chat.is_private() {
+                true => None,
+                false => Some(state.store().get(key).await),
+            }
+        }
+        ReplyMode::History { .. } => {
+            state.store().append(key, reply).await;
+            let dropped = match state.max_history(&msg.chat) {
+                Some(max) => state.cap_history(key, max).await,
+                None => 0,
+            };
+            if dropped > 0 {
+                tracing::info!(
+                    "Dropped {} messages over the history cap, user: {}",
+                    dropped,
+                    msg.chat.id
+                );
+            }
+            state
+                .update_chat(key, |chat| chat.last_reply = reply_ids.clone())
+                .await;
+            match msg.chat.is_private() {
+                true => None,
+                false => Some(state.store().get(key).await),
+            }
+        }
+    };
+    state.mark_dirty();
+    if let Some(thread) = thread {
+        state.threads.record(msg.chat.id, &reply_ids, thread);
+    }
+
+    if let (true, Some(&last)) = (linear && !invalid_json, reply_ids.last()) {
+        if let Err(err) = bot
+            .edit_message_reply_markup(msg.chat.id, last)
+            .reply_markup(ReplyAction::keyboard())
+            .await
+        {
+            tracing::warn!(
+                "Failed to add reply buttons, user: {}: {}",
+                msg.chat.id,
+                err
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Requests `n` replies to the history at once and shows them numbered, with
+/// buttons to pick the one that is added to the history.
+///
+/// With `rollback`, the last message was just added by the user and is
+/// removed again if the request fails.
+async fn reply_variants(
+    n: u8,
+    rollback: bool,
+    bot: Bot,
+    client: Client,
+    state: State,
+    msg: Message,
+) -> HandleResult {
+    let mode = ReplyMode::History {
+        rollback,
+        replacing: None,
+    };
+    let Some(_slot) = admit_reply(&bot, &state, &msg, &mode).await? else {
+        return Ok(());
+    };
+    let key = state.key(&msg);
+    let settings = state.settings(key).await;
+    let mut hists = state.store().get(key).await;
+    let reply_to = hists.last().and_then(|message| message.message_id);
+    if settings.json {
+        hists.insert(0, ChatMessage::new(Role::System, JSON_PROMPT));
+    }
+    let model = settings.model();
+    let max_tokens = state.max_tokens(&settings);
+    if !supports_vision(model) {
+        for message in &mut hists {
+            message.images.clear();
+        }
+    }
+    trim_to_budget(model, &mut hists, state.token_budget(model, max_tokens));
+    let prompt_tokens = count_prompt_tokens(model, &hists);
+    let typing = TypingIndicator::start(bot.clone(), msg.chat.id);
+    let active = state.start_stream(key);
+
+    let mut args = completion_args(&settings, max_tokens);
+    args.messages(to_request_messages(&hists)).n(n);
+    let opened = open_stream(
+        &client,
+        state.keys.as_ref(),
+        args.build()?,
+        &state.config.retry_policy,
+        false,
+        state.config.stream_timeout,
+        &active.token,
+    );
+    let opened = tokio::select! {
+        biased;
+        _ = active.token.cancelled() => Err(OpenError::Cancelled),
+        opened = opened => opened,
+    };
+    drop(active);
+    state.record_api_result(match opened {
+        Ok(_) => true,
+        Err(OpenError::Api(ref err)) => !is_retryable(err),
+        Err(OpenError::TimedOut) => false,
+        Err(OpenError::Cancelled) => true,
+    });
+    let mut stream = match opened {
+        Ok(stream) => stream,
+        Err(OpenError::Cancelled) => {
+            tracing::info!("Variants stopped, user: {}", msg.chat.id);
+            roll_back(&state, &msg, &mode).await;
+            return Ok(());
+        }
+        Err(OpenError::TimedOut) => {
+            drop(typing);
+            tracing::warn!("Variants timed out, user: {}", msg.chat.id);
+            roll_back(&state, &msg, &mode).await;
+            bot.send_message(msg.chat.id, TIMEOUT_TEXT)
+                .reply_to(state.reply_to(&msg).await)
+                .await?;
+            return Ok(());
+        }
+        Err(OpenError::Api(err)) => {
+            drop(typing);
+            let failure = ApiFailure::of(&err);
+            failure.log(msg.chat.id, &err);
+            increment_counter!("chatgpt_bot_errors_total", "type" => "openai");
+            roll_back(&state, &msg, &mode).await;
+            bot.send_message(msg.chat.id, failure.message())
+                .reply_to(state.reply_to(&msg).await)
+                .await?;
+            return Ok(());
+        }
+    };
+    let response = stream.next().await;
+    drop(typing);
+    let mut choices = match response {
+        Some(Ok(response)) => response.choices,
+        Some(Err(err)) => {
+            let failure = ApiFailure::of(&err);
+            failure.log(msg.chat.id, &err);
+            increment_counter!("chatgpt_bot_errors_total", "type" => "openai");
+            roll_back(&state, &msg, &mode).await;
+            bot.send_message(msg.chat.id, failure.message())
+                .reply_to(state.reply_to(&msg).await)
+                .await?;
+            return Ok(());
+        }
+        None => Vec::new(),
+    };
+    choices.sort_by_key(|choice| choice.index);
+    let choices: Vec<String> = choices
+        .into_iter()
+        .filter_map(|choice| choice.delta.content)
+        .filter(|content| !content.trim().is_empty())
+        // Like single replies, variants that aren't valid JSON aren't kept.
+        .filter(|content| {
+            !settings.json || serde_json::from_str::<serde_json::Value>(content).is_ok()
+        })
+        .collect();
+    if choices.is_empty() {
+        tracing::warn!("No usable variants, user: {}", msg.chat.id);
+        increment_counter!("chatgpt_bot_errors_total", "type" => "openai");
+        roll_back(&state, &msg, &mode).await;
+        bot.send_message(msg.chat.id, ApiFailure::Other.message())
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+        return Ok(());
+    }
+
+    let completion_tokens: usize = choices
+        .iter()
+        .map(|choice| count_text_tokens(model, choice))
+        .sum();
+    tracing::info!(
+        "Variants finished, user: {}, variants: {}",
+        msg.chat.id,
+        choices.len()
+    );
+    counter!("chatgpt_bot_tokens_total", prompt_tokens as u64, "model" => model.to_owned(), "kind" => "prompt");
+    counter!("chatgpt_bot_tokens_total", completion_tokens as u64, "model" => model.to_owned(), "kind" => "completion");
+
+    // Each variant gets an equal share of the message, with room for its
+    // number, an ellipsis and the blank line before the next one.
+    let share = MESSAGE_LIMIT / choices.len() - 8;
+    let text = choices
+        .iter()
+        .zip(1..)
+        .map(|(choice, number)| {
+            let end = prefix_end(choice, share);
+            let ellipsis = if end < choice.len() { "…" } else { "" };
+            format!("{}. {}{}", number, &choice[..end], ellipsis)
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let keyboard = InlineKeyboardMarkup::new([(0..choices.len()).map(|index| {
+        InlineKeyboardButton::callback((index + 1).to_string(), Button::Variant(index).data())
+    })]);
+    let sent = bot
+        .send_message(msg.chat.id, text)
+        .reply_to(state.reply_to(&msg).await)
+        .reply_markup(keyboard)
+        .await?;
+
+    let usage = TokenUsage {
+        prompt_tokens: prompt_tokens as u64,
+        completion_tokens: completion_tokens as u64,
+    };
+    state.store().add_usage(key, model.to_owned(), usage).await;
+    state
+        .update_chat(key, |chat| {
+            // The buttons of the previous reply no longer apply.
+            chat.last_reply.clear();
+            chat.variants = Some(PendingVariants {
+                message_id: sent.id,
+                reply_to,
+                choices,
+            });
+        })
+        .await;
+    state.mark_dirty();
+
+    Ok(())
+}
+
+/// Shows the picked variant `text` in place of the variants in `msg`, with
+/// the buttons of the latest reply.
+pub(crate) async fn show_variant(
+    text: String,
+    bot: Bot,
+    state: State,
+    msg: Message,
+) -> HandleResult {
+    let key = state.key(&msg);
+    if let Some(max) = state.max_history(&msg.chat) {
+        let dropped = state.cap_history(key, max).await;
+        if dropped > 0 {
+            tracing::info!(
+                "Dropped {} messages over the history cap, user: {}",
+                dropped,
+                msg.chat.id
+            );
+        }
+    }
+    let settings = state.settings(key).await;
+    let (format, model) = (settings.format, settings.model().to_owned());
+    let branded = state.config.branding.apply(&text, &model);
+    let mut parts = split_message(&branded, MESSAGE_LIMIT).into_iter();
+    let first = parts.next().unwrap_or_default();
+    edit_formatted(&bot, msg.chat.id, msg.id, first, format, None).await?;
+    let reply_to = msg.reply_to_message().map(|reply| reply.id);
+    let mut reply_ids = vec![msg.id];
+    for part in parts {
+        let reply = send_formatted(&bot, msg.chat.id, reply_to, part, format, None).await?;
+        reply_ids.push(reply.id);
+    }
+    let last = *reply_ids.last().unwrap_or(&msg.id);
+    state
+        .update_chat(key, |chat| chat.last_reply = reply_ids)
+        .await;
+
+    if let Err(err) = bot
+        .edit_message_reply_markup(msg.chat.id, last)
+        .reply_markup(ReplyAction::keyboard())
+        .await
+    {
+        tracing::warn!(
+            "Failed to add reply buttons, user: {}: {}",
+            msg.chat.id,
+            err
+        );
+    }
+
+    Ok(())
+}
+
+/// What a button under a bot message does.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Button {
+    Reply(ReplyAction),
+    /// Picks the variant with this index.
+    Variant(usize),
+}
+
+impl Button {
+    fn data(self) -> String {
+        match self {
+            Self::Reply(action) => action.data().to_owned(),
+            Self::Variant(index) => format!("v{}", index + 1),
+        }
+    }
+
+    pub(crate) fn parse(data: &str) -> Option<Self> {
+        match data.strip_prefix('v') {
+            Some(number) => number
+                .parse::<usize>()
+                .ok()?
+                .checked_sub(1)
+                .map(Self::Variant),
+            None => ReplyAction::parse(data).map(Self::Reply),
+        }
+    }
+}
+
+/// Actions of the buttons under the latest reply.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum ReplyAction {
+    Regenerate,
+    Undo,
+    Clear,
+}
+
+impl ReplyAction {
+    const ALL: [Self; 3] = [Self::Regenerate, Self::Undo, Self::Clear];
+
+    fn data(self) -> &'static str {
+        match self {
+            Self::Regenerate => "r",
+            Self::Undo => "u",
+            Self::Clear => "c",
+        }
+    }
+
+    fn parse(data: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|action| action.data() == data)
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Regenerate => "🔄 Regenerate",
+            Self::Undo => "↩️ Undo",
+            Self::Clear => "🗑 Clear",
+        }
+    }
+
+    fn keyboard() -> InlineKeyboardMarkup {
+        InlineKeyboardMarkup::new([
+            Self::ALL.map(|action| InlineKeyboardButton::callback(action.label(), action.data()))
+        ])
+    }
+}
+
+/// What to tell the user about a reply that ended for `reason`, if anything.
+fn finish_notice(reason: FinishReason, continuable: bool) -> Option<&'static str> {
+    match reason {
+        FinishReason::ContentFilter => Some("The reply was cut off by OpenAI's content filter."),
+        FinishReason::Length if continuable => {
+            Some("The reply was cut off because it got too long, use /continue to get the rest.")
+        }
+        FinishReason::Length => Some("The reply was cut off because it got too long."),
+        FinishReason::Stop | FinishReason::ToolCalls | FinishReason::FunctionCall => None,
+    }
+}
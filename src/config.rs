@@ -0,0 +1,899 @@
+//! Settings read from the environment at startup.
+
+use async_openai::config::{AzureConfig, Config as _, OpenAIConfig};
+use async_openai::error::OpenAIError;
+use dashmap::DashSet;
+use rand::Rng;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{env, fs};
+use teloxide::prelude::*;
+
+use crate::state::TokenUsage;
+
+pub(crate) type Client = async_openai::Client<ApiConfig>;
+pub(crate) type Allowlist = Arc<AllowedChats>;
+
+const EDIT_EVERY_N_CHUNKS: usize = 20;
+/// Minimum time between two saves of the histories.
+const SAVE_DEBOUNCE: Duration = Duration::from_secs(2);
+const CONVERSATION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+pub(crate) const DUPLICATE_WINDOW: Duration = Duration::from_secs(10);
+/// Default number of completions served in demo mode.
+const DEMO_LIMIT: u64 = 100;
+/// Consecutive failed OpenAI requests after which requests are paused.
+const BREAKER_THRESHOLD: u32 = 5;
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(60);
+/// Characters a streamed reply needs before it's first shown.
+const FIRST_CHUNK_CHARS: usize = 40;
+/// How long a streamed reply waits for `FIRST_CHUNK_CHARS` after its first
+/// token before being shown anyway.
+const FIRST_CHUNK_WAIT: Duration = Duration::from_secs(1);
+const WEBHOOK_PORT: u16 = 8443;
+const WEBHOOK_PATH: &str = "/webhook";
+const STREAM_TIMEOUT: Duration = Duration::from_secs(60);
+const SLOW_REPLY_AFTER: Duration = Duration::from_secs(10);
+pub(crate) const API_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+const OPENAI_MAX_RETRIES: u32 = 3;
+const OPENAI_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+const OPENAI_RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+/// Default `(model prefix, prompt, completion)` prices in dollars per 1K tokens.
+const DEFAULT_PRICES: &[(&str, f64, f64)] = &[
+    ("gpt-3.5-turbo", 0.0015, 0.002),
+    ("gpt-4", 0.03, 0.06),
+    ("gpt-4-32k", 0.06, 0.12),
+];
+
+/// An environment variable the bot can't start with.
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum ConfigError {
+    #[error("Invalid {key}={value:?}: {reason}")]
+    Invalid {
+        key: &'static str,
+        value: String,
+        reason: String,
+    },
+    #[error("{key} is required {reason}")]
+    Missing {
+        key: &'static str,
+        reason: &'static str,
+    },
+}
+
+/// Every setting of the bot, read from the environment once at startup.
+///
+/// Optional settings fall back to their defaults, but a malformed value is an
+/// error rather than being ignored.
+pub(crate) struct Config {
+    pub(crate) api: ApiConfig,
+    /// API keys replies take turns with, the first one is also used for
+    /// everything else.
+    pub(crate) api_keys: Vec<String>,
+    /// Port of the Prometheus endpoint, off without one.
+    pub(crate) metrics_port: Option<u16>,
+    /// Where Telegram sends updates to, they are polled for without one.
+    pub(crate) webhook: Option<Webhook>,
+    /// File the histories are kept in, in memory only without one.
+    pub(crate) history_path: Option<PathBuf>,
+    /// Directory `/export_all` writes backups to, instead of sending them.
+    pub(crate) backup_dir: Option<PathBuf>,
+    /// Minimum time between two saves of the histories.
+    pub(crate) save_debounce: Duration,
+    /// Chats allowed to talk to the bot, `None` allows every chat.
+    pub(crate) allowed_chats: Option<HashSet<ChatId>>,
+    /// Chats allowed to use admin commands.
+    pub(crate) admins: HashSet<ChatId>,
+    /// File `/feedback` appends to, as JSON lines.
+    pub(crate) feedback_path: Option<PathBuf>,
+    /// Chat `/feedback` is forwarded to.
+    pub(crate) feedback_chat: Option<ChatId>,
+    /// System prompt new conversations start with.
+    pub(crate) default_prompt: Option<String>,
+    /// System prompts `/preset` applies, by name.
+    pub(crate) presets: BTreeMap<String, String>,
+    /// Whether group members get a history of their own.
+    pub(crate) per_user_history: bool,
+    pub(crate) token_budget: Option<usize>,
+    /// Default maximum length of replies in tokens.
+    pub(crate) max_tokens: Option<u16>,
+    /// Maximum number of non-system messages kept per conversation.
+    pub(crate) max_history: Option<usize>,
+    /// `max_history` of private chats, which falls back to it.
+    pub(crate) max_history_private: Option<usize>,
+    /// `max_history` of groups and channels, which falls back to it.
+    pub(crate) max_history_group: Option<usize>,
+    /// Prompt size in tokens above which old messages get summarized.
+    pub(crate) compact_threshold: Option<usize>,
+    pub(crate) edit_throttle: EditThrottle,
+    /// Characters a streamed reply needs before it's first shown.
+    pub(crate) first_chunk_chars: usize,
+    /// How long after its first token a streamed reply is shown even if it's
+    /// shorter than `first_chunk_chars`.
+    pub(crate) first_chunk_wait: Duration,
+    pub(crate) retry_policy: RetryPolicy,
+    /// How long a streamed reply may go without a chunk before it's ended.
+    pub(crate) stream_timeout: Option<Duration>,
+    /// Completion requests allowed per chat and minute, unlimited without one.
+    pub(crate) rate_limit: Option<usize>,
+    /// Consecutive failed OpenAI requests after which requests are paused for
+    /// `breaker_cooldown`, never without one.
+    pub(crate) breaker_threshold: Option<u32>,
+    pub(crate) breaker_cooldown: Duration,
+    pub(crate) prices: PriceTable,
+    pub(crate) tools_enabled: bool,
+    pub(crate) branding: Branding,
+    pub(crate) placeholder: Placeholder,
+    /// A user message repeating the previous one within this window is not
+    /// added to the history again.
+    pub(crate) duplicate_window: Duration,
+    pub(crate) busy_policy: BusyPolicy,
+    /// Replies generated at the same time across all chats, unlimited if
+    /// `None`.
+    pub(crate) max_concurrent_replies: Option<usize>,
+    /// Conversations without activity for this long are reset.
+    pub(crate) conversation_ttl: Option<Duration>,
+    /// Whether to tell chats when their conversation expired.
+    pub(crate) notify_expiry: bool,
+    /// Whether replies are streamed by default, instead of sent at once.
+    pub(crate) streaming: bool,
+    /// Whether bot messages reply to the message they answer by default.
+    pub(crate) reply_threading: bool,
+    /// Whether streamed replies show their progress towards `max_tokens` by
+    /// default.
+    pub(crate) progress: bool,
+    /// Whether previews of streamed replies end in a cursor.
+    pub(crate) cursor: bool,
+    /// In demo mode, the number of completions served to non-admins before
+    /// the bot stops answering.
+    pub(crate) demo_limit: Option<u64>,
+}
+
+impl Config {
+    pub(crate) fn from_env() -> Result<Self, ConfigError> {
+        let api_keys: Vec<String> = env_string("OPENAI_API_KEYS")
+            .map(|keys| {
+                keys.split(',')
+                    .map(str::trim)
+                    .filter(|key| !key.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let api = ApiConfig::from_env()?;
+        Ok(Self {
+            api: match api_keys.first() {
+                Some(key) => api.with_api_key(key),
+                None => api,
+            },
+            api_keys,
+            metrics_port: env_parse("METRICS_PORT")?,
+            webhook: Webhook::from_env()?,
+            history_path: env::var_os("HISTORY_PATH").map(PathBuf::from),
+            backup_dir: env::var_os("BACKUP_DIR").map(PathBuf::from),
+            save_debounce: env_parse("SAVE_DEBOUNCE_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(SAVE_DEBOUNCE),
+            allowed_chats: parse_chat_ids("ALLOWED_CHAT_IDS")?,
+            admins: parse_chat_ids("ADMIN_CHAT_IDS")?.unwrap_or_default(),
+            feedback_path: env::var_os("FEEDBACK_PATH").map(PathBuf::from),
+            feedback_chat: env_parse("FEEDBACK_CHAT_ID")?.map(ChatId),
+            default_prompt: env_string("DEFAULT_SYSTEM_PROMPT"),
+            presets: env::var_os("PRESETS_PATH")
+                .map(|path| load_presets(Path::new(&path)))
+                .unwrap_or_default(),
+            per_user_history: env_parse("PER_USER_HISTORY")?.unwrap_or(false),
+            token_budget: env_parse("TOKEN_BUDGET")?,
+            max_tokens: env_parse("MAX_TOKENS")?,
+            max_history: env_parse("MAX_HISTORY_MESSAGES")?,
+            max_history_private: env_parse("MAX_HISTORY_PRIVATE")?,
+            max_history_group: env_parse("MAX_HISTORY_GROUP")?,
+            compact_threshold: env_parse("COMPACT_THRESHOLD")?,
+            edit_throttle: EditThrottle::from_env()?,
+            first_chunk_chars: env_parse("FIRST_CHUNK_CHARS")?.unwrap_or(FIRST_CHUNK_CHARS),
+            first_chunk_wait: env_parse("FIRST_CHUNK_WAIT_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(FIRST_CHUNK_WAIT),
+            retry_policy: RetryPolicy::from_env()?,
+            stream_timeout: match env_parse("STREAM_TIMEOUT_SECS")? {
+                Some(0) => None,
+                Some(secs) => Some(Duration::from_secs(secs)),
+                None => Some(STREAM_TIMEOUT),
+            },
+            rate_limit: env_positive("RATE_LIMIT_PER_MINUTE")?,
+            breaker_threshold: match env_parse("BREAKER_THRESHOLD")? {
+                Some(0) => None,
+                Some(threshold) => Some(threshold),
+                None => Some(BREAKER_THRESHOLD),
+            },
+            breaker_cooldown: env_positive("BREAKER_COOLDOWN_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(BREAKER_COOLDOWN),
+            prices: PriceTable::from_env()?,
+            tools_enabled: env_parse("ENABLE_TOOLS")?.unwrap_or(true),
+            branding: Branding::from_env(),
+            placeholder: Placeholder::from_env()?,
+            duplicate_window: env_parse("DUPLICATE_WINDOW_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(DUPLICATE_WINDOW),
+            busy_policy: env_parse("BUSY_POLICY")?.unwrap_or_default(),
+            max_concurrent_replies: env_positive("MAX_PARALLEL_REPLIES")?,
+            conversation_ttl: match env_parse("CONVERSATION_TTL_SECS")? {
+                Some(0) => None,
+                Some(secs) => Some(Duration::from_secs(secs)),
+                None => Some(CONVERSATION_TTL),
+            },
+            notify_expiry: env_parse("NOTIFY_EXPIRY")?.unwrap_or(false),
+            streaming: env_parse("STREAMING")?.unwrap_or(true),
+            reply_threading: env_parse("REPLY_THREADING")?.unwrap_or(true),
+            progress: env_parse("SHOW_PROGRESS")?.unwrap_or(false),
+            cursor: env_parse("STREAM_CURSOR")?.unwrap_or(true),
+            demo_limit: match env_parse("DEMO_MODE")?.unwrap_or(false) {
+                true => Some(env_positive("DEMO_LIMIT")?.unwrap_or(DEMO_LIMIT)),
+                false => None,
+            },
+        })
+    }
+
+    /// A configuration talking to `api`, with every other setting at its
+    /// default.
+    #[cfg(test)]
+    pub(crate) fn with_api(api: ApiConfig) -> Self {
+        Self {
+            api,
+            api_keys: Vec::new(),
+            metrics_port: None,
+            webhook: None,
+            history_path: None,
+            backup_dir: None,
+            save_debounce: SAVE_DEBOUNCE,
+            allowed_chats: None,
+            admins: HashSet::new(),
+            feedback_path: None,
+            feedback_chat: None,
+            default_prompt: None,
+            presets: BTreeMap::new(),
+            per_user_history: false,
+            token_budget: None,
+            max_tokens: None,
+            max_history: None,
+            max_history_private: None,
+            max_history_group: None,
+            compact_threshold: None,
+            edit_throttle: EditThrottle::Chunks(EDIT_EVERY_N_CHUNKS),
+            first_chunk_chars: FIRST_CHUNK_CHARS,
+            first_chunk_wait: FIRST_CHUNK_WAIT,
+            retry_policy: RetryPolicy {
+                max_retries: OPENAI_MAX_RETRIES,
+                base_delay: OPENAI_RETRY_BASE_DELAY,
+            },
+            stream_timeout: Some(STREAM_TIMEOUT),
+            rate_limit: None,
+            breaker_threshold: Some(BREAKER_THRESHOLD),
+            breaker_cooldown: BREAKER_COOLDOWN,
+            prices: PriceTable(Vec::new()),
+            tools_enabled: true,
+            branding: Branding {
+                prefix: None,
+                suffix: None,
+            },
+            placeholder: Placeholder {
+                text: None,
+                slow_text: None,
+                slow_after: SLOW_REPLY_AFTER,
+            },
+            duplicate_window: DUPLICATE_WINDOW,
+            busy_policy: BusyPolicy::default(),
+            max_concurrent_replies: None,
+            conversation_ttl: Some(CONVERSATION_TTL),
+            notify_expiry: false,
+            streaming: true,
+            reply_threading: true,
+            progress: false,
+            cursor: true,
+            demo_limit: None,
+        }
+    }
+}
+
+/// OpenAI or an API compatible with it, such as Azure OpenAI or a local
+/// server.
+#[derive(Clone, Debug)]
+pub(crate) enum ApiConfig {
+    OpenAI(OpenAIConfig),
+    Azure(AzureConfig),
+}
+
+impl ApiConfig {
+    /// Setting `OPENAI_API_VERSION` selects Azure OpenAI, which also needs
+    /// `OPENAI_API_BASE` and `AZURE_DEPLOYMENT_ID`.
+    fn from_env() -> Result<Self, ConfigError> {
+        let base = env_string("OPENAI_API_BASE").map(|base| base.trim_end_matches('/').to_owned());
+        let Some(version) = env_string("OPENAI_API_VERSION") else {
+            let config = OpenAIConfig::new();
+            return Ok(Self::OpenAI(match base {
+                Some(base) => config.with_api_base(base),
+                None => config,
+            }));
+        };
+
+        let required = |key| ConfigError::Missing {
+            key,
+            reason: "for Azure OpenAI",
+        };
+        let base = base.ok_or_else(|| required("OPENAI_API_BASE"))?;
+        let deployment =
+            env_string("AZURE_DEPLOYMENT_ID").ok_or_else(|| required("AZURE_DEPLOYMENT_ID"))?;
+        Ok(Self::Azure(
+            AzureConfig::new()
+                .with_api_version(version)
+                .with_api_base(base)
+                .with_deployment_id(deployment),
+        ))
+    }
+}
+
+impl ApiConfig {
+    pub(crate) fn with_api_key(&self, key: &str) -> Self {
+        match self {
+            Self::OpenAI(config) => Self::OpenAI(config.clone().with_api_key(key)),
+            Self::Azure(config) => Self::Azure(config.clone().with_api_key(key)),
+        }
+    }
+}
+
+impl async_openai::config::Config for ApiConfig {
+    fn headers(&self) -> reqwest::header::HeaderMap {
+        match self {
+            Self::OpenAI(config) => config.headers(),
+            Self::Azure(config) => config.headers(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        match self {
+            Self::OpenAI(config) => config.url(path),
+            Self::Azure(config) => config.url(path),
+        }
+    }
+
+    fn query(&self) -> Vec<(&str, &str)> {
+        match self {
+            Self::OpenAI(config) => config.query(),
+            Self::Azure(config) => config.query(),
+        }
+    }
+
+    fn api_base(&self) -> &str {
+        match self {
+            Self::OpenAI(config) => config.api_base(),
+            Self::Azure(config) => config.api_base(),
+        }
+    }
+
+    fn api_key(&self) -> &secrecy::Secret<String> {
+        match self {
+            Self::OpenAI(config) => config.api_key(),
+            Self::Azure(config) => config.api_key(),
+        }
+    }
+}
+
+/// Checks that the API is reachable with a cheap request, only logging the
+/// outcome so that the bot still starts if the API is temporarily down.
+pub(crate) async fn check_api(client: &Client) {
+    let base = client.config().api_base();
+    match tokio::time::timeout(API_CHECK_TIMEOUT, client.models().list()).await {
+        Ok(Ok(_)) => tracing::info!("Connected to the API at {}", base),
+        Ok(Err(OpenAIError::Reqwest(err))) => {
+            tracing::error!("The API at {} is unreachable: {}", base, err)
+        }
+        Ok(Err(err)) => tracing::warn!("The API at {} responded with an error: {}", base, err),
+        Err(_) => tracing::error!(
+            "The API at {} didn't respond within {:?}",
+            base,
+            API_CHECK_TIMEOUT
+        ),
+    }
+}
+
+/// Price in dollars per 1K tokens.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ModelPrice {
+    prompt: f64,
+    completion: f64,
+}
+
+impl ModelPrice {
+    pub(crate) fn cost(&self, usage: &TokenUsage) -> f64 {
+        (usage.prompt_tokens as f64 * self.prompt
+            + usage.completion_tokens as f64 * self.completion)
+            / 1000.0
+    }
+}
+
+/// Prices of model families, matched by the longest prefix of the model name.
+pub(crate) struct PriceTable(Vec<(String, ModelPrice)>);
+
+impl PriceTable {
+    /// Reads `MODEL_PRICES`, e.g. `gpt-4=0.03:0.06,gpt-3.5-turbo=0.0015:0.002`,
+    /// on top of the default prices.
+    fn from_env() -> Result<Self, ConfigError> {
+        let mut prices: Vec<(String, ModelPrice)> = DEFAULT_PRICES
+            .iter()
+            .map(|&(model, prompt, completion)| {
+                (model.to_owned(), ModelPrice { prompt, completion })
+            })
+            .collect();
+
+        if let Ok(value) = env::var("MODEL_PRICES") {
+            for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+                let parsed = entry.split_once('=').and_then(|(model, price)| {
+                    let (prompt, completion) = price.split_once(':')?;
+                    let price = ModelPrice {
+                        prompt: prompt.trim().parse().ok()?,
+                        completion: completion.trim().parse().ok()?,
+                    };
+                    Some((model.trim().to_owned(), price))
+                });
+                let Some((model, price)) = parsed else {
+                    return Err(ConfigError::Invalid {
+                        key: "MODEL_PRICES",
+                        value: value.clone(),
+                        reason: format!("expected model=prompt:completion, got {:?}", entry),
+                    });
+                };
+                prices.retain(|(m, _)| *m != model);
+                prices.push((model, price));
+            }
+        }
+
+        prices.sort_by_key(|(model, _)| std::cmp::Reverse(model.len()));
+        Ok(Self(prices))
+    }
+
+    pub(crate) fn get(&self, model: &str) -> Option<ModelPrice> {
+        self.0
+            .iter()
+            .find(|(prefix, _)| model.starts_with(prefix.as_str()))
+            .map(|&(_, price)| price)
+    }
+}
+
+/// How often the streamed reply message gets edited.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum EditThrottle {
+    /// Edit after every `n` non-empty chunks.
+    Chunks(usize),
+    /// Edit at most once per interval.
+    Interval(Duration),
+}
+
+impl EditThrottle {
+    fn from_env() -> Result<Self, ConfigError> {
+        if let Some(ms) = env_parse("EDIT_INTERVAL_MS")? {
+            return Ok(Self::Interval(Duration::from_millis(ms)));
+        }
+        Ok(Self::Chunks(
+            env_positive("EDIT_EVERY_N_CHUNKS")?.unwrap_or(EDIT_EVERY_N_CHUNKS),
+        ))
+    }
+
+    pub(crate) fn should_edit(&self, count: usize, last_edit: Instant) -> bool {
+        match *self {
+            Self::Chunks(n) => count.is_multiple_of(n),
+            Self::Interval(interval) => last_edit.elapsed() >= interval,
+        }
+    }
+}
+
+/// Exponential backoff with jitter for transient OpenAI failures.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RetryPolicy {
+    pub(crate) max_retries: u32,
+    base_delay: Duration,
+}
+
+impl RetryPolicy {
+    fn from_env() -> Result<Self, ConfigError> {
+        Ok(Self {
+            max_retries: env_parse("OPENAI_MAX_RETRIES")?.unwrap_or(OPENAI_MAX_RETRIES),
+            base_delay: env_parse("OPENAI_RETRY_BASE_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(OPENAI_RETRY_BASE_DELAY),
+        })
+    }
+
+    /// Delay before retry number `attempt` (starting from 0), in
+    /// `[backoff / 2, backoff]` where the backoff doubles every attempt.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(OPENAI_RETRY_MAX_DELAY);
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// What happens to a request for a reply while the chat is still getting one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum BusyPolicy {
+    /// Wait for the current reply to finish.
+    #[default]
+    Wait,
+    /// Reject the request with a "please wait" message.
+    Reject,
+}
+
+impl FromStr for BusyPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "wait" => Ok(Self::Wait),
+            "reject" => Ok(Self::Reject),
+            _ => Err("expected wait or reject".to_owned()),
+        }
+    }
+}
+
+/// Messages shown while waiting for the first tokens of a reply.
+pub(crate) struct Placeholder {
+    /// Sent as soon as a reply starts, then edited into the reply.
+    pub(crate) text: Option<String>,
+    /// Shown if no tokens arrived within `slow_after`.
+    pub(crate) slow_text: Option<String>,
+    pub(crate) slow_after: Duration,
+}
+
+impl Placeholder {
+    fn from_env() -> Result<Self, ConfigError> {
+        Ok(Self {
+            text: env_string("PLACEHOLDER_TEXT"),
+            slow_text: env_string("SLOW_REPLY_TEXT"),
+            slow_after: env_parse("SLOW_REPLY_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(SLOW_REPLY_AFTER),
+        })
+    }
+}
+
+/// Lines added before and after every final reply, not stored in the history.
+///
+/// `{model}` is replaced with the model that wrote the reply.
+pub(crate) struct Branding {
+    prefix: Option<String>,
+    suffix: Option<String>,
+}
+
+impl Branding {
+    fn from_env() -> Self {
+        let var = |key| {
+            env::var(key)
+                .ok()
+                .filter(|value: &String| !value.is_empty())
+        };
+        Self {
+            prefix: var("REPLY_PREFIX"),
+            suffix: var("REPLY_SUFFIX"),
+        }
+    }
+
+    pub(crate) fn apply(&self, text: &str, model: &str) -> String {
+        let mut branded = String::new();
+        if let Some(ref prefix) = self.prefix {
+            branded.push_str(&prefix.replace("{model}", model));
+            branded.push('\n');
+        }
+        branded.push_str(text);
+        if let Some(ref suffix) = self.suffix {
+            // Close an unterminated code block so the suffix is not rendered in it.
+            let fences = text
+                .lines()
+                .filter(|line| line.trim_start().starts_with("```"))
+                .count();
+            if !fences.is_multiple_of(2) {
+                branded.push_str("\n```");
+            }
+            branded.push_str("\n\n");
+            branded.push_str(&suffix.replace("{model}", model));
+        }
+        branded
+    }
+}
+
+/// The server Telegram sends updates to, instead of the bot polling for them.
+pub(crate) struct Webhook {
+    /// Public URL of the updates endpoint, its path is also the one served.
+    pub(crate) url: url::Url,
+    /// Local port the server listens on, e.g. behind a load balancer.
+    pub(crate) port: u16,
+}
+
+impl Webhook {
+    fn from_env() -> Result<Option<Self>, ConfigError> {
+        let Some(base) = env_string("WEBHOOK_URL") else {
+            return Ok(None);
+        };
+        let path = env_string("WEBHOOK_PATH").unwrap_or_else(|| WEBHOOK_PATH.to_owned());
+        let url = webhook_url(&base, &path).map_err(|reason| ConfigError::Invalid {
+            key: "WEBHOOK_URL",
+            value: base,
+            reason,
+        })?;
+        Ok(Some(Self {
+            url,
+            port: env_parse("WEBHOOK_PORT")?.unwrap_or(WEBHOOK_PORT),
+        }))
+    }
+}
+
+/// The URL of the updates endpoint at `path` of the server at `base`.
+fn webhook_url(base: &str, path: &str) -> Result<url::Url, String> {
+    let mut url = url::Url::parse(base).map_err(|err| err.to_string())?;
+    if url.scheme() != "https" {
+        return Err("Telegram only sends updates over https".to_owned());
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err("expected no query or fragment".to_owned());
+    }
+    let path = format!(
+        "{}/{}",
+        url.path().trim_end_matches('/'),
+        path.trim_start_matches('/')
+    );
+    url.set_path(&path);
+    Ok(url)
+}
+
+/// Chats allowed to talk to the bot.
+pub(crate) struct AllowedChats {
+    /// `None` allows every chat.
+    chats: Option<HashSet<ChatId>>,
+    /// Chats that have already been told they are not allowed.
+    pub(crate) denied: DashSet<ChatId>,
+}
+
+impl AllowedChats {
+    pub(crate) fn new(chats: Option<HashSet<ChatId>>) -> Self {
+        Self {
+            chats,
+            denied: DashSet::new(),
+        }
+    }
+
+    pub(crate) fn contains(&self, chat_id: ChatId) -> bool {
+        self.chats
+            .as_ref()
+            .is_none_or(|chats| chats.contains(&chat_id))
+    }
+}
+
+/// Reads the presets in the JSON object at `path`, mapping names to system
+/// prompts.
+///
+/// Unlike the environment, a bad file or entry only loses some presets, so it
+/// is skipped with a warning.
+fn load_presets(path: &Path) -> BTreeMap<String, String> {
+    let entries = fs::read(path)
+        .map_err(|err| err.to_string())
+        .and_then(|data| {
+            serde_json::from_slice::<serde_json::Map<String, serde_json::Value>>(&data)
+                .map_err(|err| err.to_string())
+        });
+    let entries = match entries {
+        Ok(entries) => entries,
+        Err(err) => {
+            tracing::warn!("Ignoring presets file {}: {}", path.display(), err);
+            return BTreeMap::new();
+        }
+    };
+
+    let presets = parse_presets(entries);
+    tracing::info!("Loaded {} presets from {}", presets.len(), path.display());
+    presets
+}
+
+fn parse_presets(entries: serde_json::Map<String, serde_json::Value>) -> BTreeMap<String, String> {
+    entries
+        .into_iter()
+        .filter_map(|(name, prompt)| {
+            let name = name.trim().to_lowercase();
+            match prompt.as_str().map(str::trim) {
+                Some(prompt) if !name.is_empty() && !prompt.is_empty() => {
+                    Some((name, prompt.to_owned()))
+                }
+                _ => {
+                    tracing::warn!("Ignoring invalid preset {:?}: {}", name, prompt);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Parses the comma-separated chat ids in the environment variable `key`.
+fn parse_chat_ids(key: &'static str) -> Result<Option<HashSet<ChatId>>, ConfigError> {
+    let Ok(value) = env::var(key) else {
+        return Ok(None);
+    };
+    let ids = value
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| match id.parse() {
+            Ok(id) => Ok(ChatId(id)),
+            Err(err) => Err(ConfigError::Invalid {
+                key,
+                value: value.clone(),
+                reason: format!("{:?} is not a chat id: {}", id, err),
+            }),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Some(ids))
+}
+
+/// The environment variable `key`, unless it's unset or blank.
+fn env_string(key: &str) -> Option<String> {
+    env::var(key).ok().filter(|value| !value.trim().is_empty())
+}
+
+/// Parses the environment variable `key`, `None` if it's unset.
+fn env_parse<T: FromStr>(key: &'static str) -> Result<Option<T>, ConfigError>
+where
+    T::Err: std::fmt::Display,
+{
+    let Ok(value) = env::var(key) else {
+        return Ok(None);
+    };
+    match value.trim().parse() {
+        Ok(parsed) => Ok(Some(parsed)),
+        Err(err) => Err(ConfigError::Invalid {
+            key,
+            value,
+            reason: err.to_string(),
+        }),
+    }
+}
+
+/// Parses the environment variable `key` as a number that has to be positive.
+fn env_positive<T: FromStr + Default + PartialEq>(
+    key: &'static str,
+) -> Result<Option<T>, ConfigError>
+where
+    T::Err: std::fmt::Display,
+{
+    match env_parse(key)? {
+        Some(value) if value == T::default() => Err(ConfigError::Invalid {
+            key,
+            value: env::var(key).unwrap_or_default(),
+            reason: "must be positive".to_owned(),
+        }),
+        value => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhook_url_joins_the_path() {
+        for (base, path, url) in [
+            (
+                "https://bot.example.com",
+                "/webhook",
+                "https://bot.example.com/webhook",
+            ),
+            (
+                "https://example.com/bot/",
+                "updates",
+                "https://example.com/bot/updates",
+            ),
+        ] {
+            assert_eq!(webhook_url(base, path).unwrap().as_str(), url);
+        }
+        assert!(webhook_url("http://bot.example.com", "/webhook").is_err());
+        assert!(webhook_url("bot.example.com", "/webhook").is_err());
+        assert!(webhook_url("https://bot.example.com?token=1", "/webhook").is_err());
+    }
+
+    #[test]
+    fn parse_presets_skips_invalid_entries() {
+        let entries = serde_json::json!({
+            "Coder": " You are a senior engineer. ",
+            "empty": "",
+            "number": 42,
+        });
+        let serde_json::Value::Object(entries) = entries else {
+            unreachable!();
+        };
+        assert_eq!(
+            parse_presets(entries),
+            BTreeMap::from([("coder".to_owned(), "You are a senior engineer.".to_owned())])
+        );
+    }
+
+    #[test]
+    fn parse_chat_ids_rejects_malformed_ids() {
+        env::set_var("TEST_PARSE_CHAT_IDS", "1, -100123,,");
+        env::set_var("TEST_PARSE_CHAT_IDS_INVALID", "1, x");
+        assert_eq!(
+            parse_chat_ids("TEST_PARSE_CHAT_IDS").unwrap(),
+            Some(HashSet::from([ChatId(1), ChatId(-100123)]))
+        );
+        assert!(parse_chat_ids("TEST_PARSE_CHAT_IDS_INVALID").is_err());
+        assert_eq!(parse_chat_ids("TEST_PARSE_CHAT_IDS_UNSET").unwrap(), None);
+    }
+
+    #[test]
+    fn env_parse_rejects_malformed_values() {
+        env::set_var("TEST_ENV_PARSE_VALID", " 42 ");
+        env::set_var("TEST_ENV_PARSE_INVALID", "forty-two");
+        env::set_var("TEST_ENV_PARSE_ZERO", "0");
+        assert_eq!(env_parse::<u32>("TEST_ENV_PARSE_VALID").unwrap(), Some(42));
+        assert!(env_parse::<u32>("TEST_ENV_PARSE_INVALID").is_err());
+        assert_eq!(env_parse::<u32>("TEST_ENV_PARSE_UNSET").unwrap(), None);
+        assert_eq!(
+            env_positive::<u32>("TEST_ENV_PARSE_VALID").unwrap(),
+            Some(42)
+        );
+        assert!(env_positive::<u32>("TEST_ENV_PARSE_ZERO").is_err());
+    }
+
+    #[test]
+    fn busy_policy_parses_case_insensitively() {
+        assert_eq!(" Reject ".parse(), Ok(BusyPolicy::Reject));
+        assert_eq!("wait".parse(), Ok(BusyPolicy::Wait));
+        assert!("later".parse::<BusyPolicy>().is_err());
+    }
+
+    #[test]
+    fn price_table_matches_the_longest_prefix() {
+        env::set_var("MODEL_PRICES", "gpt-4=0.01:0.02, gpt-4o=0.005:0.015,");
+        let prices = PriceTable::from_env().unwrap();
+        assert_eq!(prices.get("gpt-4-0613").map(|p| p.prompt), Some(0.01));
+        assert_eq!(prices.get("gpt-4-32k-0314").map(|p| p.prompt), Some(0.06));
+        assert_eq!(prices.get("gpt-4o-mini").map(|p| p.prompt), Some(0.005));
+        assert!(prices.get("llama").is_none());
+
+        env::set_var("MODEL_PRICES", "gpt-4=0.01:0.02, gpt-4o");
+        assert!(PriceTable::from_env().is_err());
+    }
+
+    #[test]
+    fn model_price_costs_per_1k_tokens() {
+        let price = ModelPrice {
+            prompt: 0.002,
+            completion: 0.004,
+        };
+        let usage = TokenUsage {
+            prompt_tokens: 1500,
+            completion_tokens: 500,
+        };
+        assert!((price.cost(&usage) - 0.005).abs() < 1e-9);
+    }
+
+    #[test]
+    fn retry_delay_backs_off_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(100),
+        };
+        for _ in 0..20 {
+            let delay = policy.delay(2);
+            assert!(delay >= Duration::from_millis(200) && delay <= Duration::from_millis(400));
+            assert!(policy.delay(30) <= OPENAI_RETRY_MAX_DELAY);
+        }
+    }
+
+    #[test]
+    fn edit_throttle_counts_chunks() {
+        let throttle = EditThrottle::Chunks(3);
+        assert!(throttle.should_edit(3, Instant::now()));
+        assert!(!throttle.should_edit(4, Instant::now()));
+        assert!(EditThrottle::Interval(Duration::ZERO).should_edit(1, Instant::now()));
+    }
+}
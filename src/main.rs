@@ -1,218 +1,670 @@
+mod commands;
+mod completion;
+mod config;
+mod state;
+mod store;
+#[cfg(test)]
+mod tests;
+
 use async_openai::error::OpenAIError;
-use async_openai::types::ChatCompletionRequestMessage;
-use async_openai::{
-    types::{ChatCompletionRequestMessageArgs, CreateChatCompletionRequestArgs, Role},
-    Client,
-};
-use futures::StreamExt;
+use async_openai::types::{AudioInput, CreateTranscriptionRequestArgs, Role};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use metrics::increment_counter;
+use metrics_exporter_prometheus::PrometheusBuilder;
 use std::sync::Arc;
-use std::{collections::HashMap, sync::Mutex};
-use teloxide::RequestError;
-use teloxide::{prelude::*, utils::command::BotCommands};
+use std::time::Duration;
+use std::{env, io};
+use teloxide::dispatching::ShutdownToken;
+use teloxide::net::Download;
+use teloxide::prelude::*;
+use teloxide::types::{ChatAction, Me, MessageKind, UpdateKind};
+use teloxide::update_listeners::webhooks;
+use teloxide::utils::command::BotCommands;
+use teloxide::{DownloadError, RequestError};
+use tracing_subscriber::EnvFilter;
+
+use commands::{
+    clear_history, confirm_reset_all, load_history, regenerate, run_command, undo, Command,
+};
+use completion::{
+    complete_chat, complete_edit, complete_message, show_variant, supports_vision, ApiFailure,
+    Button, ReplyAction, ReplyTo,
+};
+use config::{check_api, AllowedChats, Allowlist, BusyPolicy, Client, Config};
+use state::{expire_conversations, AppState, ChatMessage, State};
 
-type ChatMessages = Vec<ChatCompletionRequestMessage>;
-type ChatHistories = HashMap<ChatId, ChatMessages>;
-type State = Arc<Mutex<ChatHistories>>;
 type HandleResult = Result<(), AppError>;
 
-const MODEL: &str = "gpt-3.5-turbo";
+const TRANSCRIPTION_MODEL: &str = "whisper-1";
+/// Maximum size of audio files accepted by the transcription API.
+const TRANSCRIPTION_FILE_LIMIT: u32 = 25 * 1024 * 1024;
+/// Telegram bots can't download files larger than 20MB.
+const IMAGE_FILE_LIMIT: u32 = 20 * 1024 * 1024;
+/// How long to wait for in-flight replies to finish on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(thiserror::Error, Debug)]
 pub enum AppError {
-    #[error("OpenAI api error")]
+    #[error("OpenAI api error: {0}")]
     OpenAI(#[from] OpenAIError),
 
-    #[error("Teloxide error")]
+    #[error("Teloxide error: {0}")]
     Teloxide(#[from] RequestError),
+
+    #[error("Download error: {0}")]
+    Download(#[from] DownloadError),
+
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
 }
 
-async fn complete_chat(
-    content: String,
+impl AppError {
+    /// Short name of the error source, used as a metrics label.
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::OpenAI(_) => "openai",
+            Self::Teloxide(_) => "telegram",
+            Self::Download(_) => "download",
+            Self::Io(_) => "io",
+        }
+    }
+}
+
+/// Runs the action of a reply button as if the user who pressed it had sent
+/// the command.
+///
+/// Only the buttons of the latest reply in the presser's history and of
+/// variants not picked yet work, older ones just get their buttons removed.
+#[tracing::instrument(name = "request", skip_all, fields(
+    chat_id = ?query.message.as_ref().map(|msg| msg.chat.id),
+    user_id = query.from.id.0,
+    command = ?query.data,
+))]
+async fn handle_callback(
     bot: Bot,
     client: Client,
     state: State,
-    msg: Message,
+    allowlist: Allowlist,
+    query: CallbackQuery,
 ) -> HandleResult {
-    log::info!("Complete chat, user: {}, content: {}", msg.chat.id, content);
-
-    let hists;
-    {
-        let mut guard = state.lock().unwrap();
-        let messages = guard.entry(msg.chat.id).or_default();
-        messages.push(
-            ChatCompletionRequestMessageArgs::default()
-                .role(Role::User)
-                .content(content)
-                .build()?,
+    let (Some(mut msg), Some(button)) =
+        (query.message, query.data.as_deref().and_then(Button::parse))
+    else {
+        bot.answer_callback_query(query.id).await?;
+        return Ok(());
+    };
+    if !allowlist.contains(msg.chat.id) {
+        bot.answer_callback_query(query.id).await?;
+        return Ok(());
+    }
+
+    // Act for the presser rather than the bot that sent the message.
+    if let MessageKind::Common(ref mut common) = msg.kind {
+        common.from = Some(query.from);
+    }
+    let key = state.key(&msg);
+    let picked = match button {
+        Button::Reply(_) => None,
+        Button::Variant(index) => state.pick_variant(key, msg.id, index).await,
+    };
+    let latest = match button {
+        Button::Reply(_) => state
+            .store()
+            .load(key)
+            .await
+            .is_some_and(|chat| chat.last_reply.contains(&msg.id)),
+        Button::Variant(_) => picked.is_some(),
+    };
+
+    let mut answer = bot.answer_callback_query(query.id);
+    if !latest {
+        answer = answer.text("This reply is outdated.");
+    }
+    answer.await?;
+    if let Err(err) = bot.edit_message_reply_markup(msg.chat.id, msg.id).await {
+        tracing::warn!(
+            "Failed to remove reply buttons, user: {}: {}",
+            msg.chat.id,
+            err
         );
-        hists = messages.clone();
+    }
+    if !latest {
+        return Ok(());
     }
 
-    let response = bot
-        .send_message(msg.chat.id, "💭")
-        .reply_to_message_id(msg.id)
+    tracing::info!("Reply button {:?}, user: {}", button, msg.chat.id);
+    let result = match (button, picked) {
+        (Button::Reply(ReplyAction::Regenerate), _) => {
+            regenerate(bot.clone(), client, state.clone(), msg.clone()).await
+        }
+        (Button::Reply(ReplyAction::Undo), _) => {
+            undo(bot.clone(), state.clone(), msg.clone()).await
+        }
+        (Button::Reply(ReplyAction::Clear), _) => {
+            clear_history(bot.clone(), state.clone(), msg.clone()).await
+        }
+        (Button::Variant(_), Some(text)) => {
+            state.mark_dirty();
+            show_variant(text, bot.clone(), state.clone(), msg.clone()).await
+        }
+        // Outdated, which was answered above.
+        (Button::Variant(_), None) => Ok(()),
+    };
+    reply_on_error(&bot, &state, &msg, result).await
+}
+
+/// Downloads the voice message in `msg` and transcribes it with Whisper.
+async fn transcribe_voice(bot: &Bot, client: &Client, msg: &Message) -> Result<String, AppError> {
+    let Some(voice) = msg.voice() else {
+        return Ok(String::new());
+    };
+    let file = bot.get_file(&voice.file.id).await?;
+    let path = env::temp_dir().join(format!("chatgpt_bot-{}-{}.ogg", msg.chat.id, msg.id));
+
+    let result = async {
+        let mut dst = tokio::fs::File::create(&path).await?;
+        bot.download_file(&file.path, &mut dst).await?;
+
+        let request = CreateTranscriptionRequestArgs::default()
+            .file(AudioInput::from(&path))
+            .model(TRANSCRIPTION_MODEL)
+            .build()?;
+        Ok(client.audio().transcribe(request).await?.text)
+    }
+    .await;
+
+    if let Err(err) = tokio::fs::remove_file(&path).await {
+        tracing::warn!("Failed to remove {}: {}", path.display(), err);
+    }
+    result
+}
+
+#[tracing::instrument(name = "request", skip_all, fields(
+    chat_id = %msg.chat.id,
+    user_id = ?msg.from().map(|user| user.id.0),
+    msg_id = msg.id.0,
+    command = "voice",
+))]
+async fn handle_voice(
+    bot: Bot,
+    client: Client,
+    state: State,
+    allowlist: Allowlist,
+    msg: Message,
+) -> HandleResult {
+    if !check_allowed(&bot, &allowlist, &msg).await? {
+        return Ok(());
+    }
+
+    let result = chat_voice(bot.clone(), client, state.clone(), msg.clone()).await;
+    reply_on_error(&bot, &state, &msg, result).await
+}
+
+async fn chat_voice(bot: Bot, client: Client, state: State, msg: Message) -> HandleResult {
+    let size = msg.voice().map_or(0, |voice| voice.file.size);
+    if size > TRANSCRIPTION_FILE_LIMIT {
+        bot.send_message(msg.chat.id, "The voice message is too long to transcribe.")
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+        return Ok(());
+    }
+
+    bot.send_chat_action(msg.chat.id, ChatAction::Typing)
         .await?;
-    let msg_id = response.id;
-
-    let request = CreateChatCompletionRequestArgs::default()
-        .model(MODEL)
-        .messages(hists)
-        .build()?;
-
-    let mut stream = client.chat().create_stream(request).await?;
-
-    let mut chunks = Vec::new();
-    let mut count = 0;
-    while let Some(result) = stream.next().await {
-        if let Some(ref content) = result?.choices.get(0).unwrap().delta.content {
-            chunks.push(content.to_owned());
-            if !content.trim().is_empty() {
-                count += 1;
-                if count % 20 == 0 {
-                    bot.edit_message_text(msg.chat.id, msg_id, chunks.join(""))
-                        .await?;
-                }
-            }
+    let content = match transcribe_voice(&bot, &client, &msg).await {
+        Ok(content) => content,
+        Err(err) => {
+            bot.send_message(msg.chat.id, "Failed to transcribe the voice message.")
+                .reply_to(state.reply_to(&msg).await)
+                .await?;
+            tracing::error!("Transcription failed, user: {}: {}", msg.chat.id, err);
+            return Ok(());
         }
+    };
+    if content.trim().is_empty() {
+        bot.send_message(msg.chat.id, "No speech recognized in the voice message.")
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+        return Ok(());
+    }
+
+    complete_chat(content, bot, client, state, msg).await
+}
+
+#[tracing::instrument(name = "request", skip_all, fields(
+    chat_id = %msg.chat.id,
+    user_id = ?msg.from().map(|user| user.id.0),
+    msg_id = msg.id.0,
+    command = "photo",
+))]
+async fn handle_photo(
+    bot: Bot,
+    client: Client,
+    state: State,
+    allowlist: Allowlist,
+    msg: Message,
+    content: String,
+) -> HandleResult {
+    if !check_allowed(&bot, &allowlist, &msg).await? {
+        return Ok(());
     }
-    bot.edit_message_text(msg.chat.id, msg_id, chunks.join(""))
+
+    let result = chat_photo(content, bot.clone(), client, state.clone(), msg.clone()).await;
+    reply_on_error(&bot, &state, &msg, result).await
+}
+
+/// Sends the largest size of the photo in `msg` to the model along with its
+/// caption `content`.
+async fn chat_photo(
+    content: String,
+    bot: Bot,
+    client: Client,
+    state: State,
+    msg: Message,
+) -> HandleResult {
+    let model = state.settings(state.key(&msg)).await.model().to_owned();
+    if !supports_vision(&model) {
+        bot.send_message(
+            msg.chat.id,
+            format!(
+                "{} can't see images, switch to a model like gpt-4o with /model first.",
+                model
+            ),
+        )
+        .reply_to(state.reply_to(&msg).await)
         .await?;
+        return Ok(());
+    }
 
-    let mut guard = state.lock().unwrap();
-    let messages = guard.entry(msg.chat.id).or_default();
-    messages.push(
-        ChatCompletionRequestMessageArgs::default()
-            .role(Role::Assistant)
-            .content(chunks.join(""))
-            .build()?,
-    );
+    let Some(photo) = msg
+        .photo()
+        .and_then(|sizes| sizes.iter().max_by_key(|size| size.width * size.height))
+    else {
+        return Ok(());
+    };
+    if photo.file.size > IMAGE_FILE_LIMIT {
+        bot.send_message(msg.chat.id, "The photo is too large.")
+            .reply_to(state.reply_to(&msg).await)
+            .await?;
+        return Ok(());
+    }
 
-    Ok(())
+    bot.send_chat_action(msg.chat.id, ChatAction::Typing)
+        .await?;
+    let url = match download_image(&bot, &photo.file.id).await {
+        Ok(url) => url,
+        Err(err) => {
+            bot.send_message(msg.chat.id, "Failed to download the photo.")
+                .reply_to(state.reply_to(&msg).await)
+                .await?;
+            tracing::error!("Photo download failed, user: {}: {}", msg.chat.id, err);
+            return Ok(());
+        }
+    };
+
+    let mut user_message = ChatMessage::new(Role::User, content);
+    user_message.images.push(url);
+    complete_message(user_message, bot, client, state, msg).await
 }
 
-async fn set_prompt(prompt: String, bot: Bot, state: State, msg: Message) -> HandleResult {
-    log::info!("Set prompt, user: {}, prompt: {}", msg.chat.id, prompt);
+/// Downloads a photo as a data URL, which doesn't expose the bot token to
+/// OpenAI like a Telegram file URL would.
+async fn download_image(bot: &Bot, file_id: &str) -> Result<String, AppError> {
+    let file = bot.get_file(file_id).await?;
+    let mut data = Vec::new();
+    bot.download_file(&file.path, &mut data).await?;
+    // Telegram converts photos to JPEG.
+    Ok(format!("data:image/jpeg;base64,{}", BASE64.encode(data)))
+}
 
-    {
-        let mut guard = state.lock().unwrap();
-        let messages = guard.entry(msg.chat.id).or_default();
-        messages.clear();
-        messages.push(
-            ChatCompletionRequestMessageArgs::default()
-                .role(Role::System)
-                .content(prompt)
-                .build()?,
-        );
+/// Whether `msg` comes from an allowed chat, telling unauthorized chats once.
+async fn check_allowed(bot: &Bot, allowlist: &Allowlist, msg: &Message) -> Result<bool, AppError> {
+    if allowlist.contains(msg.chat.id) {
+        return Ok(true);
     }
 
-    bot.send_message(msg.chat.id, "Prompt set.")
+    tracing::info!("Unauthorized chat: {}", msg.chat.id);
+    if allowlist.denied.insert(msg.chat.id) {
+        bot.send_message(
+            msg.chat.id,
+            "Sorry, this bot is private and not available in this chat.",
+        )
         .reply_to_message_id(msg.id)
         .await?;
+    }
+    Ok(false)
+}
 
-    Ok(())
+/// The chat input of a plain text message, or `None` if it is not meant for
+/// the bot. In groups, the bot has to be mentioned or replied to.
+fn chat_input(msg: &Message, me: &Me) -> Option<String> {
+    let text = msg.text()?;
+    if text.starts_with('/') {
+        return None;
+    }
+    addressed_input(text, msg, me).filter(|text| !text.is_empty())
 }
 
-async fn view_histories(bot: Bot, state: State, msg: Message) -> HandleResult {
-    let content = {
-        let mut guard = state.lock().unwrap();
-        let messages = guard.entry(msg.chat.id).or_default();
-        if messages.is_empty() {
-            "Empty chat history.".to_owned()
-        } else {
-            messages
-                .iter()
-                .map(|msg| format!("[{}]: {}", msg.role, msg.content.trim()))
-                .collect::<Vec<String>>()
-                .join("\n\n")
-        }
+/// The chat input of an edited message, as plain text or with `/chat`.
+fn edited_input(msg: &Message, me: &Me) -> Option<String> {
+    match Command::parse(msg.text()?, me.username()) {
+        Ok(Command::Chat(content)) => Some(content),
+        Ok(_) => None,
+        Err(_) => chat_input(msg, me),
+    }
+}
+
+/// The caption of a photo meant for the bot, possibly empty.
+fn photo_input(msg: &Message, me: &Me) -> Option<String> {
+    msg.photo()?;
+    addressed_input(msg.caption().unwrap_or_default(), msg, me)
+}
+
+/// `text` without the bot's mention if `msg` is meant for the bot, which in
+/// groups has to be mentioned or replied to.
+fn addressed_input(text: &str, msg: &Message, me: &Me) -> Option<String> {
+    if msg.chat.is_private() {
+        return Some(text.to_owned());
+    }
+
+    let mention = format!("@{}", me.username());
+    if text.contains(&mention) {
+        return Some(text.replace(&mention, "").trim().to_owned());
+    }
+    let replied = msg
+        .reply_to_message()
+        .and_then(Message::from)
+        .is_some_and(|user| user.id == me.id);
+    replied.then(|| text.to_owned())
+}
+
+#[tracing::instrument(name = "request", skip_all, fields(
+    chat_id = %msg.chat.id,
+    user_id = ?msg.from().map(|user| user.id.0),
+    msg_id = msg.id.0,
+    command = "chat",
+))]
+async fn handle_text(
+    bot: Bot,
+    client: Client,
+    state: State,
+    allowlist: Allowlist,
+    msg: Message,
+    content: String,
+) -> HandleResult {
+    if !check_allowed(&bot, &allowlist, &msg).await? {
+        return Ok(());
+    }
+
+    let result = match confirm_reset_all(&bot, &state, &msg, &content).await {
+        Ok(true) => Ok(()),
+        Ok(false) => complete_chat(content, bot.clone(), client, state.clone(), msg.clone()).await,
+        Err(err) => Err(err),
+    };
+    reply_on_error(&bot, &state, &msg, result).await
+}
+
+#[tracing::instrument(name = "request", skip_all, fields(
+    chat_id = %msg.chat.id,
+    user_id = ?msg.from().map(|user| user.id.0),
+    msg_id = msg.id.0,
+    command = "edit",
+))]
+async fn handle_edit(
+    bot: Bot,
+    client: Client,
+    state: State,
+    allowlist: Allowlist,
+    msg: Message,
+    content: String,
+) -> HandleResult {
+    if !check_allowed(&bot, &allowlist, &msg).await? {
+        return Ok(());
+    }
+
+    let result = complete_edit(content, bot.clone(), client, state.clone(), msg.clone()).await;
+    reply_on_error(&bot, &state, &msg, result).await
+}
+
+/// Logs a failed handler result and apologizes to the user, so a single bad
+/// request never goes unanswered.
+async fn reply_on_error(
+    bot: &Bot,
+    state: &State,
+    msg: &Message,
+    result: HandleResult,
+) -> HandleResult {
+    let Err(err) = result else {
+        return Ok(());
     };
 
+    increment_counter!("chatgpt_bot_errors_total", "type" => err.kind());
+    let content = match err {
+        AppError::OpenAI(ref err) => {
+            let failure = ApiFailure::of(err);
+            failure.log(msg.chat.id, err);
+            failure.message()
+        }
+        _ => {
+            tracing::error!("Failed to handle message, user: {}: {}", msg.chat.id, err);
+            "Sorry, something went wrong, please try again later."
+        }
+    };
     bot.send_message(msg.chat.id, content)
-        .reply_to_message_id(msg.id)
+        .reply_to(state.reply_to(msg).await)
         .await?;
 
     Ok(())
 }
 
-async fn clear_history(bot: Bot, state: State, msg: Message) -> HandleResult {
-    {
-        let mut guard = state.lock().unwrap();
-        let messages = guard.entry(msg.chat.id).or_default();
-        messages.clear();
+#[tracing::instrument(name = "request", skip_all, fields(
+    chat_id = %msg.chat.id,
+    user_id = ?msg.from().map(|user| user.id.0),
+    msg_id = msg.id.0,
+    command = "load",
+))]
+async fn handle_document(
+    bot: Bot,
+    state: State,
+    allowlist: Allowlist,
+    msg: Message,
+) -> HandleResult {
+    if !check_allowed(&bot, &allowlist, &msg).await? {
+        return Ok(());
     }
 
-    bot.send_message(msg.chat.id, "Chat histories cleared.")
-        .reply_to_message_id(msg.id)
-        .await?;
-
-    Ok(())
+    let result = load_history(bot.clone(), state.clone(), msg.clone()).await;
+    reply_on_error(&bot, &state, &msg, result).await
 }
 
+#[tracing::instrument(name = "request", skip_all, fields(
+    chat_id = %msg.chat.id,
+    user_id = ?msg.from().map(|user| user.id.0),
+    msg_id = msg.id.0,
+    command = %command_name(&msg),
+))]
 async fn handle_command(
     bot: Bot,
     client: Client,
     state: State,
+    allowlist: Allowlist,
     msg: Message,
     cmd: Command,
 ) -> HandleResult {
-    match cmd {
-        Command::Help => {
-            bot.send_message(msg.chat.id, Command::descriptions().to_string())
-                .await?;
-        }
-        Command::Prompt(prompt) => {
-            set_prompt(prompt, bot, state, msg).await?;
-        }
-        Command::Chat(content) => {
-            complete_chat(content, bot, client, state, msg).await?;
-        }
-        Command::View => {
-            view_histories(bot, state, msg).await?;
-        }
-        Command::Clear => {
-            clear_history(bot, state, msg).await?;
-        }
+    if !check_allowed(&bot, &allowlist, &msg).await? {
+        return Ok(());
     }
-    Ok(())
+
+    increment_counter!("chatgpt_bot_commands_total", "command" => command_name(&msg));
+    let result = run_command(bot.clone(), client, state.clone(), msg.clone(), cmd).await;
+    reply_on_error(&bot, &state, &msg, result).await
 }
 
-#[derive(BotCommands, Clone, Debug)]
-#[command(
-    rename_rule = "lowercase",
-    description = "These commands are supported:"
-)]
-enum Command {
-    #[command(description = "display this text.")]
-    Help,
-    #[command(description = "set prompt text.")]
-    Prompt(String),
-    #[command(description = "chat with gpt.")]
-    Chat(String),
-    #[command(description = "view chat histories.")]
-    View,
-    #[command(description = "clear history chats.")]
-    Clear,
+/// Name of the command in `msg`, without the leading slash and bot username.
+fn command_name(msg: &Message) -> String {
+    msg.text()
+        .and_then(|text| text.split_whitespace().next())
+        .and_then(|cmd| cmd.strip_prefix('/'))
+        .map(|cmd| cmd.split('@').next().unwrap_or(cmd).to_lowercase())
+        .unwrap_or_default()
+}
+
+/// Updates are processed sequentially per chat, except `/stop` which must not
+/// wait behind the reply it is meant to cancel.
+///
+/// Rejecting busy chats needs their updates to be processed concurrently
+/// instead, then only the replies are serialized by
+/// [`AppState::lock_replies`].
+fn distribution_key(update: &Update) -> Option<(ChatId, bool)> {
+    let chat = update.chat()?;
+    let stop = match &update.kind {
+        UpdateKind::Message(msg) => msg
+            .text()
+            .and_then(|text| text.split_whitespace().next())
+            .is_some_and(|cmd| cmd == "/stop" || cmd.starts_with("/stop@")),
+        _ => false,
+    };
+    Some((chat.id, stop))
 }
 
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::init();
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
 
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(err) => {
+            tracing::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
     let bot = Bot::from_env();
 
-    let client = Client::new();
-    let state = Arc::new(Mutex::new(ChatHistories::new()));
+    if let Some(port) = config.metrics_port {
+        match PrometheusBuilder::new()
+            .with_http_listener(([0, 0, 0, 0], port))
+            .install()
+        {
+            Ok(()) => tracing::info!("Serving metrics on port {}", port),
+            Err(err) => tracing::error!("Failed to start the metrics endpoint: {}", err),
+        }
+    }
+
+    let client = Client::with_config(config.api.clone());
+    check_api(&client).await;
+    let allowlist = Arc::new(AllowedChats::new(config.allowed_chats.clone()));
+    let state = Arc::new(AppState::new(config));
+    let saver = state.spawn_saver();
+    tokio::spawn(expire_conversations(bot.clone(), state.clone()));
+
+    let messages = Update::filter_message()
+        .branch(
+            dptree::entry()
+                .filter_command::<Command>()
+                .endpoint(handle_command),
+        )
+        .branch(dptree::filter(|msg: Message| msg.voice().is_some()).endpoint(handle_voice))
+        .branch(
+            dptree::filter_map(|msg: Message, me: Me| photo_input(&msg, &me))
+                .endpoint(handle_photo),
+        )
+        .branch(
+            dptree::filter(|msg: Message| {
+                msg.document().is_some()
+                    && msg
+                        .caption()
+                        .is_some_and(|caption| caption.trim_start().starts_with("/load"))
+            })
+            .endpoint(handle_document),
+        )
+        .branch(
+            dptree::filter_map(|msg: Message, me: Me| chat_input(&msg, &me)).endpoint(handle_text),
+        );
+    let edits = Update::filter_edited_message().branch(
+        dptree::filter_map(|msg: Message, me: Me| edited_input(&msg, &me)).endpoint(handle_edit),
+    );
+    let handler = dptree::entry()
+        .branch(messages)
+        .branch(edits)
+        .branch(Update::filter_callback_query().endpoint(handle_callback));
+
+    let mut dispatcher = Dispatcher::builder(bot.clone(), handler)
+        .dependencies(dptree::deps![client, state.clone(), allowlist])
+        .distribution_function(match state.config.busy_policy {
+            BusyPolicy::Wait => distribution_key,
+            BusyPolicy::Reject => |_| None,
+        })
+        .error_handler(LoggingErrorHandler::with_custom_text(
+            "An error has occurred in the dispatcher",
+        ))
+        .build();
+    tokio::spawn(shutdown_on_ctrlc(
+        dispatcher.shutdown_token(),
+        state.clone(),
+    ));
+    match state.config.webhook {
+        Some(ref webhook) => {
+            let address = ([0, 0, 0, 0], webhook.port).into();
+            // Sets the webhook, which is deleted again on shutdown.
+            let options = webhooks::Options::new(address, webhook.url.clone());
+            let listener = match webhooks::axum(bot, options).await {
+                Ok(listener) => listener,
+                Err(err) => {
+                    tracing::error!("Failed to set the webhook {}: {}", webhook.url, err);
+                    std::process::exit(1);
+                }
+            };
+            tracing::info!(
+                "Receiving updates at {} on port {}",
+                webhook.url,
+                webhook.port
+            );
+            dispatcher
+                .dispatch_with_listener(
+                    listener,
+                    LoggingErrorHandler::with_custom_text("An error from the webhook"),
+                )
+                .await;
+        }
+        None => dispatcher.dispatch().await,
+    }
+
+    state.shutdown.cancel();
+    if let Some(saver) = saver {
+        if let Err(err) = saver.await {
+            tracing::error!("History saver task failed: {}", err);
+        }
+    }
+    state.save_on_exit().await;
+}
 
-    let handler = Update::filter_message().branch(
-        dptree::entry()
-            .filter_command::<Command>()
-            .endpoint(handle_command),
+/// On ^C, stops the in-flight replies, which finalize what they have received
+/// so far, and shuts down the dispatcher once its handlers are done.
+///
+/// Exits after [`SHUTDOWN_TIMEOUT`] if a handler doesn't get there in time.
+async fn shutdown_on_ctrlc(token: ShutdownToken, state: State) {
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        tracing::error!("Failed to listen for ^C: {}", err);
+        return;
+    }
+    tracing::info!(
+        "^C received, stopping {} replies and shutting down...",
+        state.streams.len()
     );
+    state.shutdown.cancel();
 
-    Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![client, state])
-        .enable_ctrlc_handler()
-        .build()
-        .dispatch()
-        .await;
+    let Ok(done) = token.shutdown() else {
+        return;
+    };
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, done).await.is_err() {
+        tracing::warn!(
+            "Handlers didn't finish within {:?}, exiting anyway",
+            SHUTDOWN_TIMEOUT
+        );
+        state.save_on_exit().await;
+        std::process::exit(1);
+    }
 }
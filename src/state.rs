@@ -0,0 +1,1531 @@
+//! Chat histories, their settings and the state shared by all handlers.
+
+use async_openai::error::OpenAIError;
+use async_openai::types::Role;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{fs, io};
+use teloxide::prelude::*;
+use teloxide::types::{Chat, MessageId};
+use tokio::sync::{mpsc, OwnedMutexGuard, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::completion::{
+    default_tools, max_reply_tokens, ActiveStream, Format, Tools, RESPONSE_TOKEN_RESERVE,
+    SUMMARY_PREFIX,
+};
+use crate::config::{ApiConfig, BusyPolicy, Client, Config};
+use crate::store::{HistoryStore, InMemoryStore};
+
+pub(crate) type ChatMessages = Vec<ChatMessage>;
+pub(crate) type State = Arc<AppState>;
+
+pub(crate) const MODEL: &str = "gpt-3.5-turbo";
+pub(crate) const DEFAULT_CONVERSATION: &str = "default";
+pub(crate) const CONVERSATION_NAME_LIMIT: usize = 32;
+/// How long the models listed by the API are reused by `/models`.
+const MODELS_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+/// Maximum number of bot replies remembered for branching in groups.
+const THREAD_LIMIT: usize = 1024;
+/// How often idle conversations are looked for.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+/// How long an API key that hit its rate limit or quota is skipped.
+const KEY_REST: Duration = Duration::from_secs(60);
+
+/// Key of a chat history: the chat, and the user when histories are kept per
+/// user in groups.
+///
+/// Serialized as `chat` or `chat:user`, so that it can key a JSON object.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct ChatKey {
+    pub(crate) chat: ChatId,
+    pub(crate) user: Option<UserId>,
+}
+
+impl std::fmt::Display for ChatKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.user {
+            Some(user) => write!(f, "{}:{}", self.chat, user),
+            None => write!(f, "{}", self.chat),
+        }
+    }
+}
+
+impl FromStr for ChatKey {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (chat, user) = match s.split_once(':') {
+            Some((chat, user)) => (chat, Some(UserId(user.parse()?))),
+            None => (s, None),
+        };
+        Ok(Self {
+            chat: ChatId(chat.parse()?),
+            user,
+        })
+    }
+}
+
+impl Serialize for ChatKey {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ChatKey {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let key = String::deserialize(deserializer)?;
+        key.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A message of a conversation as stored in the chat history.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ChatMessage {
+    pub(crate) role: Role,
+    pub(crate) content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) name: Option<String>,
+    /// Images sent with a user message, as data URLs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) images: Vec<String>,
+    /// When the message was added, unknown for histories from before
+    /// timestamps were stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) created_at: Option<DateTime<Utc>>,
+    /// The Telegram message a user message was sent as, to pick up edits to
+    /// it. Not kept across restarts.
+    #[serde(skip)]
+    pub(crate) message_id: Option<MessageId>,
+}
+
+impl ChatMessage {
+    pub(crate) fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            name: None,
+            images: Vec::new(),
+            created_at: Some(Utc::now()),
+            message_id: None,
+        }
+    }
+
+    /// Whether the messages say the same, no matter when they were sent.
+    fn same_as(&self, other: &Self) -> bool {
+        self.role == other.role
+            && self.content == other.content
+            && self.name == other.name
+            && self.images == other.images
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ChatState {
+    /// Messages of the active conversation.
+    pub(crate) messages: ChatMessages,
+    #[serde(default = "default_conversation")]
+    pub(crate) conversation: String,
+    /// Inactive conversations by name.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) conversations: HashMap<String, ChatMessages>,
+    #[serde(flatten)]
+    pub(crate) settings: ChatSettings,
+    /// Tokens used per model over the lifetime of the chat, not reset by
+    /// clearing the history.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) usage: HashMap<String, TokenUsage>,
+    /// Number of messages dropped from the active conversation by the
+    /// history cap.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub(crate) trimmed: usize,
+    /// Ids of the messages of the latest reply continuing the history.
+    #[serde(skip)]
+    pub(crate) last_reply: Vec<MessageId>,
+    /// When the last user message was added to the history.
+    #[serde(skip)]
+    pub(crate) last_user_at: Option<Instant>,
+    /// The backend configuration the latest reply was generated with, as
+    /// reported by the API.
+    #[serde(skip)]
+    pub(crate) fingerprint: Option<String>,
+    /// Whether the user was told that the active conversation is close to
+    /// the token budget.
+    #[serde(skip)]
+    pub(crate) budget_warned: bool,
+    /// Replies shown for the user to pick the one added to the history.
+    #[serde(skip)]
+    pub(crate) variants: Option<PendingVariants>,
+}
+
+/// Replies to the user message `reply_to`, shown as numbered options in
+/// `message_id`.
+#[derive(Clone, Debug)]
+pub(crate) struct PendingVariants {
+    pub(crate) message_id: MessageId,
+    pub(crate) reply_to: Option<MessageId>,
+    pub(crate) choices: Vec<String>,
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct TokenUsage {
+    pub(crate) prompt_tokens: u64,
+    pub(crate) completion_tokens: u64,
+}
+
+/// Per-chat overrides, `None` means the default is used.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ChatSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) model: Option<String>,
+    #[serde(default)]
+    pub(crate) format: Format,
+    /// Whether replies are requested as JSON objects.
+    #[serde(default)]
+    pub(crate) json: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) presence_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) frequency_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_tokens: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) lang: Option<Lang>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) streaming: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reply_threading: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) progress: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) seed: Option<i64>,
+    /// Number of replies requested at once for the user to pick from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) variants: Option<u8>,
+    /// Sequences the model stops generating at.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) stop: Vec<String>,
+    /// System prompt new conversations of the chat start with, instead of the
+    /// default one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) persona: Option<String>,
+}
+
+impl ChatSettings {
+    pub(crate) fn model(&self) -> &str {
+        self.model.as_deref().unwrap_or(MODEL)
+    }
+}
+
+/// Language of the bot's own messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Lang {
+    #[default]
+    En,
+    Zh,
+}
+
+/// Messages of the bot looked up in the catalog of a [`Lang`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Text {
+    PromptSet,
+    /// Sent instead of [`Text::PromptSet`] for a prompt taking up much of the
+    /// model's context.
+    PromptSetLong,
+    PromptUsage,
+    /// Followed by the prompt length limit.
+    PromptTooLong,
+    HistoryCleared,
+    EmptyHistory,
+    /// Followed by the number of messages removed by the history cap.
+    TrimmedNote,
+    BudgetWarning,
+    LangCurrent,
+    LangSet,
+    LangReset,
+    UnknownLang,
+}
+
+impl Lang {
+    pub(crate) const ALL: [Self; 2] = [Self::En, Self::Zh];
+
+    /// Parses a language code such as `en` or Telegram's `zh-hans`.
+    pub(crate) fn parse(code: &str) -> Option<Self> {
+        let code = code.trim().to_lowercase();
+        let primary = code.split(['-', '_']).next().unwrap_or_default();
+        Self::ALL.into_iter().find(|lang| lang.code() == primary)
+    }
+
+    pub(crate) fn code(self) -> &'static str {
+        match self {
+            Self::En => "en",
+            Self::Zh => "zh",
+        }
+    }
+
+    pub(crate) fn text(self, text: Text) -> &'static str {
+        match (self, text) {
+            (Self::En, Text::PromptSet) => "Prompt set.",
+            (Self::En, Text::PromptSetLong) => {
+                "Prompt set, but it takes up a large part of the model's context, \
+                leaving less room for the conversation."
+            }
+            (Self::En, Text::PromptUsage) => {
+                "Usage: /prompt <system prompt>, which also clears the chat history."
+            }
+            (Self::En, Text::PromptTooLong) => "The prompt is too long, the limit in characters is",
+            (Self::En, Text::HistoryCleared) => "Chat histories cleared.",
+            (Self::En, Text::EmptyHistory) => "Empty chat history.",
+            (Self::En, Text::TrimmedNote) => "Older messages removed by the history cap:",
+            (Self::En, Text::BudgetWarning) => {
+                "This conversation is close to the context limit, so older messages will soon \
+                 be left out. Use /compact to summarize them or /clear to start over."
+            }
+            (Self::En, Text::LangCurrent) => "Current language:",
+            (Self::En, Text::LangSet) => "Language set to",
+            (Self::En, Text::LangReset) => "Language reset to your Telegram language.",
+            (Self::En, Text::UnknownLang) => "Unknown language, available languages:",
+            (Self::Zh, Text::PromptSet) => "提示词已设置。",
+            (Self::Zh, Text::PromptSetLong) => {
+                "提示词已设置，但它占用了模型上下文的很大一部分，留给对话的空间更少。"
+            }
+            (Self::Zh, Text::PromptUsage) => "用法：/prompt <系统提示词>，同时会清除聊天记录。",
+            (Self::Zh, Text::PromptTooLong) => "提示词过长，字符数上限为",
+            (Self::Zh, Text::HistoryCleared) => "聊天记录已清除。",
+            (Self::Zh, Text::EmptyHistory) => "聊天记录为空。",
+            (Self::Zh, Text::TrimmedNote) => "因历史记录上限被移除的旧消息数：",
+            (Self::Zh, Text::BudgetWarning) => {
+                "当前对话即将达到上下文上限，较早的消息很快会被忽略。\
+                 可以使用 /compact 总结它们，或使用 /clear 重新开始。"
+            }
+            (Self::Zh, Text::LangCurrent) => "当前语言：",
+            (Self::Zh, Text::LangSet) => "语言已设置为",
+            (Self::Zh, Text::LangReset) => "语言已重置为你的 Telegram 语言。",
+            (Self::Zh, Text::UnknownLang) => "未知语言，可用语言：",
+        }
+    }
+}
+
+pub(crate) fn default_conversation() -> String {
+    DEFAULT_CONVERSATION.to_owned()
+}
+
+impl Default for ChatState {
+    fn default() -> Self {
+        Self {
+            messages: ChatMessages::new(),
+            conversation: default_conversation(),
+            conversations: HashMap::new(),
+            settings: ChatSettings::default(),
+            usage: HashMap::new(),
+            trimmed: 0,
+            last_reply: Vec::new(),
+            last_user_at: None,
+            fingerprint: None,
+            budget_warned: false,
+            variants: None,
+        }
+    }
+}
+
+impl ChatState {
+    /// Takes variant `index` of the variants shown in `message_id`, to be
+    /// added to the active conversation `messages`, unless they are outdated.
+    ///
+    /// Variants are outdated once another is picked or the message they
+    /// reply to is no longer the last one.
+    pub(crate) fn pick_variant(
+        &mut self,
+        message_id: MessageId,
+        index: usize,
+        messages: &[ChatMessage],
+    ) -> Option<String> {
+        let pending = self
+            .variants
+            .take_if(|pending| pending.message_id == message_id)?;
+        let latest = messages.last().is_some_and(|message| {
+            message.role == Role::User && message.message_id == pending.reply_to
+        });
+        let choice = pending.choices.into_iter().nth(index).filter(|_| latest)?;
+        self.last_reply = vec![message_id];
+        Some(choice)
+    }
+
+    /// Whether `message` repeats the last user message of `messages`, added
+    /// less than `window` ago.
+    pub(crate) fn is_duplicate(
+        &self,
+        messages: &[ChatMessage],
+        message: &ChatMessage,
+        window: Duration,
+    ) -> bool {
+        self.last_user_at.is_some_and(|at| at.elapsed() < window)
+            && messages
+                .iter()
+                .rev()
+                .find(|message| message.role == Role::User)
+                .is_some_and(|last| last.same_as(message))
+    }
+
+    pub(crate) fn has_conversation(&self, name: &str) -> bool {
+        self.conversation == name || self.conversations.contains_key(name)
+    }
+
+    /// Makes `name` the active conversation, creating it with `initial` if it
+    /// doesn't exist, and keeps `active`, the messages of the previous one.
+    ///
+    /// Returns the messages of `name`, which the active conversation is
+    /// replaced with.
+    pub(crate) fn switch_conversation(
+        &mut self,
+        name: &str,
+        initial: ChatMessages,
+        active: ChatMessages,
+    ) -> ChatMessages {
+        if self.conversation == name {
+            return active;
+        }
+        let messages = self.conversations.remove(name).unwrap_or(initial);
+        self.reset_tracking();
+        let previous_name = std::mem::replace(&mut self.conversation, name.to_owned());
+        self.conversations.insert(previous_name, active);
+        messages
+    }
+
+    /// Forgets what was tracked about the messages of the active
+    /// conversation, once they are replaced.
+    pub(crate) fn reset_tracking(&mut self) {
+        self.trimmed = 0;
+        self.budget_warned = false;
+        self.last_reply.clear();
+    }
+
+    /// The first free name of the form `{conversation}-{n}` for a copy of the
+    /// active conversation.
+    pub(crate) fn fork_name(&self) -> String {
+        (1..)
+            .map(|n| {
+                let suffix = format!("-{}", n);
+                let len = CONVERSATION_NAME_LIMIT.saturating_sub(suffix.len());
+                let base: String = self.conversation.chars().take(len).collect();
+                base + &suffix
+            })
+            .find(|name| !self.has_conversation(name))
+            .unwrap_or_default()
+    }
+
+    /// Removes the inactive conversations without activity since `cutoff`,
+    /// returning how many were removed.
+    fn expire(&mut self, cutoff: DateTime<Utc>) -> usize {
+        let before = self.conversations.len();
+        self.conversations
+            .retain(|_, messages| !idle_since(messages, cutoff));
+        before - self.conversations.len()
+    }
+}
+
+/// The system prompt of a conversation, i.e. its leading system message
+/// unless that is a summary.
+pub(crate) fn system_prompt(messages: &[ChatMessage]) -> Option<&str> {
+    messages
+        .first()
+        .filter(|m| matches!(m.role, Role::System) && !m.content.starts_with(SUMMARY_PREFIX))
+        .map(|m| m.content.as_str())
+}
+
+/// Replaces the system prompt of `messages`, or inserts one, keeping the rest
+/// of the conversation.
+pub(crate) fn set_system_prompt(messages: &mut ChatMessages, prompt: String) {
+    let message = ChatMessage::new(Role::System, prompt);
+    if system_prompt(messages).is_some() {
+        messages[0] = message;
+    } else {
+        messages.insert(0, message);
+    }
+}
+
+/// Drops the oldest non-system messages so that at most `max` of them
+/// remain, returning the number of dropped messages.
+pub(crate) fn cap_messages(messages: &mut ChatMessages, max: usize) -> usize {
+    let excess = messages
+        .iter()
+        .filter(|message| !matches!(message.role, Role::System))
+        .count()
+        .saturating_sub(max);
+    let mut remaining = excess;
+    messages.retain(|message| {
+        if remaining == 0 || matches!(message.role, Role::System) {
+            return true;
+        }
+        remaining -= 1;
+        false
+    });
+    excess
+}
+
+/// Removes the last exchange of `messages`, i.e. the trailing assistant
+/// reply, if any, and the user message before it. System messages are never
+/// removed.
+pub(crate) fn undo_exchange(messages: &mut ChatMessages) -> bool {
+    let assistant = messages.pop_if(|message| matches!(message.role, Role::Assistant));
+    let user = messages.pop_if(|message| matches!(message.role, Role::User));
+    assistant.is_some() || user.is_some()
+}
+
+/// Whether `messages` have an exchange and the last message is older than
+/// `cutoff`. Messages without timestamps never expire.
+fn idle_since(messages: &[ChatMessage], cutoff: DateTime<Utc>) -> bool {
+    messages.iter().any(|message| message.role != Role::System)
+        && messages
+            .last()
+            .and_then(|message| message.created_at)
+            .is_some_and(|created_at| created_at < cutoff)
+}
+
+/// Sliding-window limit on completion requests per chat.
+struct RateLimiter {
+    max_requests: usize,
+    window: Duration,
+    requests: DashMap<ChatId, VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    fn new(max_requests: usize, window: Duration) -> Self {
+        Self {
+            max_requests,
+            window,
+            requests: DashMap::new(),
+        }
+    }
+
+    /// Records a request made at `now`, or returns how long to wait until the
+    /// next request is allowed.
+    fn check(&self, chat_id: ChatId, now: Instant) -> Result<(), Duration> {
+        let mut requests = self.requests.entry(chat_id).or_default();
+        while requests
+            .front()
+            .is_some_and(|&time| now.saturating_duration_since(time) >= self.window)
+        {
+            requests.pop_front();
+        }
+
+        if requests.len() >= self.max_requests {
+            let oldest = requests.front().copied().unwrap_or(now);
+            return Err((oldest + self.window).saturating_duration_since(now));
+        }
+        requests.push_back(now);
+        Ok(())
+    }
+}
+
+/// Pauses requests to OpenAI after `threshold` consecutive failures, shared by
+/// all chats.
+///
+/// After the cooldown a single trial request is let through, which closes the
+/// breaker again if it succeeds.
+struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: parking_lot::Mutex<BreakerState>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BreakerState {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A trial request is in flight, which is given up on at `until`.
+    HalfOpen {
+        until: Instant,
+    },
+}
+
+impl CircuitBreaker {
+    fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: parking_lot::Mutex::new(BreakerState::Closed { failures: 0 }),
+        }
+    }
+
+    fn allow(&self, now: Instant) -> bool {
+        let mut state = self.state.lock();
+        match *state {
+            BreakerState::Closed { .. } => true,
+            // A trial that never finished, e.g. a cancelled one, doesn't keep
+            // the breaker half-open forever.
+            BreakerState::Open { until } | BreakerState::HalfOpen { until } if now >= until => {
+                tracing::info!("Circuit breaker half-open, trying a request");
+                *state = BreakerState::HalfOpen {
+                    until: now + self.cooldown,
+                };
+                true
+            }
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => false,
+        }
+    }
+
+    fn record_success(&self) {
+        let mut state = self.state.lock();
+        if !matches!(*state, BreakerState::Closed { .. }) {
+            tracing::info!("Circuit breaker closed, OpenAI recovered");
+        }
+        *state = BreakerState::Closed { failures: 0 };
+    }
+
+    fn record_failure(&self, now: Instant) {
+        let mut state = self.state.lock();
+        let failures = match *state {
+            BreakerState::Closed { failures } => failures + 1,
+            BreakerState::HalfOpen { .. } => self.threshold,
+            BreakerState::Open { .. } => return,
+        };
+        if failures < self.threshold {
+            *state = BreakerState::Closed { failures };
+            return;
+        }
+        tracing::warn!(
+            "Circuit breaker open after {} failures, pausing requests for {:?}",
+            failures,
+            self.cooldown
+        );
+        *state = BreakerState::Open {
+            until: now + self.cooldown,
+        };
+    }
+}
+
+/// Limits the replies generated at the same time across all chats, handing
+/// out free slots in the order they were asked for.
+pub(crate) struct ReplySlots {
+    semaphore: Arc<Semaphore>,
+    /// Tickets handed out to replies waiting for a slot.
+    issued: AtomicU64,
+    /// Tickets of replies that got a slot or stopped waiting.
+    done: Arc<AtomicU64>,
+}
+
+/// A place in the queue for a reply slot.
+pub(crate) struct QueueTicket {
+    number: u64,
+    done: Arc<AtomicU64>,
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl ReplySlots {
+    fn new(limit: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            issued: AtomicU64::new(0),
+            done: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// A slot if one is free right away.
+    pub(crate) fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        self.semaphore.clone().try_acquire_owned().ok()
+    }
+
+    /// Waits for a slot, in the order of the calls.
+    pub(crate) async fn acquire(&self) -> OwnedSemaphorePermit {
+        // The semaphore is never closed.
+        self.semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("reply slots closed")
+    }
+
+    /// A place in the queue, which is left again when the ticket is dropped.
+    pub(crate) fn enqueue(&self) -> QueueTicket {
+        QueueTicket {
+            number: self.issued.fetch_add(1, Ordering::Relaxed),
+            done: self.done.clone(),
+        }
+    }
+
+    /// The 1-based place of `ticket` in the queue.
+    pub(crate) fn position(&self, ticket: &QueueTicket) -> u64 {
+        ticket
+            .number
+            .saturating_sub(self.done.load(Ordering::Relaxed))
+            + 1
+    }
+}
+
+/// Clients with different API keys that replies take turns with, spreading
+/// them across the rate limits of the keys.
+///
+/// A key that hit its rate limit or ran out of quota is skipped for
+/// [`KEY_REST`].
+pub(crate) struct KeyPool {
+    clients: Vec<Client>,
+    /// Until when each key is skipped.
+    resting: parking_lot::Mutex<Vec<Option<Instant>>>,
+    next: AtomicUsize,
+}
+
+impl KeyPool {
+    fn new(api: &ApiConfig, keys: &[String]) -> Self {
+        Self {
+            clients: keys
+                .iter()
+                .map(|key| Client::with_config(api.with_api_key(key)))
+                .collect(),
+            resting: parking_lot::Mutex::new(vec![None; keys.len()]),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// The index and client of the next key that isn't resting, or of the one
+    /// that is done resting first if all are.
+    pub(crate) fn next(&self, now: Instant) -> (usize, &Client) {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let resting = self.resting.lock();
+        let key = (0..self.len())
+            .map(|offset| (start + offset) % self.len())
+            .min_by_key(|&key| resting[key].filter(|&until| until > now))
+            .unwrap_or_default();
+        (key, &self.clients[key])
+    }
+
+    pub(crate) fn rest(&self, key: usize, now: Instant) {
+        tracing::warn!("Skipping API key {} for {:?}", key + 1, KEY_REST);
+        self.resting.lock()[key] = Some(now + KEY_REST);
+    }
+}
+
+pub(crate) struct AppState {
+    pub(crate) config: Config,
+    store: Arc<dyn HistoryStore>,
+    pub(crate) persistence: Option<Persistence>,
+    rate_limiter: Option<RateLimiter>,
+    breaker: Option<CircuitBreaker>,
+    pub(crate) reply_slots: Option<ReplySlots>,
+    /// Replies take turns with the keys, if more than one is configured.
+    pub(crate) keys: Option<KeyPool>,
+    /// Cancellation tokens of the in-flight replies, keyed by chat.
+    pub(crate) streams: DashMap<ChatKey, (u64, CancellationToken)>,
+    /// Cancelled on shutdown, which stops all in-flight replies.
+    pub(crate) shutdown: CancellationToken,
+    pub(crate) threads: Threads,
+    next_stream_id: AtomicU64,
+    pub(crate) tools: Tools,
+    /// Unconfirmed `/reset_all` requests and whether they delete the history
+    /// file.
+    pub(crate) pending_resets: DashMap<ChatId, (Instant, bool)>,
+    pub(crate) started: Instant,
+    /// Held while a reply is generated, so each chat gets one at a time.
+    reply_locks: DashMap<ChatKey, Arc<tokio::sync::Mutex<()>>>,
+    /// Completions counted against `demo_limit` since the bot started.
+    pub(crate) demo_served: AtomicU64,
+    /// Chat models listed by the API, and when they were listed.
+    models: parking_lot::Mutex<Option<(Instant, Vec<String>)>>,
+}
+
+impl AppState {
+    pub(crate) fn new(config: Config) -> Self {
+        let persistence = config
+            .history_path
+            .clone()
+            .map(|path| Persistence::new(path, config.save_debounce));
+        let histories = persistence
+            .as_ref()
+            .map(Persistence::load)
+            .unwrap_or_default();
+        Self::with_store(
+            config,
+            persistence,
+            Arc::new(InMemoryStore::from(histories)),
+        )
+    }
+
+    /// State keeping the chats in `store`, saved to `persistence` if set.
+    pub(crate) fn with_store(
+        config: Config,
+        persistence: Option<Persistence>,
+        store: Arc<dyn HistoryStore>,
+    ) -> Self {
+        Self {
+            store,
+            persistence,
+            rate_limiter: config
+                .rate_limit
+                .map(|max_requests| RateLimiter::new(max_requests, RATE_LIMIT_WINDOW)),
+            breaker: config
+                .breaker_threshold
+                .map(|threshold| CircuitBreaker::new(threshold, config.breaker_cooldown)),
+            reply_slots: config.max_concurrent_replies.map(ReplySlots::new),
+            keys: (config.api_keys.len() > 1).then(|| KeyPool::new(&config.api, &config.api_keys)),
+            streams: DashMap::new(),
+            shutdown: CancellationToken::new(),
+            threads: Threads::default(),
+            next_stream_id: AtomicU64::new(0),
+            tools: default_tools(),
+            pending_resets: DashMap::new(),
+            started: Instant::now(),
+            reply_locks: DashMap::new(),
+            demo_served: AtomicU64::new(0),
+            models: parking_lot::Mutex::new(None),
+            config,
+        }
+    }
+
+    /// Registers a new in-flight reply for `key` that `/stop` can cancel.
+    pub(crate) fn start_stream(self: &Arc<Self>, key: ChatKey) -> ActiveStream {
+        let id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+        let token = self.shutdown.child_token();
+        self.streams.insert(key, (id, token.clone()));
+        ActiveStream {
+            state: self.clone(),
+            key,
+            id,
+            token,
+        }
+    }
+
+    /// Cancels the in-flight reply of `key`, returning whether there was one.
+    pub(crate) fn stop_stream(&self, key: ChatKey) -> bool {
+        self.streams
+            .get(&key)
+            .map(|entry| entry.1.cancel())
+            .is_some()
+    }
+
+    /// Key of the history `msg` belongs to.
+    ///
+    /// Anonymous admins and channel posts have no real sender, so they share
+    /// the chat history.
+    pub(crate) fn key(&self, msg: &Message) -> ChatKey {
+        let user = match msg.from() {
+            Some(user)
+                if self.config.per_user_history
+                    && !msg.chat.is_private()
+                    && msg.sender_chat().is_none() =>
+            {
+                Some(user.id)
+            }
+            _ => None,
+        };
+        ChatKey {
+            chat: msg.chat.id,
+            user,
+        }
+    }
+
+    /// The thread `msg` continues if it replies to a bot reply other than the
+    /// latest one of the history.
+    pub(crate) async fn thread_of_reply(&self, msg: &Message) -> Option<ChatMessages> {
+        let reply = msg.reply_to_message()?;
+        let latest = self
+            .store
+            .load(self.key(msg))
+            .await
+            .is_some_and(|chat| chat.last_reply.contains(&reply.id));
+        if latest {
+            return None;
+        }
+        self.threads.get(msg.chat.id, reply.id)
+    }
+
+    /// Messages a new conversation of a chat with `settings` starts with.
+    pub(crate) fn initial_messages(&self, settings: &ChatSettings) -> ChatMessages {
+        settings
+            .persona
+            .as_ref()
+            .or(self.config.default_prompt.as_ref())
+            .map(|prompt| ChatMessage::new(Role::System, prompt.as_str()))
+            .into_iter()
+            .collect()
+    }
+
+    /// Where handlers read and write the chats.
+    pub(crate) fn store(&self) -> &dyn HistoryStore {
+        &*self.store
+    }
+
+    /// Changes the chat of `key` with `f`, creating it with the initial
+    /// messages first if it doesn't exist yet.
+    ///
+    /// `f` must leave the messages of the active conversation alone, they
+    /// are only changed through [`HistoryStore`].
+    pub(crate) async fn update_chat<R: Send>(
+        &self,
+        key: ChatKey,
+        f: impl FnOnce(&mut ChatState) -> R + Send,
+    ) -> R {
+        let new = ChatState {
+            messages: self.initial_messages(&ChatSettings::default()),
+            ..Default::default()
+        };
+        let mut result = None;
+        self.store
+            .update(key, new, Box::new(|chat| result = Some(f(chat))))
+            .await;
+        result.expect("the store runs every update")
+    }
+
+    /// The settings of `key`, the defaults if it has none.
+    pub(crate) async fn settings(&self, key: ChatKey) -> ChatSettings {
+        self.store.settings(key).await
+    }
+
+    /// Adds variant `index` of the variants shown in `message_id` to the
+    /// active conversation of `key`, see [`ChatState::pick_variant`].
+    pub(crate) async fn pick_variant(
+        &self,
+        key: ChatKey,
+        message_id: MessageId,
+        index: usize,
+    ) -> Option<String> {
+        let messages = self.store.get(key).await;
+        let choice = self
+            .update_chat(key, |chat| chat.pick_variant(message_id, index, &messages))
+            .await?;
+        let reply = ChatMessage::new(Role::Assistant, choice.as_str());
+        self.store.append(key, reply).await;
+        self.mark_dirty();
+        Some(choice)
+    }
+
+    /// Drops the oldest non-system messages of the active conversation of
+    /// `key` over `max`, see [`cap_messages`], returning how many it dropped.
+    pub(crate) async fn cap_history(&self, key: ChatKey, max: usize) -> usize {
+        let mut messages = self.store.get(key).await;
+        let dropped = cap_messages(&mut messages, max);
+        if dropped > 0 {
+            self.store.replace(key, messages).await;
+            self.update_chat(key, |chat| chat.trimmed += dropped).await;
+        }
+        dropped
+    }
+
+    /// The language of messages to `msg`: the one set with `/lang`, or the
+    /// sender's Telegram language.
+    pub(crate) async fn lang(&self, msg: &Message) -> Lang {
+        self.settings(self.key(msg))
+            .await
+            .lang
+            .or_else(|| {
+                msg.from()
+                    .and_then(|user| user.language_code.as_deref())
+                    .and_then(Lang::parse)
+            })
+            .unwrap_or_default()
+    }
+
+    /// Maximum number of prompt tokens to send to `model`, leaving room for
+    /// a reply of `max_tokens`.
+    pub(crate) fn token_budget(&self, model: &str, max_tokens: Option<u16>) -> usize {
+        let context_size = tiktoken_rs::model::get_context_size(model);
+        let reserve = max_tokens.map_or(RESPONSE_TOKEN_RESERVE, usize::from);
+        let budget = context_size.saturating_sub(reserve);
+        self.config
+            .token_budget
+            .map_or(budget, |limit| limit.min(budget))
+    }
+
+    pub(crate) fn streaming(&self, settings: &ChatSettings) -> bool {
+        settings.streaming.unwrap_or(self.config.streaming)
+    }
+
+    /// Counts a completion for `chat_id` against the demo limit, returning
+    /// whether it may be served. Admins are neither limited nor counted.
+    pub(crate) fn take_demo_completion(&self, chat_id: ChatId) -> bool {
+        let Some(limit) = self.config.demo_limit else {
+            return true;
+        };
+        self.config.admins.contains(&chat_id)
+            || self
+                .demo_served
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |served| {
+                    (served < limit).then_some(served + 1)
+                })
+                .is_ok()
+    }
+
+    /// The chat models the API lists, reusing the last list for
+    /// [`MODELS_CACHE_TTL`].
+    pub(crate) async fn list_models(&self, client: &Client) -> Result<Vec<String>, OpenAIError> {
+        if let Some((listed, ref models)) = *self.models.lock() {
+            if listed.elapsed() < MODELS_CACHE_TTL {
+                return Ok(models.clone());
+            }
+        }
+        let response = client.models().list().await?;
+        let mut models: Vec<String> = response
+            .data
+            .into_iter()
+            .map(|model| model.id)
+            .filter(|id| is_chat_model(id))
+            .collect();
+        models.sort();
+        *self.models.lock() = Some((Instant::now(), models.clone()));
+        Ok(models)
+    }
+
+    /// Maximum number of non-system messages kept per conversation of `chat`.
+    pub(crate) fn max_history(&self, chat: &Chat) -> Option<usize> {
+        let cap = match chat.is_private() {
+            true => self.config.max_history_private,
+            false => self.config.max_history_group,
+        };
+        cap.or(self.config.max_history)
+    }
+
+    pub(crate) fn progress(&self, settings: &ChatSettings) -> bool {
+        settings.progress.unwrap_or(self.config.progress)
+    }
+
+    pub(crate) fn reply_threading(&self, settings: &ChatSettings) -> bool {
+        settings
+            .reply_threading
+            .unwrap_or(self.config.reply_threading)
+    }
+
+    /// The message that messages answering `msg` reply to, none if reply
+    /// threading is off in its chat.
+    pub(crate) async fn reply_to(&self, msg: &Message) -> Option<MessageId> {
+        let threading = self
+            .settings(self.key(msg))
+            .await
+            .reply_threading
+            .unwrap_or(self.config.reply_threading);
+        threading.then_some(msg.id)
+    }
+
+    /// The reply length limit of a chat with `settings`, capped to what its
+    /// model can produce.
+    pub(crate) fn max_tokens(&self, settings: &ChatSettings) -> Option<u16> {
+        let limit = max_reply_tokens(settings.model());
+        settings
+            .max_tokens
+            .or(self.config.max_tokens)
+            .map(|max_tokens| max_tokens.min(limit))
+    }
+
+    /// Waits until the chat of `msg` can get a reply, or returns `None` if it
+    /// is busy and the busy policy is to reject.
+    pub(crate) async fn lock_replies(&self, msg: &Message) -> Option<OwnedMutexGuard<()>> {
+        let lock = self.reply_locks.entry(self.key(msg)).or_default().clone();
+        match self.config.busy_policy {
+            BusyPolicy::Wait => Some(lock.lock_owned().await),
+            BusyPolicy::Reject => lock.try_lock_owned().ok(),
+        }
+    }
+
+    /// Records a completion request for `chat_id`, or returns how long the chat
+    /// has to wait before making another one.
+    pub(crate) fn check_rate_limit(&self, chat_id: ChatId) -> Result<(), Duration> {
+        match self.rate_limiter {
+            Some(ref limiter) => limiter.check(chat_id, Instant::now()),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether a request to OpenAI may be made, rather than waiting for it to
+    /// recover from repeated failures.
+    pub(crate) fn allow_api_request(&self) -> bool {
+        self.breaker
+            .as_ref()
+            .is_none_or(|breaker| breaker.allow(Instant::now()))
+    }
+
+    /// Records whether OpenAI answered a request, rather than failing in a way
+    /// that suggests an outage.
+    pub(crate) fn record_api_result(&self, answered: bool) {
+        if let Some(ref breaker) = self.breaker {
+            match answered {
+                true => breaker.record_success(),
+                false => breaker.record_failure(Instant::now()),
+            }
+        }
+    }
+
+    /// Tells the saver task that the histories changed.
+    pub(crate) fn mark_dirty(&self) {
+        if let Some(ref persistence) = self.persistence {
+            // A full channel already has a save pending.
+            let _ = persistence.dirty.try_send(());
+        }
+    }
+
+    /// Starts the task saving the histories after they change, or `None` if
+    /// persistence is disabled.
+    pub(crate) fn spawn_saver(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        let dirty = self.persistence.as_ref()?.signals.lock().take()?;
+        Some(tokio::spawn(run_saver(self.clone(), dirty)))
+    }
+
+    /// Saves `chats` to the history file, if persistence is enabled.
+    fn save(&self, chats: &HashMap<ChatKey, ChatState>) {
+        let Some(ref persistence) = self.persistence else {
+            return;
+        };
+        if let Err(err) = persistence.save(chats) {
+            tracing::error!(
+                "Failed to save histories to {}: {}",
+                persistence.path.display(),
+                err
+            );
+        }
+    }
+
+    /// A copy of all histories, each locked only while it's copied.
+    pub(crate) async fn snapshot(&self) -> HashMap<ChatKey, ChatState> {
+        self.store.snapshot().await
+    }
+
+    /// Saves all histories before exiting and logs what was saved.
+    pub(crate) async fn save_on_exit(&self) {
+        if self.persistence.is_none() {
+            tracing::info!("Persistence is disabled, histories are not saved");
+            return;
+        }
+        let chats = self.snapshot().await;
+        self.save(&chats);
+        let conversations: usize = chats
+            .values()
+            .map(|chat| 1 + chat.conversations.len())
+            .sum();
+        tracing::info!(
+            "Saved {} conversations of {} chats",
+            conversations,
+            chats.len()
+        );
+    }
+}
+
+/// Whether the model `id` listed by the API does chat completions.
+fn is_chat_model(id: &str) -> bool {
+    id.starts_with("gpt-") && !id.contains("instruct")
+}
+
+/// Conversations up to the bot replies in groups, so that replying to an
+/// earlier reply branches off at that point. Only the latest replies are kept.
+#[derive(Default)]
+pub(crate) struct Threads {
+    messages: DashMap<(ChatId, MessageId), Arc<ChatMessages>>,
+    order: parking_lot::Mutex<VecDeque<(ChatId, MessageId)>>,
+}
+
+impl Threads {
+    /// Records `messages` as the thread ending at the reply made of `ids`.
+    pub(crate) fn record(&self, chat_id: ChatId, ids: &[MessageId], messages: ChatMessages) {
+        let messages = Arc::new(messages);
+        let mut order = self.order.lock();
+        for &id in ids {
+            self.messages.insert((chat_id, id), messages.clone());
+            order.push_back((chat_id, id));
+        }
+        while order.len() > THREAD_LIMIT {
+            if let Some(key) = order.pop_front() {
+                self.messages.remove(&key);
+            }
+        }
+    }
+
+    fn get(&self, chat_id: ChatId, id: MessageId) -> Option<ChatMessages> {
+        self.messages
+            .get(&(chat_id, id))
+            .map(|messages| messages.as_ref().clone())
+    }
+}
+
+pub(crate) struct Persistence {
+    pub(crate) path: PathBuf,
+    /// Minimum time between two saves.
+    debounce: Duration,
+    dirty: mpsc::Sender<()>,
+    /// Receiving end of `dirty`, until the saver task takes it.
+    signals: parking_lot::Mutex<Option<mpsc::Receiver<()>>>,
+    /// Held while writing, so the saver task and the save on exit don't
+    /// write at the same time.
+    writing: parking_lot::Mutex<()>,
+}
+
+impl Persistence {
+    fn new(path: PathBuf, debounce: Duration) -> Self {
+        let (dirty, signals) = mpsc::channel(1);
+        Self {
+            path,
+            debounce,
+            dirty,
+            signals: parking_lot::Mutex::new(Some(signals)),
+            writing: parking_lot::Mutex::new(()),
+        }
+    }
+
+    fn load(&self) -> HashMap<ChatKey, ChatState> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                tracing::info!("No history file at {}", self.path.display());
+                return HashMap::new();
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to read histories from {}, starting empty: {}",
+                    self.path.display(),
+                    err
+                );
+                return HashMap::new();
+            }
+        };
+
+        match serde_json::from_slice(&data) {
+            Ok(histories) => histories,
+            Err(err) => {
+                tracing::warn!(
+                    "Corrupt history file {}, starting empty: {}",
+                    self.path.display(),
+                    err
+                );
+                HashMap::new()
+            }
+        }
+    }
+
+    fn save(&self, histories: &HashMap<ChatKey, ChatState>) -> io::Result<()> {
+        let data = serde_json::to_vec(histories)?;
+        let _writing = self.writing.lock();
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+/// Periodically resets the conversations that have been idle for longer than
+/// the TTL, skipping chats with a reply in progress.
+pub(crate) async fn expire_conversations(bot: Bot, state: State) {
+    let Some(ttl) = state.config.conversation_ttl else {
+        return;
+    };
+    let Ok(ttl) = chrono::Duration::from_std(ttl) else {
+        tracing::warn!("Ignoring too long CONVERSATION_TTL_SECS");
+        return;
+    };
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = state.shutdown.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+
+        let cutoff = Utc::now() - ttl;
+        let keys = state.store.keys().await;
+        let mut expired = Vec::new();
+        for key in keys {
+            let replying = state
+                .reply_locks
+                .get(&key)
+                .is_some_and(|lock| lock.try_lock().is_err());
+            if replying || state.streams.contains_key(&key) {
+                continue;
+            }
+            let idle = idle_since(&state.store.get(key).await, cutoff);
+            if idle {
+                let initial = state.initial_messages(&state.settings(key).await);
+                state.store.replace(key, initial).await;
+            }
+            let count = state
+                .update_chat(key, |chat| {
+                    if idle {
+                        chat.reset_tracking();
+                    }
+                    chat.expire(cutoff) + usize::from(idle)
+                })
+                .await;
+            if count > 0 {
+                expired.push((key, count));
+            }
+        }
+        if expired.is_empty() {
+            continue;
+        }
+
+        tracing::info!(
+            "Expired {} conversations of {} chats",
+            expired.iter().map(|(_, count)| count).sum::<usize>(),
+            expired.len()
+        );
+        state.mark_dirty();
+        if !state.config.notify_expiry {
+            continue;
+        }
+        for (key, _) in expired {
+            if let Err(err) = bot
+                .send_message(
+                    key.chat,
+                    "The conversation was cleared after a period of inactivity.",
+                )
+                .await
+            {
+                tracing::warn!("Failed to notify expiry, user: {}: {}", key, err);
+            }
+        }
+    }
+}
+
+/// Saves the histories whenever they change, at most once per debounce
+/// interval so that bursts of changes end up in a single write.
+///
+/// Stops on shutdown, when [`AppState::save_on_exit`] does the final save.
+async fn run_saver(state: State, mut dirty: mpsc::Receiver<()>) {
+    let Some(debounce) = state.persistence.as_ref().map(|p| p.debounce) else {
+        return;
+    };
+    loop {
+        tokio::select! {
+            _ = state.shutdown.cancelled() => break,
+            signal = dirty.recv() => if signal.is_none() { break },
+        }
+        tokio::select! {
+            _ = state.shutdown.cancelled() => break,
+            _ = tokio::time::sleep(debounce) => {}
+        }
+        // The save below covers the changes signalled while waiting.
+        while dirty.try_recv().is_ok() {}
+
+        let chats = state.snapshot().await;
+        let state = state.clone();
+        if let Err(err) = tokio::task::spawn_blocking(move || state.save(&chats)).await {
+            tracing::error!("Failed to join history save task: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DUPLICATE_WINDOW;
+
+    fn message(role: Role, content: &str, minutes_ago: i64) -> ChatMessage {
+        ChatMessage {
+            created_at: Some(Utc::now() - chrono::Duration::minutes(minutes_ago)),
+            ..ChatMessage::new(role, content)
+        }
+    }
+
+    #[test]
+    fn chat_key_round_trips() {
+        for key in ["-100123", "-100123:42"] {
+            let parsed: ChatKey = key.parse().unwrap();
+            assert_eq!(parsed.to_string(), key);
+        }
+        assert_eq!(
+            "7:8".parse::<ChatKey>().unwrap(),
+            ChatKey {
+                chat: ChatId(7),
+                user: Some(UserId(8)),
+            }
+        );
+        assert!("7:x".parse::<ChatKey>().is_err());
+    }
+
+    #[test]
+    fn duplicate_ignores_timestamps() {
+        let chat = ChatState {
+            last_user_at: Some(Instant::now()),
+            ..Default::default()
+        };
+        let messages = [message(Role::User, "hi", 1)];
+        let hi = ChatMessage::new(Role::User, "hi");
+        assert!(chat.is_duplicate(&messages, &hi, DUPLICATE_WINDOW));
+        let hello = ChatMessage::new(Role::User, "hello");
+        assert!(!chat.is_duplicate(&messages, &hello, DUPLICATE_WINDOW));
+        assert!(!chat.is_duplicate(&messages, &hi, Duration::ZERO));
+    }
+
+    #[test]
+    fn fork_name_skips_taken_names() {
+        let mut chat = ChatState::default();
+        assert_eq!(chat.fork_name(), "default-1");
+        chat.conversations
+            .insert("default-1".to_owned(), Vec::new());
+        assert_eq!(chat.fork_name(), "default-2");
+
+        chat.conversation = "x".repeat(CONVERSATION_NAME_LIMIT);
+        let name = chat.fork_name();
+        assert_eq!(name.chars().count(), CONVERSATION_NAME_LIMIT);
+        assert!(name.ends_with("-1"));
+    }
+
+    #[test]
+    fn system_prompt_skips_summaries() {
+        let mut messages = vec![ChatMessage::new(
+            Role::System,
+            format!("{}earlier", SUMMARY_PREFIX),
+        )];
+        assert_eq!(system_prompt(&messages), None);
+
+        set_system_prompt(&mut messages, "Be brief.".to_owned());
+        assert_eq!(system_prompt(&messages), Some("Be brief."));
+        assert_eq!(messages.len(), 2);
+        set_system_prompt(&mut messages, "Be kind.".to_owned());
+        assert_eq!(system_prompt(&messages), Some("Be kind."));
+        assert_eq!(messages.len(), 2);
+    }
+
+    #[test]
+    fn cap_messages_keeps_system_messages() {
+        let mut messages = vec![ChatMessage::new(Role::System, "prompt")];
+        for content in ["1", "2", "3", "4"] {
+            messages.push(ChatMessage::new(Role::User, content));
+        }
+        assert_eq!(cap_messages(&mut messages, 2), 2);
+        let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["prompt", "3", "4"]);
+        assert_eq!(cap_messages(&mut messages, 2), 0);
+    }
+
+    #[test]
+    fn undo_removes_the_last_exchange() {
+        let mut messages = vec![
+            ChatMessage::new(Role::System, "prompt"),
+            ChatMessage::new(Role::User, "hi"),
+            ChatMessage::new(Role::Assistant, "hello"),
+        ];
+        assert!(undo_exchange(&mut messages));
+        assert_eq!(messages.len(), 1);
+        assert!(!undo_exchange(&mut messages));
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[test]
+    fn expire_resets_idle_conversations() {
+        let cutoff = Utc::now() - chrono::Duration::minutes(10);
+        assert!(idle_since(&[message(Role::User, "old", 20)], cutoff));
+        let mut chat = ChatState::default();
+        chat.conversations
+            .insert("recent".to_owned(), vec![message(Role::User, "new", 1)]);
+        chat.conversations
+            .insert("stale".to_owned(), vec![message(Role::User, "old", 30)]);
+        chat.conversations.insert(
+            "prompt only".to_owned(),
+            vec![message(Role::System, "prompt", 30)],
+        );
+
+        assert_eq!(chat.expire(cutoff), 1);
+        let mut names: Vec<&str> = chat.conversations.keys().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(names, ["prompt only", "recent"]);
+    }
+
+    #[test]
+    fn messages_without_timestamps_never_expire() {
+        let mut old = ChatMessage::new(Role::User, "hi");
+        old.created_at = None;
+        assert!(!idle_since(&[old], Utc::now()));
+    }
+
+    #[test]
+    fn rate_limiter_waits_for_the_oldest_request() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let chat = ChatId(1);
+        let start = Instant::now();
+        assert!(limiter.check(chat, start).is_ok());
+        assert!(limiter.check(chat, start + Duration::from_secs(10)).is_ok());
+        assert_eq!(
+            limiter.check(chat, start + Duration::from_secs(20)),
+            Err(Duration::from_secs(40))
+        );
+        assert!(limiter.check(ChatId(2), start).is_ok());
+        assert!(limiter.check(chat, start + Duration::from_secs(60)).is_ok());
+    }
+
+    #[test]
+    fn key_pool_skips_resting_keys() {
+        let api = ApiConfig::OpenAI(async_openai::config::OpenAIConfig::new());
+        let keys = KeyPool::new(&api, &["a".to_owned(), "b".to_owned(), "c".to_owned()]);
+        let now = Instant::now();
+        let order: Vec<usize> = (0..4).map(|_| keys.next(now).0).collect();
+        assert_eq!(order, [0, 1, 2, 0]);
+
+        keys.rest(2, now);
+        let order: Vec<usize> = (0..3).map(|_| keys.next(now).0).collect();
+        assert_eq!(order, [1, 0, 0]);
+        let later = now + KEY_REST;
+        let order: Vec<usize> = (0..3).map(|_| keys.next(later).0).collect();
+        assert_eq!(order, [1, 2, 0]);
+    }
+
+    #[test]
+    fn reply_slots_count_queue_positions() {
+        let slots = ReplySlots::new(1);
+        let slot = slots.try_acquire();
+        assert!(slot.is_some());
+        assert!(slots.try_acquire().is_none());
+
+        let first = slots.enqueue();
+        let second = slots.enqueue();
+        assert_eq!(slots.position(&first), 1);
+        assert_eq!(slots.position(&second), 2);
+        drop(first);
+        assert_eq!(slots.position(&second), 1);
+        drop(slot);
+        assert!(slots.try_acquire().is_some());
+    }
+
+    #[test]
+    fn circuit_breaker_opens_and_recovers() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        breaker.record_failure(start);
+        assert!(breaker.allow(start));
+        breaker.record_failure(start);
+        assert!(!breaker.allow(start + Duration::from_secs(59)));
+
+        // One trial after the cooldown, which reopens the breaker if it fails.
+        let later = start + Duration::from_secs(60);
+        assert!(breaker.allow(later));
+        assert!(!breaker.allow(later));
+        breaker.record_failure(later);
+        assert!(!breaker.allow(later + Duration::from_secs(59)));
+
+        let recovered = later + Duration::from_secs(60);
+        assert!(breaker.allow(recovered));
+        breaker.record_success();
+        assert!(breaker.allow(recovered));
+        breaker.record_failure(recovered);
+        assert!(breaker.allow(recovered));
+    }
+
+    #[test]
+    fn lang_parses_telegram_codes() {
+        assert_eq!(Lang::parse("zh-hans"), Some(Lang::Zh));
+        assert_eq!(Lang::parse(" EN_us "), Some(Lang::En));
+        assert_eq!(Lang::parse("fr"), None);
+    }
+}
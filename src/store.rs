@@ -0,0 +1,215 @@
+//! Where the conversations of the chats are kept.
+
+use dashmap::DashMap;
+use futures::future::{self, BoxFuture, FutureExt};
+use std::collections::HashMap;
+
+use crate::state::{ChatKey, ChatMessage, ChatMessages, ChatSettings, ChatState, TokenUsage};
+
+/// Changes a chat in place, see [`HistoryStore::update`].
+pub(crate) type ChatUpdate<'a> = Box<dyn FnOnce(&mut ChatState) + Send + 'a>;
+
+/// Storage of the chats, so that handlers don't depend on whether they're
+/// kept in memory, in a file or in a database.
+///
+/// The messages of the active conversation are only read and written with
+/// [`get`](Self::get), [`append`](Self::append), [`pop`](Self::pop),
+/// [`replace`](Self::replace) and [`clear`](Self::clear), so that a store
+/// can keep them apart from the rest of the chat. Settings, usage and the
+/// other conversations are read with [`load`](Self::load) and changed with
+/// [`update`](Self::update).
+pub(crate) trait HistoryStore: Send + Sync {
+    /// The messages of the active conversation of `key`, empty if there is
+    /// none yet.
+    fn get(&self, key: ChatKey) -> BoxFuture<'_, ChatMessages>;
+
+    /// Adds `message` to the end of the active conversation of `key`.
+    fn append(&self, key: ChatKey, message: ChatMessage) -> BoxFuture<'_, ()>;
+
+    /// Removes the last message of the active conversation of `key`,
+    /// returning it.
+    fn pop(&self, key: ChatKey) -> BoxFuture<'_, Option<ChatMessage>>;
+
+    /// Replaces the messages of the active conversation of `key`.
+    fn replace(&self, key: ChatKey, messages: ChatMessages) -> BoxFuture<'_, ()>;
+
+    /// Removes all messages of the active conversation of `key`.
+    fn clear(&self, key: ChatKey) -> BoxFuture<'_, ()> {
+        self.replace(key, ChatMessages::new())
+    }
+
+    /// The settings of `key`, the defaults if it has none.
+    fn settings(&self, key: ChatKey) -> BoxFuture<'_, ChatSettings> {
+        async move {
+            self.load(key)
+                .await
+                .map(|chat| chat.settings)
+                .unwrap_or_default()
+        }
+        .boxed()
+    }
+
+    /// Adds `usage` of `model` to what `key` has used.
+    fn add_usage(&self, key: ChatKey, model: String, usage: TokenUsage) -> BoxFuture<'_, ()> {
+        self.update(
+            key,
+            ChatState::default(),
+            Box::new(move |chat| {
+                let total = chat.usage.entry(model).or_default();
+                total.prompt_tokens += usage.prompt_tokens;
+                total.completion_tokens += usage.completion_tokens;
+            }),
+        )
+    }
+
+    /// A copy of the chat of `key`, if it exists.
+    fn load(&self, key: ChatKey) -> BoxFuture<'_, Option<ChatState>>;
+
+    /// Changes the chat of `key` with `f`, creating it as `new` first if it
+    /// doesn't exist yet.
+    fn update<'a>(&'a self, key: ChatKey, new: ChatState, f: ChatUpdate<'a>) -> BoxFuture<'a, ()>;
+
+    /// Deletes the chat of `key`, returning it if it existed.
+    fn remove(&self, key: ChatKey) -> BoxFuture<'_, Option<ChatState>>;
+
+    /// Keys of all chats.
+    fn keys(&self) -> BoxFuture<'_, Vec<ChatKey>>;
+
+    /// A copy of all chats, each copied on its own.
+    fn snapshot(&self) -> BoxFuture<'_, HashMap<ChatKey, ChatState>> {
+        async move {
+            let mut chats = HashMap::new();
+            for key in self.keys().await {
+                if let Some(chat) = self.load(key).await {
+                    chats.insert(key, chat);
+                }
+            }
+            chats
+        }
+        .boxed()
+    }
+}
+
+/// The chats kept in memory, the default store.
+#[derive(Default)]
+pub(crate) struct InMemoryStore(DashMap<ChatKey, ChatState>);
+
+impl From<HashMap<ChatKey, ChatState>> for InMemoryStore {
+    fn from(chats: HashMap<ChatKey, ChatState>) -> Self {
+        Self(chats.into_iter().collect())
+    }
+}
+
+impl HistoryStore for InMemoryStore {
+    fn get(&self, key: ChatKey) -> BoxFuture<'_, ChatMessages> {
+        let messages = self
+            .0
+            .get(&key)
+            .map(|chat| chat.messages.clone())
+            .unwrap_or_default();
+        future::ready(messages).boxed()
+    }
+
+    fn append(&self, key: ChatKey, message: ChatMessage) -> BoxFuture<'_, ()> {
+        self.0.entry(key).or_default().messages.push(message);
+        future::ready(()).boxed()
+    }
+
+    fn pop(&self, key: ChatKey) -> BoxFuture<'_, Option<ChatMessage>> {
+        let message = self
+            .0
+            .get_mut(&key)
+            .and_then(|mut chat| chat.messages.pop());
+        future::ready(message).boxed()
+    }
+
+    fn replace(&self, key: ChatKey, messages: ChatMessages) -> BoxFuture<'_, ()> {
+        self.0.entry(key).or_default().messages = messages;
+        future::ready(()).boxed()
+    }
+
+    fn settings(&self, key: ChatKey) -> BoxFuture<'_, ChatSettings> {
+        let settings = self
+            .0
+            .get(&key)
+            .map(|chat| chat.settings.clone())
+            .unwrap_or_default();
+        future::ready(settings).boxed()
+    }
+
+    fn load(&self, key: ChatKey) -> BoxFuture<'_, Option<ChatState>> {
+        future::ready(self.0.get(&key).map(|chat| chat.clone())).boxed()
+    }
+
+    fn update<'a>(&'a self, key: ChatKey, new: ChatState, f: ChatUpdate<'a>) -> BoxFuture<'a, ()> {
+        f(&mut self.0.entry(key).or_insert(new));
+        future::ready(()).boxed()
+    }
+
+    fn remove(&self, key: ChatKey) -> BoxFuture<'_, Option<ChatState>> {
+        future::ready(self.0.remove(&key).map(|(_, chat)| chat)).boxed()
+    }
+
+    fn keys(&self) -> BoxFuture<'_, Vec<ChatKey>> {
+        future::ready(self.0.iter().map(|chat| *chat.key()).collect()).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::Role;
+
+    #[tokio::test]
+    async fn in_memory_store_keeps_messages_and_chats() {
+        let store = InMemoryStore::default();
+        let key = ChatKey {
+            chat: teloxide::types::ChatId(1),
+            user: None,
+        };
+        assert!(store.get(key).await.is_empty());
+        assert!(store.load(key).await.is_none());
+
+        store.append(key, ChatMessage::new(Role::User, "Hi")).await;
+        store
+            .append(key, ChatMessage::new(Role::Assistant, "Hello!"))
+            .await;
+        let popped = store.pop(key).await;
+        assert_eq!(
+            popped.map(|message| message.content).as_deref(),
+            Some("Hello!")
+        );
+        store
+            .replace(key, vec![ChatMessage::new(Role::System, "Be brief.")])
+            .await;
+        let messages = store.get(key).await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "Be brief.");
+
+        store
+            .update(
+                key,
+                ChatState::default(),
+                Box::new(|chat| chat.settings.json = true),
+            )
+            .await;
+        let usage = TokenUsage {
+            prompt_tokens: 3,
+            completion_tokens: 2,
+        };
+        store.add_usage(key, "gpt-4".to_owned(), usage).await;
+        store.add_usage(key, "gpt-4".to_owned(), usage).await;
+        assert!(store.settings(key).await.json);
+        assert_eq!(
+            store.load(key).await.unwrap().usage["gpt-4"].prompt_tokens,
+            6
+        );
+        assert_eq!(store.get(key).await.len(), 1);
+
+        store.clear(key).await;
+        assert!(store.get(key).await.is_empty());
+        assert_eq!(store.keys().await, vec![key]);
+        assert!(store.remove(key).await.is_some());
+        assert!(store.snapshot().await.is_empty());
+    }
+}
@@ -0,0 +1,927 @@
+//! Tests of whole replies, against mocked OpenAI and Telegram APIs.
+
+use async_openai::config::OpenAIConfig;
+use async_openai::types::Role;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use teloxide::prelude::*;
+use teloxide::types::MessageId;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+use crate::commands::{clear_history, feedback, regenerate};
+use crate::completion::{complete_chat, complete_edit, show_variant, ApiFailure, Format};
+use crate::config::{ApiConfig, Client, Config};
+use crate::state::{AppState, ChatMessage, Lang, State, Text};
+
+const CHAT_ID: i64 = 42;
+
+/// Answers Bot API requests the way Telegram does, giving sent messages
+/// increasing ids.
+struct TelegramApi {
+    next_id: AtomicI64,
+}
+
+impl Respond for TelegramApi {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let body: Value = serde_json::from_slice(&request.body).unwrap_or_default();
+        let result = match api_method(request).as_str() {
+            "sendmessage" => {
+                let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                sent_message(id, &body)
+            }
+            "editmessagetext" | "editmessagereplymarkup" => {
+                sent_message(body["message_id"].as_i64().unwrap_or_default(), &body)
+            }
+            _ => json!(true),
+        };
+        ResponseTemplate::new(200).set_body_json(json!({ "ok": true, "result": result }))
+    }
+}
+
+/// The Bot API method of `request`, which Telegram matches case-insensitively.
+fn api_method(request: &Request) -> String {
+    let path = request.url.path();
+    path.rsplit('/').next().unwrap_or(path).to_lowercase()
+}
+
+fn sent_message(id: i64, body: &Value) -> Value {
+    json!({
+        "message_id": id,
+        "date": 0,
+        "chat": { "id": CHAT_ID, "type": "private", "first_name": "Test" },
+        "text": body["text"].as_str().unwrap_or_default(),
+    })
+}
+
+/// A private text message from the test user.
+fn user_message(text: &str) -> Message {
+    serde_json::from_value(json!({
+        "message_id": 1,
+        "date": 0,
+        "chat": { "id": CHAT_ID, "type": "private", "first_name": "Test" },
+        "from": { "id": CHAT_ID, "is_bot": false, "first_name": "Test" },
+        "text": text,
+    }))
+    .unwrap()
+}
+
+/// A streamed completion whose chunks are `contents`.
+fn completion_stream(contents: &[&str]) -> ResponseTemplate {
+    completion_stream_finishing(contents, "stop")
+}
+
+/// A streamed completion whose chunks are `contents`, ending for
+/// `finish_reason`.
+fn completion_stream_finishing(contents: &[&str], finish_reason: &str) -> ResponseTemplate {
+    let deltas = contents
+        .iter()
+        .map(|content| json!({ "content": content }))
+        .collect();
+    delta_stream(deltas, finish_reason)
+}
+
+/// A streamed completion that calls the tool `name` with `arguments`.
+fn tool_call_stream(name: &str, arguments: &str) -> ResponseTemplate {
+    let call = json!({ "tool_calls": [{
+        "index": 0,
+        "id": "call-test",
+        "type": "function",
+        "function": { "name": name, "arguments": arguments },
+    }] });
+    delta_stream(vec![call], "tool_calls")
+}
+
+/// A streamed completion whose chunks have `deltas`, ending for
+/// `finish_reason`.
+fn delta_stream(deltas: Vec<Value>, finish_reason: &str) -> ResponseTemplate {
+    let chunk = |delta: Value, finish_reason: Value| {
+        json!({
+            "id": "chatcmpl-test",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "gpt-3.5-turbo",
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        })
+    };
+    let mut events = vec![chunk(json!({ "role": "assistant" }), Value::Null)];
+    events.extend(deltas.into_iter().map(|delta| chunk(delta, Value::Null)));
+    events.push(chunk(json!({}), json!(finish_reason)));
+
+    let mut body: String = events
+        .iter()
+        .map(|event| format!("data: {}\n\n", event))
+        .collect();
+    body.push_str("data: [DONE]\n\n");
+    ResponseTemplate::new(200).set_body_raw(body, "text/event-stream")
+}
+
+/// The response to a request with an invalid API key.
+fn auth_error() -> ResponseTemplate {
+    ResponseTemplate::new(401).set_body_json(json!({
+        "error": {
+            "message": "Incorrect API key provided.",
+            "type": "invalid_request_error",
+            "param": null,
+            "code": "invalid_api_key",
+        },
+    }))
+}
+
+struct Harness {
+    openai: MockServer,
+    telegram: MockServer,
+    bot: Bot,
+    client: Client,
+    state: State,
+}
+
+impl Harness {
+    /// A bot with the default configuration changed by `configure`, whose
+    /// completions get `completion` as response.
+    async fn start(completion: ResponseTemplate, configure: impl FnOnce(&mut Config)) -> Self {
+        let openai = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(completion)
+            .mount(&openai)
+            .await;
+        let telegram = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(TelegramApi {
+                next_id: AtomicI64::new(100),
+            })
+            .mount(&telegram)
+            .await;
+
+        let api = ApiConfig::OpenAI(
+            OpenAIConfig::new()
+                .with_api_base(format!("{}/v1", openai.uri()))
+                .with_api_key("sk-test"),
+        );
+        let mut config = Config::with_api(api.clone());
+        configure(&mut config);
+        Self {
+            bot: Bot::new("123456:test").set_api_url(telegram.uri().parse().unwrap()),
+            client: Client::with_config(api),
+            state: Arc::new(AppState::new(config)),
+            openai,
+            telegram,
+        }
+    }
+
+    async fn send(&self, text: &str) {
+        complete_chat(
+            text.to_owned(),
+            self.bot.clone(),
+            self.client.clone(),
+            self.state.clone(),
+            user_message(text),
+        )
+        .await
+        .unwrap();
+    }
+
+    /// The role and content of the messages in the history of the test chat.
+    async fn history(&self) -> Vec<(Role, String)> {
+        let msg = user_message("");
+        let messages = self.state.store().get(self.state.key(&msg)).await;
+        messages
+            .iter()
+            .map(|message| (message.role, message.content.clone()))
+            .collect()
+    }
+
+    /// The bodies of the Bot API requests made with `api_method`.
+    async fn telegram_requests(&self, api: &str) -> Vec<Value> {
+        self.telegram
+            .received_requests()
+            .await
+            .unwrap_or_default()
+            .iter()
+            .filter(|request| api_method(request) == api.to_lowercase())
+            .map(|request| serde_json::from_slice(&request.body).unwrap())
+            .collect()
+    }
+
+    /// The text the test chat sees last, sent or edited in.
+    async fn last_text(&self) -> Option<String> {
+        self.telegram
+            .received_requests()
+            .await
+            .unwrap_or_default()
+            .iter()
+            .filter(|request| {
+                matches!(
+                    api_method(request).as_str(),
+                    "sendmessage" | "editmessagetext"
+                )
+            })
+            .filter_map(|request| {
+                let body: Value = serde_json::from_slice(&request.body).ok()?;
+                Some(body["text"].as_str()?.to_owned())
+            })
+            .next_back()
+    }
+
+    /// The contents of the messages sent to OpenAI in the last completion
+    /// request.
+    async fn prompt(&self) -> Vec<String> {
+        let requests = self.openai.received_requests().await.unwrap_or_default();
+        let body: Value = serde_json::from_slice(&requests.last().unwrap().body).unwrap();
+        body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|message| message["content"].as_str().map(str::to_owned))
+            .collect()
+    }
+}
+
+#[tokio::test]
+async fn streamed_replies_are_added_to_the_history() {
+    let harness = Harness::start(completion_stream(&["Hello", ", world!"]), |config| {
+        config.first_chunk_chars = 5;
+    })
+    .await;
+    harness.send("Hi").await;
+
+    assert_eq!(
+        harness.history().await,
+        [
+            (Role::User, "Hi".to_owned()),
+            (Role::Assistant, "Hello, world!".to_owned())
+        ]
+    );
+    assert_eq!(harness.prompt().await, ["Hi"]);
+    assert_eq!(harness.last_text().await.as_deref(), Some("Hello, world!"));
+    let previews = harness.telegram_requests("sendMessage").await;
+    assert_eq!(previews.len(), 1);
+    // Previews end in a cursor that the finished reply doesn't have.
+    assert_eq!(previews[0]["text"], "Hello▌");
+    // The preview is edited into the finished reply, which gets the buttons.
+    let edits = harness.telegram_requests("editMessageText").await;
+    let markups = harness.telegram_requests("editMessageReplyMarkup").await;
+    assert_eq!(markups.len(), 1);
+    assert_eq!(
+        markups[0]["message_id"],
+        edits.last().unwrap()["message_id"]
+    );
+}
+
+#[tokio::test]
+async fn short_fragments_are_not_shown_alone() {
+    let harness = Harness::start(completion_stream(&["Hello", ", world!"]), |_| {}).await;
+    harness.send("Hi").await;
+
+    let sent = harness.telegram_requests("sendMessage").await;
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0]["text"], "Hello, world!");
+}
+
+#[tokio::test]
+async fn replies_without_streaming_are_sent_at_once() {
+    let harness = Harness::start(
+        ResponseTemplate::new(200).set_body_json(json!({
+            "id": "chatcmpl-test",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-3.5-turbo",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": "Hello, world!" },
+                "finish_reason": "stop",
+            }],
+        })),
+        |config| config.streaming = false,
+    )
+    .await;
+    harness.send("Hi").await;
+
+    let sent = harness.telegram_requests("sendMessage").await;
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0]["text"], "Hello, world!");
+    assert!(harness
+        .telegram_requests("editMessageText")
+        .await
+        .is_empty());
+    assert_eq!(harness.history().await.len(), 2);
+}
+
+#[tokio::test]
+async fn old_messages_are_trimmed_from_the_prompt() {
+    let harness = Harness::start(completion_stream(&["Sure."]), |config| {
+        config.token_budget = Some(60);
+    })
+    .await;
+    let old = [
+        "first ".repeat(20),
+        "second ".repeat(20),
+        "third ".repeat(20),
+    ];
+    {
+        let msg = user_message("");
+        let key = harness.state.key(&msg);
+        for (i, content) in old.iter().enumerate() {
+            let role = if i % 2 == 0 {
+                Role::User
+            } else {
+                Role::Assistant
+            };
+            let message = ChatMessage::new(role, content.as_str());
+            harness.state.store().append(key, message).await;
+        }
+    }
+    harness.send("And now?").await;
+
+    let prompt = harness.prompt().await;
+    assert_eq!(prompt, [old[2].as_str(), "And now?"]);
+    // Only the prompt is trimmed, the history is kept whole.
+    assert_eq!(harness.history().await.len(), old.len() + 2);
+}
+
+#[tokio::test]
+async fn api_errors_are_reported_and_roll_back_the_message() {
+    let harness = Harness::start(auth_error(), |_| {}).await;
+    harness.send("Hi").await;
+
+    assert_eq!(
+        harness.last_text().await.as_deref(),
+        Some(ApiFailure::Auth.message())
+    );
+    assert!(harness.history().await.is_empty());
+    // Authentication errors are not retried.
+    let requests = harness.openai.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+}
+
+#[tokio::test]
+async fn api_errors_replace_the_placeholder() {
+    let harness = Harness::start(auth_error(), |config| {
+        config.placeholder.text = Some("💭".to_owned());
+    })
+    .await;
+    harness.send("Hi").await;
+
+    let sent = harness.telegram_requests("sendMessage").await;
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0]["text"], "💭");
+    let edits = harness.telegram_requests("editMessageText").await;
+    assert_eq!(edits.len(), 1);
+    assert_eq!(edits[0]["text"], ApiFailure::Auth.message());
+}
+
+#[tokio::test]
+async fn invalid_json_replies_are_not_kept() {
+    let harness = Harness::start(completion_stream(&["{\"answer\":", " 4"]), |_| {}).await;
+    {
+        let msg = user_message("");
+        harness
+            .state
+            .update_chat(harness.state.key(&msg), |chat| chat.settings.json = true)
+            .await;
+    }
+    harness.send("What is 2 + 2?").await;
+
+    let requests = harness.openai.received_requests().await.unwrap();
+    let body: Value = serde_json::from_slice(&requests[0].body).unwrap();
+    assert_eq!(body["response_format"]["type"], "json_object");
+    assert_eq!(
+        harness.history().await,
+        [(Role::User, "What is 2 + 2?".to_owned())]
+    );
+    assert!(harness
+        .last_text()
+        .await
+        .is_some_and(|text| text.contains("not valid JSON")));
+}
+
+#[tokio::test]
+async fn crossing_the_budget_warns_once() {
+    let harness = Harness::start(completion_stream(&["Sure."]), |config| {
+        config.token_budget = Some(60);
+    })
+    .await;
+    harness.send(&"long ".repeat(50)).await;
+    harness.send(&"longer ".repeat(50)).await;
+
+    let warnings = harness
+        .telegram_requests("sendMessage")
+        .await
+        .into_iter()
+        .filter(|body| body["text"] == Lang::En.text(Text::BudgetWarning))
+        .count();
+    assert_eq!(warnings, 1);
+}
+
+#[tokio::test]
+async fn code_blocks_are_sent_as_entities() {
+    let harness = Harness::start(
+        completion_stream(&["Run `main`:\n", "```rust\nfn main() {}\n```"]),
+        |_| {},
+    )
+    .await;
+    {
+        let msg = user_message("");
+        harness
+            .state
+            .update_chat(harness.state.key(&msg), |chat| {
+                chat.settings.format = Format::Entities
+            })
+            .await;
+    }
+    harness.send("Hi").await;
+
+    let sent = harness.telegram_requests("sendMessage").await;
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0]["text"], "Run main:\nfn main() {}");
+    assert_eq!(
+        sent[0]["entities"],
+        json!([
+            { "type": "code", "offset": 4, "length": 4 },
+            { "type": "pre", "offset": 10, "length": 12, "language": "rust" },
+        ])
+    );
+}
+
+#[tokio::test]
+async fn html_previews_close_their_tags() {
+    let harness = Harness::start(
+        completion_stream(&["```rust\nif a < b {", "}\n```"]),
+        |config| config.first_chunk_chars = 5,
+    )
+    .await;
+    {
+        let msg = user_message("");
+        harness
+            .state
+            .update_chat(harness.state.key(&msg), |chat| {
+                chat.settings.format = Format::Html
+            })
+            .await;
+    }
+    harness.send("Hi").await;
+
+    let previews = harness.telegram_requests("sendMessage").await;
+    assert_eq!(previews.len(), 1);
+    assert_eq!(previews[0]["parse_mode"], "HTML");
+    assert_eq!(
+        previews[0]["text"],
+        "<pre><code class=\"language-rust\">if a &lt; b {▌</code></pre>"
+    );
+    let edits = harness.telegram_requests("editMessageText").await;
+    assert_eq!(
+        edits.last().unwrap()["text"],
+        "<pre><code class=\"language-rust\">if a &lt; b {}</code></pre>"
+    );
+}
+
+#[tokio::test]
+async fn quoted_messages_are_added_to_the_prompt() {
+    let harness = Harness::start(completion_stream(&["A cat."]), |_| {}).await;
+    let msg: Message = serde_json::from_value(json!({
+        "message_id": 2,
+        "date": 0,
+        "chat": { "id": CHAT_ID, "type": "private", "first_name": "Test" },
+        "from": { "id": CHAT_ID, "is_bot": false, "first_name": "Test" },
+        "text": "What is this?",
+        "reply_to_message": {
+            "message_id": 1,
+            "date": 0,
+            "chat": { "id": CHAT_ID, "type": "private", "first_name": "Test" },
+            "from": { "id": 7, "is_bot": false, "first_name": "Alice" },
+            "photo": [{ "file_id": "photo", "file_unique_id": "photo", "width": 1, "height": 1 }],
+            "caption": "Look",
+        },
+    }))
+    .unwrap();
+    complete_chat(
+        "What is this?".to_owned(),
+        harness.bot.clone(),
+        harness.client.clone(),
+        harness.state.clone(),
+        msg,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        harness.prompt().await.last().unwrap(),
+        "In reply to this message from Alice:\n\"\"\"\n(a photo) Look\n\"\"\"\n\nWhat is this?"
+    );
+}
+
+#[tokio::test]
+async fn edited_messages_are_answered_in_place() {
+    let harness = Harness::start(completion_stream(&["Hello!"]), |_| {}).await;
+    harness.send("Hi").await;
+    complete_edit(
+        "Hey".to_owned(),
+        harness.bot.clone(),
+        harness.client.clone(),
+        harness.state.clone(),
+        user_message("Hey"),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        harness.history().await,
+        vec![
+            (Role::User, "Hey".to_owned()),
+            (Role::Assistant, "Hello!".to_owned()),
+        ]
+    );
+    assert_eq!(harness.telegram_requests("sendMessage").await.len(), 1);
+    let edits = harness.telegram_requests("editMessageText").await;
+    assert_eq!(edits.last().unwrap()["message_id"], 100);
+}
+
+#[tokio::test]
+async fn rate_limited_edits_keep_the_previous_reply() {
+    let harness = Harness::start(completion_stream(&["Hello!"]), |config| {
+        config.rate_limit = Some(1);
+    })
+    .await;
+    harness.send("Hi").await;
+    complete_edit(
+        "Hey".to_owned(),
+        harness.bot.clone(),
+        harness.client.clone(),
+        harness.state.clone(),
+        user_message("Hey"),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        harness.history().await,
+        vec![
+            (Role::User, "Hi".to_owned()),
+            (Role::Assistant, "Hello!".to_owned()),
+        ]
+    );
+    assert_eq!(harness.openai.received_requests().await.unwrap().len(), 1);
+    assert!(harness.telegram_requests("deleteMessage").await.is_empty());
+}
+
+#[tokio::test]
+async fn deleted_placeholders_are_replaced_by_a_new_message() {
+    let harness = Harness::start(completion_stream(&["Hello!"]), |config| {
+        config.placeholder.text = Some("💭".to_owned());
+    })
+    .await;
+    Mock::given(method("POST"))
+        .and(path("/bot123456:test/EditMessageText"))
+        .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+            "ok": false,
+            "error_code": 400,
+            "description": "Bad Request: message to edit not found",
+        })))
+        .with_priority(1)
+        .mount(&harness.telegram)
+        .await;
+    harness.send("Hi").await;
+
+    let sent = harness.telegram_requests("sendMessage").await;
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[1]["text"], "Hello!");
+    assert_eq!(harness.history().await.last().unwrap().1, "Hello!");
+}
+
+#[tokio::test]
+async fn personas_are_kept_after_clearing() {
+    let harness = Harness::start(completion_stream(&["Ahoy!"]), |config| {
+        config.default_prompt = Some("You are helpful.".to_owned());
+    })
+    .await;
+    {
+        let msg = user_message("");
+        harness
+            .state
+            .update_chat(harness.state.key(&msg), |chat| {
+                chat.settings.persona = Some("You are a pirate.".to_owned())
+            })
+            .await;
+    }
+    harness.send("Hi").await;
+    clear_history(
+        harness.bot.clone(),
+        harness.state.clone(),
+        user_message("/clear"),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        harness.history().await,
+        vec![(Role::System, "You are a pirate.".to_owned())]
+    );
+}
+
+#[tokio::test]
+async fn cut_off_replies_get_a_note_that_is_not_kept() {
+    let harness = Harness::start(
+        completion_stream_finishing(&["Once upon a time"], "length"),
+        |_| {},
+    )
+    .await;
+    harness.send("Tell me a story").await;
+
+    let note = "The reply was cut off because it got too long, use /continue to get the rest.";
+    let sent = harness.telegram_requests("sendMessage").await;
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0]["text"], format!("Once upon a time\n\n{}", note));
+    assert_eq!(
+        sent[0]["entities"],
+        json!([{ "type": "italic", "offset": 18, "length": note.len() }])
+    );
+    assert_eq!(
+        harness.history().await.last().unwrap().1,
+        "Once upon a time"
+    );
+}
+
+#[tokio::test]
+async fn private_chats_use_their_own_history_cap() {
+    let harness = Harness::start(completion_stream(&["Hello!"]), |config| {
+        config.default_prompt = Some("You are helpful.".to_owned());
+        config.max_history = Some(10);
+        config.max_history_private = Some(2);
+    })
+    .await;
+    harness.send("Hi").await;
+    harness.send("How are you?").await;
+
+    assert_eq!(
+        harness.history().await,
+        [
+            (Role::System, "You are helpful.".to_owned()),
+            (Role::User, "How are you?".to_owned()),
+            (Role::Assistant, "Hello!".to_owned()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn feedback_is_forwarded_even_if_it_cant_be_written() {
+    let harness = Harness::start(completion_stream(&[]), |config| {
+        config.feedback_path = Some("/nonexistent/feedback.jsonl".into());
+        config.feedback_chat = Some(ChatId(7));
+    })
+    .await;
+    feedback(
+        "Great bot".to_owned(),
+        harness.bot.clone(),
+        harness.state.clone(),
+        user_message("/feedback Great bot"),
+    )
+    .await
+    .unwrap();
+
+    let sent = harness.telegram_requests("sendMessage").await;
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[0]["chat_id"], 7);
+    assert_eq!(
+        sent[0]["text"],
+        format!("Feedback from chat {0}, user {0}:\nGreat bot", CHAT_ID)
+    );
+    assert_eq!(sent[1]["chat_id"], CHAT_ID);
+    assert!(harness.history().await.is_empty());
+}
+
+#[tokio::test]
+async fn variants_that_are_not_json_are_dropped() {
+    let harness = Harness::start(
+        ResponseTemplate::new(200).set_body_json(json!({
+            "id": "chatcmpl-test",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-3.5-turbo",
+            "choices": [
+                {
+                    "index": 0,
+                    "message": { "role": "assistant", "content": "Hi!" },
+                    "finish_reason": "stop",
+                },
+                {
+                    "index": 1,
+                    "message": { "role": "assistant", "content": "{\"greeting\": " },
+                    "finish_reason": "length",
+                },
+            ],
+        })),
+        |_| {},
+    )
+    .await;
+    {
+        let msg = user_message("");
+        harness
+            .state
+            .update_chat(harness.state.key(&msg), |chat| {
+                chat.settings.variants = Some(2);
+                chat.settings.json = true;
+            })
+            .await;
+    }
+    harness.send("Hi").await;
+
+    assert_eq!(
+        harness.last_text().await.as_deref(),
+        Some(ApiFailure::Other.message())
+    );
+    assert!(harness.history().await.is_empty());
+}
+
+#[tokio::test]
+async fn only_the_picked_variant_is_kept() {
+    let harness = Harness::start(
+        ResponseTemplate::new(200).set_body_json(json!({
+            "id": "chatcmpl-test",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-3.5-turbo",
+            "choices": [
+                {
+                    "index": 1,
+                    "message": { "role": "assistant", "content": "Hello there!" },
+                    "finish_reason": "stop",
+                },
+                {
+                    "index": 0,
+                    "message": { "role": "assistant", "content": "Hi!" },
+                    "finish_reason": "stop",
+                },
+            ],
+        })),
+        |_| {},
+    )
+    .await;
+    {
+        let msg = user_message("");
+        harness
+            .state
+            .update_chat(harness.state.key(&msg), |chat| {
+                chat.settings.variants = Some(2)
+            })
+            .await;
+    }
+    harness.send("Hi").await;
+
+    let requests = harness.openai.received_requests().await.unwrap();
+    let body: Value = serde_json::from_slice(&requests[0].body).unwrap();
+    assert_eq!(body["n"], 2);
+    let sent = harness.telegram_requests("sendMessage").await;
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0]["text"], "1. Hi!\n\n2. Hello there!");
+    assert_eq!(
+        sent[0]["reply_markup"]["inline_keyboard"][0][1]["callback_data"],
+        "v2"
+    );
+    assert_eq!(harness.history().await, [(Role::User, "Hi".to_owned())]);
+
+    let mut variants = user_message("");
+    variants.id = MessageId(100);
+    let picked = harness
+        .state
+        .pick_variant(harness.state.key(&variants), variants.id, 1)
+        .await;
+    assert_eq!(picked.as_deref(), Some("Hello there!"));
+    show_variant(
+        picked.unwrap(),
+        harness.bot.clone(),
+        harness.state.clone(),
+        variants.clone(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        harness.history().await,
+        [
+            (Role::User, "Hi".to_owned()),
+            (Role::Assistant, "Hello there!".to_owned())
+        ]
+    );
+    assert_eq!(harness.last_text().await.as_deref(), Some("Hello there!"));
+    // Picking again does nothing.
+    let picked = harness
+        .state
+        .pick_variant(harness.state.key(&variants), variants.id, 0)
+        .await;
+    assert_eq!(picked, None);
+}
+
+#[tokio::test]
+async fn regenerating_counts_against_the_demo_limit() {
+    let harness = Harness::start(completion_stream(&["Hello!"]), |config| {
+        config.demo_limit = Some(1);
+    })
+    .await;
+    harness.send("Hi").await;
+    regenerate(
+        harness.bot.clone(),
+        harness.client.clone(),
+        harness.state.clone(),
+        user_message("/regenerate"),
+    )
+    .await
+    .unwrap();
+
+    let requests = harness.openai.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        harness.last_text().await.as_deref(),
+        Some("The demo limit has been reached, thanks for trying the bot!")
+    );
+}
+
+#[tokio::test]
+async fn tool_rounds_count_against_the_demo_limit() {
+    let harness = Harness::start(tool_call_stream("get_current_time", "{}"), |config| {
+        config.demo_limit = Some(2);
+    })
+    .await;
+    harness.send("What time is it?").await;
+
+    let requests = harness.openai.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(harness.state.demo_served.load(Ordering::Relaxed), 2);
+    assert_eq!(
+        harness.last_text().await.as_deref(),
+        Some("The demo limit has been reached, thanks for trying the bot!")
+    );
+    assert!(harness.history().await.is_empty());
+}
+
+#[tokio::test]
+async fn replies_time_out_while_connecting() {
+    let harness = Harness::start(
+        completion_stream(&["Hello!"]).set_delay(Duration::from_secs(5)),
+        |config| config.stream_timeout = Some(Duration::from_millis(100)),
+    )
+    .await;
+    harness.send("Hi").await;
+
+    assert_eq!(
+        harness.last_text().await.as_deref(),
+        Some("The reply timed out before anything arrived, please try again.")
+    );
+    assert!(harness.history().await.is_empty());
+}
+
+#[tokio::test]
+async fn replies_can_be_stopped_while_connecting() {
+    let harness = Arc::new(
+        Harness::start(
+            completion_stream(&["Hello!"]).set_delay(Duration::from_secs(30)),
+            |_| {},
+        )
+        .await,
+    );
+    let sending = tokio::spawn({
+        let harness = harness.clone();
+        async move { harness.send("Hi").await }
+    });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let msg = user_message("");
+    assert!(harness.state.stop_stream(harness.state.key(&msg)));
+    tokio::time::timeout(Duration::from_secs(5), sending)
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert!(harness.history().await.is_empty());
+}
+
+#[tokio::test]
+async fn replies_broken_off_by_an_error_keep_what_they_got() {
+    let chunk = json!({
+        "id": "chatcmpl-test",
+        "object": "chat.completion.chunk",
+        "created": 0,
+        "model": "gpt-3.5-turbo",
+        "choices": [{ "index": 0, "delta": { "content": "Hello" }, "finish_reason": null }],
+    });
+    let body = format!("data: {}\n\ndata: {{not json\n\n", chunk);
+    let harness = Harness::start(
+        ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"),
+        |_| {},
+    )
+    .await;
+    harness.send("Hi").await;
+
+    assert_eq!(
+        harness.last_text().await.as_deref(),
+        Some(format!("Hello\n\n{}", ApiFailure::Other.message()).as_str())
+    );
+    assert_eq!(
+        harness.history().await,
+        [
+            (Role::User, "Hi".to_owned()),
+            (Role::Assistant, "Hello".to_owned())
+        ]
+    );
+}